@@ -0,0 +1,145 @@
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nbpm::generation::Generation;
+use nbpm::lock::LockMode;
+use nbpm::nbpkg;
+use nbpm::recipe::Recipe;
+use nbpm::state::InstalledState;
+use nbpm::Package;
+
+const USAGE: &str = "Usage: nb-build <staged-dir> <package.toml> [-o <output-dir>]
+       nb-build --recipe <recipe.toml>
+
+Builds a nebula package (.nbpkg) from a staged directory. The package.toml file holds the
+package's metadata: name, version and dependencies.
+
+With --recipe, the missing build dependencies of the recipe are installed, its sources are
+fetched and built, installing into a staging directory under fakeroot-dir, and the package is
+created from it.";
+
+fn main() {
+    let args: Vec<String> = nbpm::config::args();
+    let mut positional = vec![];
+    let mut outdir = PathBuf::from(".");
+    let mut recipe = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "-o" | "--output" => match args.get(i + 1) {
+                Some(o) => {
+                    outdir = PathBuf::from(o);
+                    i += 1;
+                }
+                None => usage(),
+            },
+            "-r" | "--recipe" => match args.get(i + 1) {
+                Some(r) => {
+                    recipe = Some(PathBuf::from(r));
+                    i += 1;
+                }
+                None => usage(),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            a => positional.push(a.to_string()),
+        }
+        i += 1;
+    }
+    if let Some(recipe) = recipe {
+        build_recipe(&recipe);
+        return;
+    }
+    if positional.len() != 2 {
+        usage();
+    }
+
+    let package: Package = match nbpm::read_toml(Path::new(&positional[1])) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("[!] Cannot read package metadata {}: {}", positional[1], e);
+            process::exit(1);
+        }
+    };
+
+    match nbpkg::build(Path::new(&positional[0]), package, &outdir) {
+        Ok(path) => println!("[*] package created: {}", path.display()),
+        Err(e) => {
+            eprintln!("[!] Cannot build package: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn build_recipe(path: &Path) {
+    let recipe = match Recipe::from(path) {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("[!] Cannot read recipe {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    // build dependencies are installed, and the build directory and the log of the recipe are
+    // recreated: another build of the same recipe must not run meanwhile
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let repos = match nebula.repos() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("[!] Cannot read the repositories: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = nebula.initialize(&repos) {
+        eprintln!("[!] Cannot initialize the repositories: {}", e);
+        process::exit(1);
+    }
+    let config = nebula.config();
+    let mut state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    let built = recipe.build(config, &repos, &mut state);
+    // the build dependencies installed are kept, even if the build failed
+    if let Err(e) = state.save(config) {
+        eprintln!("[!] Cannot save the installed state: {}", e);
+        process::exit(1);
+    }
+    match Generation::commit(config, &mut state) {
+        Ok(Some(n)) => println!("[*] generation {} recorded", n),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("[!] Cannot record the generation: {}", e);
+            process::exit(1);
+        }
+    }
+    match built {
+        Ok(path) => println!("[*] package created: {}", path.display()),
+        Err(e) => {
+            eprintln!("[!] Cannot build {}: {}", recipe.name, e);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
@@ -0,0 +1,287 @@
+use std::process;
+use std::time::Duration;
+
+use nbpm::config::{ConfigSource, Configuration, Problem};
+use nbpm::lock::{Lock, LockMode};
+use toml::Value;
+
+const USAGE: &str = "Usage: nb-config check
+       nb-config show [--show-secrets]
+       nb-config get [--show-secrets] <key>
+       nb-config set <key> <value>
+
+Inspects nebula's configuration. check validates the configuration and lists its problems. show
+prints every key of the effective configuration, that is the configuration file with the default
+values filled in, and where its value comes from: the file, or the defaults. Keys are dotted
+paths, e.g. nebula-dir or repositories.debian.0.suite. Passwords are printed as \"***\" unless
+--show-secrets is given.
+
+set changes a key in the configuration file. The value is read as a TOML value (a number, a
+boolean, an array...) or as a string otherwise. The file is rewritten, so its comments are not
+preserved.";
+
+fn main() {
+    let mut args: Vec<String> = nbpm::config::args();
+    let secrets = args.len();
+    args.retain(|a| a != "--show-secrets");
+    let secrets = if args.len() != secrets {
+        Secrets::Show
+    } else {
+        Secrets::Redact
+    };
+    let config = match Configuration::discover(nbpm::config::config_arg().as_deref()) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+
+    match (args.first().map(|a| a.as_str()), args.len()) {
+        (Some("check"), 1) => check(&config),
+        (Some("show"), 1) => show(&config, secrets),
+        (Some("get"), 2) => get(&config, &args[1], secrets),
+        (Some("set"), 3) => set(&config, &args[1], &args[2]),
+        (Some("-h"), _) | (Some("--help"), _) => println!("{}", USAGE),
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    }
+}
+
+fn check(config: &Configuration) {
+    if let Some(source) = &config.source {
+        println!("[*] configuration file: {}", source);
+    }
+    let problems = config.check();
+    for problem in &problems {
+        println!("[!] {}", problem);
+    }
+    if problems.iter().any(|p| p.is_error()) {
+        process::exit(1);
+    }
+    println!("[*] configuration is valid");
+}
+
+/// Whether `show` and `get` print the passwords of the configuration.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Secrets {
+    Show,
+    Redact,
+}
+
+fn show(config: &Configuration, secrets: Secrets) {
+    let effective = effective(config, secrets);
+    // the keys missing from the file got their default value
+    let file: Option<Value> = match &config.source {
+        Some(source) => {
+            println!("# configuration file: {}\n", source);
+            match nbpm::read_toml(&source.path) {
+                Ok(v) => Some(v),
+                Err(e) => {
+                    eprintln!("[!] Cannot read {}: {}", source.path.display(), e);
+                    process::exit(1);
+                }
+            }
+        }
+        None => None,
+    };
+    let mut values = vec![];
+    flatten("", &effective, &mut values);
+    for (key, value) in values {
+        let origin = match &file {
+            Some(f) if lookup(f, &key).is_some() => "file",
+            _ => "default",
+        };
+        println!("{} = {}  # {}", key, value, origin);
+    }
+}
+
+fn get(config: &Configuration, key: &str, secrets: Secrets) {
+    let value = effective(config, secrets);
+    let current = match lookup(&value, key) {
+        Some(v) => v,
+        None => {
+            eprintln!("[!] Key {} is not set", key);
+            process::exit(1);
+        }
+    };
+    match current {
+        Value::String(s) => println!("{}", s),
+        Value::Table(_) => print!("{}", current),
+        v => println!("{}", v),
+    }
+}
+
+fn set(config: &Configuration, key: &str, raw: &str) {
+    let source: &ConfigSource = match &config.source {
+        Some(s) => s,
+        None => {
+            eprintln!("[!] The configuration was not loaded from a file");
+            process::exit(1);
+        }
+    };
+    // without nebula's home there is no other nebula process to exclude, and the configuration
+    // may point it somewhere wrong, so it's not created just for the lock
+    let _lock = if config.nebulahome.is_dir() {
+        let timeout = config.lock_timeout.map(Duration::from_secs);
+        match Lock::acquire(&config.nebulahome, LockMode::Write, timeout) {
+            Ok(l) => Some(l),
+            Err(e) => {
+                eprintln!("[!] {}", e);
+                process::exit(1);
+            }
+        }
+    } else {
+        None
+    };
+    let mut document: Value = match nbpm::read_toml(&source.path) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("[!] Cannot read {}: {}", source.path.display(), e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = set_key(&mut document, key, parse_value(raw)) {
+        eprintln!("[!] Cannot set {}: {}", key, e);
+        process::exit(1);
+    }
+
+    // the new configuration must still be loadable, and must not add new errors
+    let mut new_config: Configuration = match document.clone().try_into() {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("[!] Cannot set {}: {}", key, e);
+            process::exit(1);
+        }
+    };
+    new_config.source = config.source.clone();
+    let old_problems = config.check();
+    let new_errors: Vec<Problem> = new_config
+        .check()
+        .into_iter()
+        .filter(|p| p.is_error() && !old_problems.contains(p))
+        .collect();
+    if !new_errors.is_empty() {
+        for e in &new_errors {
+            eprintln!("[!] {}", e);
+        }
+        eprintln!("[!] {} not changed", source.path.display());
+        process::exit(1);
+    }
+
+    // an interrupted write never truncates the file
+    if let Err(e) = nbpm::write_toml_atomic(&source.path, &document) {
+        eprintln!("[!] Cannot write {}: {}", source.path.display(), e);
+        process::exit(1);
+    }
+    println!("[*] {} set in {}", key, source.path.display());
+}
+
+/// The configuration in use, with the default values filled in.
+fn effective(config: &Configuration, secrets: Secrets) -> Value {
+    let mut value = match Value::try_from(config) {
+        Ok(v) => v,
+        Err(e) => {
+            eprintln!("[!] Cannot serialize the configuration: {}", e);
+            process::exit(1);
+        }
+    };
+    if secrets == Secrets::Redact {
+        redact(&mut value);
+    }
+    value
+}
+
+/// Replaces the passwords of the configuration (the network settings, global or of a
+/// repository), so they don't end up in terminals and bug reports.
+fn redact(value: &mut Value) {
+    match value {
+        Value::Table(t) => {
+            for (k, v) in t.iter_mut() {
+                if k == "password" && v.is_str() {
+                    *v = Value::String("***".to_string());
+                } else {
+                    redact(v);
+                }
+            }
+        }
+        Value::Array(a) => a.iter_mut().for_each(redact),
+        _ => (),
+    }
+}
+
+/// Finds the value at the dotted `key`, array elements are indexed by number.
+fn lookup<'a>(value: &'a Value, key: &str) -> Option<&'a Value> {
+    let mut current = value;
+    for segment in key.split('.') {
+        current = match segment.parse::<usize>() {
+            Ok(i) => current.get(i)?,
+            Err(_) => current.get(segment)?,
+        };
+    }
+    Some(current)
+}
+
+/// Lists the values of the tables (and arrays of tables) of `value` with their dotted keys.
+fn flatten(prefix: &str, value: &Value, values: &mut Vec<(String, Value)>) {
+    let key = |k: &str| {
+        if prefix.is_empty() {
+            k.to_string()
+        } else {
+            format!("{}.{}", prefix, k)
+        }
+    };
+    match value {
+        Value::Table(t) => {
+            for (k, v) in t {
+                flatten(&key(k), v, values);
+            }
+        }
+        Value::Array(a) if !a.is_empty() && a.iter().all(|v| v.is_table()) => {
+            for (i, v) in a.iter().enumerate() {
+                flatten(&key(&i.to_string()), v, values);
+            }
+        }
+        v => values.push((prefix.to_string(), v.clone())),
+    }
+}
+
+/// Reads a TOML value, falling back to a plain string.
+fn parse_value(raw: &str) -> Value {
+    match toml::from_str::<Value>(&format!("value = {}", raw)) {
+        Ok(Value::Table(mut t)) => t.remove("value").unwrap(),
+        _ => Value::String(raw.to_string()),
+    }
+}
+
+/// Sets the value at the dotted `key`, creating the missing tables.
+fn set_key(document: &mut Value, key: &str, value: Value) -> Result<(), String> {
+    let segments: Vec<&str> = key.split('.').collect();
+    let (last, parents) = segments.split_last().unwrap();
+    let mut current = document;
+    for segment in parents {
+        current = match current {
+            Value::Table(t) => t
+                .entry(segment.to_string())
+                .or_insert_with(|| Value::Table(Default::default())),
+            Value::Array(a) => match segment.parse::<usize>() {
+                Ok(i) if i < a.len() => &mut a[i],
+                _ => return Err(format!("no element {} in the array", segment)),
+            },
+            _ => return Err(format!("{} is not a table", segment)),
+        };
+    }
+    match current {
+        Value::Table(t) => {
+            t.insert(last.to_string(), value);
+        }
+        Value::Array(a) => match last.parse::<usize>().ok().and_then(|i| a.get_mut(i)) {
+            Some(v) => *v = value,
+            None => return Err(format!("no element {} in the array", last)),
+        },
+        _ => return Err(format!("the parent of {} is not a table", last)),
+    }
+    Ok(())
+}
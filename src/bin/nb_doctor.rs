@@ -0,0 +1,119 @@
+use std::process;
+
+use nbpm::doctor;
+use nbpm::generation::SwitchJournal;
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+const USAGE: &str = "Usage: nb-doctor [--fix]
+
+Checks the links of the destination directory against the installed packages. With --fix, stray
+links are removed and the packages with missing or inconsistent links are linked again, and an
+interrupted nb-rollback is undone.";
+
+fn main() {
+    let args: Vec<String> = nbpm::config::args();
+    let fix = match args.first().map(|a| a.as_str()) {
+        None => false,
+        Some("--fix") if args.len() == 1 => true,
+        Some("-h") | Some("--help") => {
+            println!("{}", USAGE);
+            return;
+        }
+        _ => {
+            eprintln!("{}", USAGE);
+            process::exit(1);
+        }
+    };
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let mode = if fix { LockMode::Write } else { LockMode::Read };
+    let _lock = match nebula.lock(mode) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let config = nebula.config();
+    let mut state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+
+    // with --fix, taking the lock already undid an interrupted switch
+    match SwitchJournal::load(config) {
+        Ok(Some(journal)) => {
+            println!(
+                "[!] the switch to generation {} was interrupted, it can be undone with --fix",
+                journal.to
+            );
+            process::exit(1);
+        }
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("[!] Cannot read the generation switch journal: {}", e);
+            process::exit(1);
+        }
+    }
+
+    let problems = match doctor::diagnose(config, &state) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("[!] Cannot check the destination directory: {}", e);
+            process::exit(1);
+        }
+    };
+    if problems.is_empty() {
+        println!("[*] no problems found");
+        return;
+    }
+    for problem in &problems {
+        println!("{}", problem);
+    }
+    if !fix {
+        let fixable = problems.iter().filter(|p| p.fixable()).count();
+        println!(
+            "[*] {} problems found, {} can be fixed with --fix",
+            problems.len(),
+            fixable
+        );
+        process::exit(1);
+    }
+
+    let result = doctor::repair(config, &mut state, &problems);
+    // links changed even if the repair stopped halfway
+    if let Err(e) = state.save(config) {
+        eprintln!("[!] Cannot save the installed state: {}", e);
+        process::exit(1);
+    }
+    if let Err(e) = result {
+        eprintln!("[!] Cannot repair the destination directory: {}", e);
+        process::exit(1);
+    }
+    let remaining = match doctor::diagnose(config, &state) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("[!] Cannot check the destination directory: {}", e);
+            process::exit(1);
+        }
+    };
+    if remaining.is_empty() {
+        println!("[*] {} problems fixed", problems.len());
+        return;
+    }
+    eprintln!("[!] {} problems left:", remaining.len());
+    for problem in &remaining {
+        eprintln!("    {}", problem);
+    }
+    process::exit(1);
+}
@@ -0,0 +1,92 @@
+use std::process;
+
+use nbpm::gc;
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+const USAGE: &str = "Usage: nb-gc [-n|--dry-run] [--keep N]
+
+Removes the package trees no longer used by the installed packages or by any recorded generation.
+With --keep, the generations older than the N most recent ones are deleted first, the current
+generation is always kept.";
+
+fn main() {
+    let args: Vec<String> = nbpm::config::args();
+    let mut dry_run = false;
+    let mut keep = None;
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-n" | "--dry-run" => dry_run = true,
+            "--keep" => match args.next().map(|n| n.parse::<usize>()) {
+                Some(Ok(n)) => keep = Some(n),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            _ => {
+                eprintln!("{}", USAGE);
+                process::exit(1);
+            }
+        }
+    }
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    // a dry run removes nothing, other readers can run meanwhile
+    let mode = if dry_run {
+        LockMode::Read
+    } else {
+        LockMode::Write
+    };
+    let _lock = match nebula.lock(mode) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let config = nebula.config();
+    let state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let collected = match gc::collect(config, &state, keep, dry_run) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("[!] Cannot collect the package store: {}", e);
+            process::exit(1);
+        }
+    };
+    let verb = if dry_run { "would remove" } else { "removed" };
+    for n in &collected.generations {
+        println!("{} generation {}", verb, n);
+    }
+    for tree in &collected.trees {
+        println!("{} {}", verb, tree);
+    }
+    for leftover in &collected.leftovers {
+        println!("{} {}", verb, leftover.display());
+    }
+    println!(
+        "[*] {} package trees {}, {} reclaimed{}",
+        collected.trees.len(),
+        if dry_run { "unused" } else { "removed" },
+        gc::format_size(collected.bytes),
+        if dry_run { " (dry run)" } else { "" }
+    );
+}
@@ -0,0 +1,65 @@
+use std::process;
+
+use nbpm::generation::Generation;
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+fn main() {
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Read) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let config = nebula.config();
+    let state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    let generations = match Generation::list(config) {
+        Ok(g) => g,
+        Err(e) => {
+            eprintln!("[!] Cannot read the generations: {}", e);
+            process::exit(1);
+        }
+    };
+    if generations.is_empty() {
+        println!("[*] no generations recorded yet");
+        return;
+    }
+
+    // every generation is listed with its changes from the previous one
+    let empty = InstalledState::default();
+    for (i, generation) in generations.iter().enumerate() {
+        let current = if state.generation == Some(generation.number) {
+            " (current)"
+        } else {
+            ""
+        };
+        println!(
+            "{:>4}  {}  {} packages{}",
+            generation.number,
+            generation.created_str(),
+            generation.state.packages.len(),
+            current
+        );
+        let previous = match i {
+            0 => &empty,
+            _ => &generations[i - 1].state,
+        };
+        for change in generation.diff(previous) {
+            println!("        {}", change);
+        }
+    }
+}
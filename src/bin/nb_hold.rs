@@ -0,0 +1,48 @@
+use std::process;
+
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+fn main() {
+    let names: Vec<String> = nbpm::config::args();
+    if names.is_empty() {
+        eprintln!("Usage: nb-hold <package>...");
+        process::exit(1);
+    }
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let mut state = match InstalledState::load(nebula.config()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    for name in &names {
+        match state.hold(name) {
+            Ok(true) => println!("[*] {} held", name),
+            Ok(false) => println!("[*] {} was already held", name),
+            Err(e) => {
+                eprintln!("[!] Cannot hold {}: {}", name, e);
+                process::exit(1);
+            }
+        }
+    }
+    if let Err(e) = state.save(nebula.config()) {
+        eprintln!("[!] Cannot save the installed state: {}", e);
+        process::exit(1);
+    }
+}
@@ -1,16 +1,99 @@
-extern crate regex;
+use std::process;
 
-use nbpm::Repository;
+use nbpm::generation::Generation;
+use nbpm::install;
+use nbpm::lock::LockMode;
+use nbpm::policy;
+use nbpm::state::InstalledState;
 
 fn main() {
-    let repos = nbpm::repos::create_repos().unwrap();
-    nbpm::initialize(&repos).unwrap();
-    // let mut matches = vec![];
-    for repo in repos {
-        let match_ = repo.search("libc", None).unwrap();
-        for m in match_.unwrap() {
-            println!("{}\n", m);
+    let names: Vec<String> = nbpm::config::args();
+    if names.is_empty() {
+        eprintln!("Usage: nb-install <package>...");
+        process::exit(1);
+    }
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let repos = match nebula.repos() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("[!] Cannot read the repositories: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = nebula.initialize(&repos) {
+        eprintln!("[!] Cannot initialize the repositories: {}", e);
+        process::exit(1);
+    }
+    let config = nebula.config();
+    let mut state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
         }
-        // matches.append(&mut match_.unwrap());
+    };
+    let mut failed = false;
+    for name in &names {
+        // select the candidate honouring repository priorities and pins
+        let candidate = match policy::select_candidate(&repos, &config.pins, name) {
+            Ok(Some(c)) => c,
+            Err(e) => {
+                eprintln!("[!] Cannot select a candidate for {}: {}", name, e);
+                failed = true;
+                break;
+            }
+            Ok(None) => {
+                eprintln!("[!] Package {} not found", name);
+                failed = true;
+                break;
+            }
+        };
+        println!(
+            "[*] installing {} {} from {} (priority {})",
+            name, candidate.package.version, candidate.repository, candidate.priority
+        );
+        match install::install(config, &repos, &mut state, &candidate) {
+            Ok(true) => println!("[*] {} {} installed", name, candidate.package.version),
+            Ok(false) => println!(
+                "[*] {} {} is already installed",
+                name, candidate.package.version
+            ),
+            Err(e) => {
+                eprintln!("[!] Cannot install {}: {}", name, e);
+                failed = true;
+                break;
+            }
+        }
+        // the state is saved after every package, so it always matches the links
+        if let Err(e) = state.save(config) {
+            eprintln!("[!] Cannot save the installed state: {}", e);
+            process::exit(1);
+        }
+    }
+    // whatever got installed is recorded as a new generation
+    match Generation::commit(config, &mut state) {
+        Ok(Some(n)) => println!("[*] generation {} recorded", n),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("[!] Cannot record the generation: {}", e);
+            process::exit(1);
+        }
+    }
+    if failed {
+        process::exit(1);
     }
 }
@@ -0,0 +1,63 @@
+use std::process;
+
+use nbpm::generation::Generation;
+use nbpm::install;
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+fn main() {
+    let names: Vec<String> = nbpm::config::args();
+    if names.is_empty() {
+        eprintln!("Usage: nb-remove <package>...");
+        process::exit(1);
+    }
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let config = nebula.config();
+    let mut state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    let mut failed = false;
+    for name in &names {
+        match install::remove(config, &mut state, name) {
+            Ok(()) => println!("[*] {} removed", name),
+            Err(e) => {
+                eprintln!("[!] Cannot remove {}: {}", name, e);
+                failed = true;
+                break;
+            }
+        }
+        if let Err(e) = state.save(config) {
+            eprintln!("[!] Cannot save the installed state: {}", e);
+            process::exit(1);
+        }
+    }
+    match Generation::commit(config, &mut state) {
+        Ok(Some(n)) => println!("[*] generation {} recorded", n),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("[!] Cannot record the generation: {}", e);
+            process::exit(1);
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
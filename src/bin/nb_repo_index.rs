@@ -0,0 +1,102 @@
+use std::path::{Path, PathBuf};
+use std::process;
+
+use nbpm::repos::nebula;
+use nbpm::sign;
+
+const USAGE: &str = "Usage: nb-repo-index [--sign <secret-key>] [--valid-for <days>] <packages-dir>
+       nb-repo-index --gen-key <secret-key>
+
+Generates a nebula repository from a directory of .nbpkg packages. The package index and the
+release file are written into the given directory, which can then be served as a repository.
+
+With --sign, the release file and every package are signed with the given ed25519 key.
+With --valid-for, clients refuse the release once the given number of days has passed, so the
+repository must be indexed again before then.
+--gen-key creates a new key and prints its public part, to be added to trusted-keys.";
+
+fn main() {
+    let args: Vec<String> = nbpm::config::args();
+    let mut dir = None;
+    let mut key = None;
+    let mut valid_for = None;
+    let mut i = 0;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--sign" => match args.get(i + 1) {
+                Some(k) => {
+                    key = Some(PathBuf::from(k));
+                    i += 1;
+                }
+                None => usage(),
+            },
+            "--valid-for" => match args.get(i + 1).map(|d| d.parse::<u64>()) {
+                Some(Ok(days)) => {
+                    valid_for = Some(days * 24 * 3600);
+                    i += 1;
+                }
+                _ => usage(),
+            },
+            "--gen-key" => match args.get(i + 1) {
+                Some(k) => {
+                    gen_key(Path::new(k));
+                    return;
+                }
+                None => usage(),
+            },
+            "-h" | "--help" => usage(),
+            d if dir.is_none() => dir = Some(PathBuf::from(d)),
+            _ => usage(),
+        }
+        i += 1;
+    }
+    let dir = match dir {
+        Some(d) => d,
+        None => usage(),
+    };
+
+    // read the key before indexing, so a bad key doesn't leave a half signed repository
+    let keypair = key.map(|k| match sign::read_keypair(&k) {
+        Ok(kp) => kp,
+        Err(e) => {
+            eprintln!("[!] Cannot read key {}: {}", k.display(), e);
+            process::exit(1);
+        }
+    });
+
+    match nebula::index_directory(&dir, valid_for) {
+        Ok(release) => println!("[*] index {} generated: {}", release.index, release.hash),
+        Err(e) => {
+            eprintln!("[!] Cannot index {}: {}", dir.display(), e);
+            process::exit(1);
+        }
+    }
+
+    if let Some(keypair) = keypair {
+        match nebula::sign_repository(&dir, &keypair) {
+            Ok(()) => println!("[*] repository signed"),
+            Err(e) => {
+                eprintln!("[!] Cannot sign {}: {}", dir.display(), e);
+                process::exit(1);
+            }
+        }
+    }
+}
+
+fn gen_key(path: &Path) {
+    match sign::generate_key(path) {
+        Ok(public) => {
+            println!("[*] secret key written to {}", path.display());
+            println!("{}", public);
+        }
+        Err(e) => {
+            eprintln!("[!] Cannot generate key: {}", e);
+            process::exit(1);
+        }
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("{}", USAGE);
+    process::exit(1);
+}
@@ -0,0 +1,87 @@
+use std::process;
+
+use nbpm::generation::{self, Generation};
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+const USAGE: &str = "Usage: nb-rollback [-f|--force] [generation]
+
+Switches the destination directory back to a recorded generation (see nb-generations), the one
+before the current generation by default. The current holds are kept: held packages are not
+changed, the rollback fails instead, unless --force is given.";
+
+fn main() {
+    let args: Vec<String> = nbpm::config::args();
+    let mut force = false;
+    let mut target = None;
+    for arg in &args {
+        match arg.as_str() {
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return;
+            }
+            "-f" | "--force" => force = true,
+            n => match n.parse::<u32>() {
+                Ok(n) if target.is_none() => target = Some(n),
+                _ => {
+                    eprintln!("{}", USAGE);
+                    process::exit(1);
+                }
+            },
+        }
+    }
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let config = nebula.config();
+    let mut state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    let generations = match Generation::list(config) {
+        Ok(g) => g,
+        Err(e) => {
+            eprintln!("[!] Cannot read the generations: {}", e);
+            process::exit(1);
+        }
+    };
+    // by default, the latest generation older than the current one
+    let target = match target {
+        Some(n) => generations.iter().find(|g| g.number == n),
+        None => generations
+            .iter()
+            .rev()
+            .find(|g| state.generation.is_some_and(|c| g.number < c)),
+    };
+    let target = match target {
+        Some(g) => g,
+        None => {
+            eprintln!("[!] No generation to roll back to, see nb-generations");
+            process::exit(1);
+        }
+    };
+
+    if let Err(e) = generation::switch_to(config, &mut state, target, force) {
+        eprintln!(
+            "[!] Cannot roll back to generation {}: {}",
+            target.number, e
+        );
+        process::exit(1);
+    }
+    println!("[*] switched to generation {}", target.number);
+}
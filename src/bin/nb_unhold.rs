@@ -0,0 +1,45 @@
+use std::process;
+
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+
+fn main() {
+    let names: Vec<String> = nbpm::config::args();
+    if names.is_empty() {
+        eprintln!("Usage: nb-unhold <package>...");
+        process::exit(1);
+    }
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let mut state = match InstalledState::load(nebula.config()) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    for name in &names {
+        if state.unhold(name) {
+            println!("[*] {} unheld", name);
+        } else {
+            println!("[*] {} was not held", name);
+        }
+    }
+    if let Err(e) = state.save(nebula.config()) {
+        eprintln!("[!] Cannot save the installed state: {}", e);
+        process::exit(1);
+    }
+}
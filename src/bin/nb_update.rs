@@ -1,18 +1,53 @@
 // #[macro_use]
 // extern crate log;
-extern crate simplelog;
-
 // use std::path::Path;
 // use nbpm::debian;
+use std::process;
+
+use nbpm::lock::LockMode;
 use nbpm::Repository;
 
 fn main() {
     // set up environment
-    // let config = Configuration::from(Path::new("config.toml")).unwrap();
-    let repos = nbpm::create_repos().unwrap();
-    nbpm::initialize(&repos).unwrap();
-    for repo in repos {
-        repo.update().unwrap();
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Write) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let repos = match nebula.repos() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("[!] Cannot read the repositories: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = nebula.initialize(&repos) {
+        eprintln!("[!] Cannot initialize the repositories: {}", e);
+        process::exit(1);
+    }
+    // a failed update keeps the previous index, so carry on with the other repositories
+    let mut failed = false;
+    for repo in &repos {
+        if let Err(e) = repo.update() {
+            eprintln!(
+                "[!] Cannot update {}, keeping its previous index: {}",
+                repo.name(),
+                e
+            );
+            failed = true;
+        }
+    }
+    if failed {
+        process::exit(1);
     }
     println!("[*] repositories updated");
 
@@ -0,0 +1,115 @@
+use std::process;
+
+use nbpm::generation::Generation;
+use nbpm::install;
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+use nbpm::upgrade;
+
+fn main() {
+    // upgrade the given packages, or every installed package
+    let args: Vec<String> = nbpm::config::args();
+    let dry_run = args.iter().any(|a| a == "-n" || a == "--dry-run");
+    let names: Vec<String> = args
+        .into_iter()
+        .filter(|a| a != "-n" && a != "--dry-run")
+        .collect();
+    let names = if names.is_empty() {
+        None
+    } else {
+        Some(&names[..])
+    };
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    // a dry run only reads the state and the repositories, other readers can run meanwhile
+    let mode = if dry_run {
+        LockMode::Read
+    } else {
+        LockMode::Write
+    };
+    let _lock = match nebula.lock(mode) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let repos = match nebula.repos() {
+        Ok(r) => r,
+        Err(e) => {
+            eprintln!("[!] Cannot read the repositories: {}", e);
+            process::exit(1);
+        }
+    };
+    if let Err(e) = nebula.initialize(&repos) {
+        eprintln!("[!] Cannot initialize the repositories: {}", e);
+        process::exit(1);
+    }
+    let config = nebula.config();
+    let mut state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+
+    let plan = match upgrade::plan_upgrade(&state, &repos, &config.pins, names) {
+        Ok(p) => p,
+        Err(e) => {
+            eprintln!("[!] Cannot plan the upgrade: {}", e);
+            process::exit(1);
+        }
+    };
+    if plan.is_empty() {
+        println!("[*] everything is up to date");
+        return;
+    }
+    for u in &plan {
+        println!(
+            "{}: {} -> {} ({})",
+            u.installed.name,
+            u.installed.version,
+            u.candidate.package.version,
+            u.candidate.repository
+        );
+    }
+    for held in &state.held {
+        println!("{}: held", held);
+    }
+    if dry_run {
+        return;
+    }
+
+    let mut failed = false;
+    for u in &plan {
+        if let Err(e) = install::install(config, &repos, &mut state, &u.candidate) {
+            eprintln!("[!] Cannot upgrade {}: {}", u.installed.name, e);
+            failed = true;
+            break;
+        }
+        if let Err(e) = state.save(config) {
+            eprintln!("[!] Cannot save the installed state: {}", e);
+            process::exit(1);
+        }
+    }
+    // a bad upgrade can be undone with nb-rollback
+    match Generation::commit(config, &mut state) {
+        Ok(Some(n)) => println!("[*] generation {} recorded", n),
+        Ok(None) => (),
+        Err(e) => {
+            eprintln!("[!] Cannot record the generation: {}", e);
+            process::exit(1);
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+    println!("[*] {} packages upgraded", plan.len());
+}
@@ -0,0 +1,93 @@
+use std::process;
+
+use nbpm::install;
+use nbpm::lock::LockMode;
+use nbpm::state::InstalledState;
+use nbpm::verify;
+
+fn main() {
+    // verify the given packages, or every installed package
+    let names: Vec<String> = nbpm::config::args();
+
+    let nebula = match nbpm::Nebula::from_args() {
+        Ok(n) => n,
+        Err(e) => {
+            eprintln!("[!] Fatal: {}", e);
+            process::exit(1);
+        }
+    };
+    let _lock = match nebula.lock(LockMode::Read) {
+        Ok(l) => l,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+    let config = nebula.config();
+    let state = match InstalledState::load(config) {
+        Ok(s) => s,
+        Err(e) => {
+            eprintln!("[!] Cannot read the installed state: {}", e);
+            process::exit(1);
+        }
+    };
+    let packages = if names.is_empty() {
+        state.packages.iter().collect()
+    } else {
+        let mut packages = vec![];
+        for name in &names {
+            match state.get(name) {
+                Some(p) => packages.push(p),
+                None => {
+                    eprintln!("[!] {} is not installed", name);
+                    process::exit(1);
+                }
+            }
+        }
+        packages
+    };
+    let destdir = match install::destdir(config) {
+        Ok(d) => d,
+        Err(e) => {
+            eprintln!("[!] {}", e);
+            process::exit(1);
+        }
+    };
+
+    let mut failed = false;
+    for package in packages {
+        let verification = match verify::verify(config, &destdir, package) {
+            Ok(v) => v,
+            Err(e) => {
+                eprintln!("[!] Cannot verify {}: {}", package.name, e);
+                failed = true;
+                continue;
+            }
+        };
+        if !verification.issues.is_empty() {
+            eprintln!(
+                "[!] {} {}: {} problems",
+                package.name,
+                package.version,
+                verification.issues.len()
+            );
+            for issue in &verification.issues {
+                eprintln!("    {}", issue);
+            }
+            failed = true;
+        } else if !verification.checksums {
+            println!(
+                "[*] {} {}: no checksums, {} links ok",
+                package.name, package.version, verification.links
+            );
+        } else {
+            println!(
+                "[*] {} {}: {} files, {} links ok",
+                package.name, package.version, verification.files, verification.links
+            );
+        }
+    }
+    if failed {
+        process::exit(1);
+    }
+}
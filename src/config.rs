@@ -1,36 +1,117 @@
-use serde_derive::Deserialize;
-use std::fs::read_to_string;
+use serde_derive::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs::{self, read_to_string, OpenOptions};
 use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
 
 use crate::errors::NebulaError;
+use crate::policy::Pin;
 use crate::repos::{DebConfig, NebulaConfig};
+use crate::transport;
 
-#[derive(Deserialize, Debug)]
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq)]
 pub enum Arch {
     #[serde(rename = "amd64")]
     Amd64,
+    #[serde(rename = "arm64")]
+    Arm64,
+    #[serde(rename = "armhf")]
+    Armhf,
+    #[serde(rename = "i386")]
+    I386,
+    #[serde(rename = "riscv64")]
+    Riscv64,
+    #[serde(rename = "ppc64el")]
+    Ppc64el,
+    #[serde(rename = "s390x")]
+    S390x,
+    /// Architecture independent packages
+    #[serde(rename = "all")]
+    All,
 }
 
 impl Arch {
     pub fn to_str(&self) -> &str {
         match self {
             Arch::Amd64 => "amd64",
+            Arch::Arm64 => "arm64",
+            Arch::Armhf => "armhf",
+            Arch::I386 => "i386",
+            Arch::Riscv64 => "riscv64",
+            Arch::Ppc64el => "ppc64el",
+            Arch::S390x => "s390x",
+            Arch::All => "all",
         }
     }
 }
 
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
 pub struct RepoConfigs {
-    pub nebula: NebulaConfig,
-    // debian repository configs
-    // #[serde(rename = "Debian")]
-    pub debian: Option<DebConfig>,
+    pub nebula: Option<NebulaConfig>,
+    // debian-family repository configs
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub debian: Vec<DebConfig>,
 }
 
-#[derive(Deserialize, Debug)]
+/// Network settings used by every HTTP(S) transfer. They can be set globally (`[network]`) and
+/// overridden per repository (`[repositories.<name>.network]`).
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct NetworkConfig {
+    /// HTTP(S) proxy, e.g. `http://proxy.corp:3128`
+    pub proxy: Option<String>,
+    /// Hosts that must be reached without the proxy
+    #[serde(rename = "no-proxy")]
+    pub noproxy: Option<Vec<String>>,
+    /// CA bundle used to verify the peer's certificate
+    #[serde(rename = "ca-bundle")]
+    pub cabundle: Option<PathBuf>,
+    /// Client certificate and private key for TLS authentication
+    #[serde(rename = "client-cert")]
+    pub clientcert: Option<PathBuf>,
+    #[serde(rename = "client-key")]
+    pub clientkey: Option<PathBuf>,
+    /// HTTP basic auth credentials
+    pub username: Option<String>,
+    pub password: Option<String>,
+    /// netrc-style file to read credentials from, looked up by host
+    pub netrc: Option<PathBuf>,
+    /// Timeouts in seconds
+    pub timeout: Option<u64>,
+    #[serde(rename = "connect-timeout")]
+    pub connect_timeout: Option<u64>,
+}
+
+impl NetworkConfig {
+    /// Returns the settings of `self` overridden by the ones set in `other`.
+    pub fn merge(&self, other: Option<&NetworkConfig>) -> NetworkConfig {
+        let other = match other {
+            Some(o) => o,
+            None => return self.clone(),
+        };
+        NetworkConfig {
+            proxy: other.proxy.clone().or_else(|| self.proxy.clone()),
+            noproxy: other.noproxy.clone().or_else(|| self.noproxy.clone()),
+            cabundle: other.cabundle.clone().or_else(|| self.cabundle.clone()),
+            clientcert: other.clientcert.clone().or_else(|| self.clientcert.clone()),
+            clientkey: other.clientkey.clone().or_else(|| self.clientkey.clone()),
+            username: other.username.clone().or_else(|| self.username.clone()),
+            password: other.password.clone().or_else(|| self.password.clone()),
+            netrc: other.netrc.clone().or_else(|| self.netrc.clone()),
+            timeout: other.timeout.or(self.timeout),
+            connect_timeout: other.connect_timeout.or(self.connect_timeout),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 pub struct Configuration {
     // system configuration
     pub arch: Arch,
+    // architectures whose packages can be installed besides the native ones
+    #[serde(rename = "foreign-architectures", default)]
+    pub foreign_archs: Vec<Arch>,
 
     // nebula paths
     #[serde(rename = "fakeroot-dir")]
@@ -40,21 +121,649 @@ pub struct Configuration {
     #[serde(rename = "nebula-dir")]
     pub nebulahome: PathBuf,
 
+    // seconds to wait for the lock held by another nebula process, fail right away if unset
+    #[serde(rename = "lock-timeout")]
+    pub lock_timeout: Option<u64>,
+
+    // global network settings
+    #[serde(default)]
+    pub network: NetworkConfig,
+
     // repository configurations
     #[serde(rename = "repositories")]
     pub repos: RepoConfigs,
+
+    // pin rules, deciding which candidate wins when several are available
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<Pin>,
+
+    // where the configuration was loaded from, if it was loaded from a file
+    #[serde(skip)]
+    pub source: Option<ConfigSource>,
+}
+
+/// The file a configuration was loaded from, and why that file was chosen.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigSource {
+    pub path: PathBuf,
+    /// How the file was found: `--config`, `NEBULA_CONFIG`, user or system configuration
+    pub origin: String,
+}
+
+impl fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.path.display(), self.origin)
+    }
+}
+
+/// A problem found validating a configuration, see `Configuration::check`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// The configuration cannot be used
+    Error(String),
+    /// The configuration can be used, but probably does not do what was intended
+    Warning(String),
+}
+
+impl Problem {
+    pub fn is_error(&self) -> bool {
+        matches!(self, Problem::Error(_))
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Error(m) => write!(f, "error: {}", m),
+            Problem::Warning(m) => write!(f, "warning: {}", m),
+        }
+    }
+}
+
+/// Name of the environment variable that can point to the configuration file.
+pub const CONFIG_ENV: &str = "NEBULA_CONFIG";
+/// System wide configuration file.
+pub const SYSTEM_CONFIG: &str = "/etc/nebula/config.toml";
+
+/// Finds the configuration file. The locations are checked in order:
+///
+/// 1. `cli_path`, given with `--config`
+/// 2. the `NEBULA_CONFIG` environment variable
+/// 3. `$XDG_CONFIG_HOME/nebula/config.toml` (`~/.config/nebula/config.toml` if unset)
+/// 4. `/etc/nebula/config.toml`
+///
+/// An explicitly given path (1 and 2) must exist, the others are skipped if missing.
+pub fn find_config(cli_path: Option<&Path>) -> Result<ConfigSource, NebulaError> {
+    let explicit = match cli_path {
+        Some(p) => Some((p.to_path_buf(), "--config")),
+        None => env::var_os(CONFIG_ENV).map(|p| (PathBuf::from(p), CONFIG_ENV)),
+    };
+    if let Some((path, source)) = explicit {
+        return if path.is_file() {
+            Ok(ConfigSource {
+                path,
+                origin: source.to_string(),
+            })
+        } else {
+            Err(NebulaError::Config(format!(
+                "configuration file {} (from {}) not found",
+                path.display(),
+                source
+            )))
+        };
+    }
+
+    let mut candidates = vec![];
+    let xdg_home = match env::var_os("XDG_CONFIG_HOME") {
+        Some(x) if !x.is_empty() => Some(PathBuf::from(x)),
+        _ => env::var_os("HOME").map(|h| PathBuf::from(h).join(".config")),
+    };
+    if let Some(xdg_home) = xdg_home {
+        candidates.push((xdg_home.join("nebula/config.toml"), "user configuration"));
+    }
+    candidates.push((PathBuf::from(SYSTEM_CONFIG), "system configuration"));
+
+    match candidates.iter().find(|(p, _)| p.is_file()) {
+        Some((path, origin)) => Ok(ConfigSource {
+            path: path.clone(),
+            origin: origin.to_string(),
+        }),
+        None => Err(NebulaError::Config(format!(
+            "no configuration file found, searched: {}. Use --config or {} to give one",
+            candidates
+                .iter()
+                .map(|(p, _)| p.display().to_string())
+                .collect::<Vec<String>>()
+                .join(", "),
+            CONFIG_ENV
+        ))),
+    }
+}
+
+/// Returns the path given with `--config <path>` (or `--config=<path>`) in the command line
+/// arguments, if any.
+pub fn config_arg() -> Option<PathBuf> {
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(PathBuf::from(path));
+        }
+    }
+    None
+}
+
+/// Returns the command line arguments (without the program name), removing the `--config`
+/// option, which is handled when the configuration is loaded.
+pub fn args() -> Vec<String> {
+    let mut args = vec![];
+    let mut iter = env::args().skip(1);
+    while let Some(arg) = iter.next() {
+        if arg == "--config" {
+            iter.next();
+        } else if !arg.starts_with("--config=") {
+            args.push(arg);
+        }
+    }
+    args
 }
 
 impl Configuration {
+    /// Finds (see `find_config`) and loads the configuration file.
+    pub fn discover(cli_path: Option<&Path>) -> Result<Configuration, NebulaError> {
+        let source = find_config(cli_path)?;
+        debug!("using configuration file {}", source);
+        let path = source.path.clone();
+        match Configuration::from(&path) {
+            Ok(mut c) => {
+                c.source = Some(source);
+                Ok(c)
+            }
+            Err(NebulaError::TomlDe(e)) => Err(NebulaError::Config(format!(
+                "invalid configuration file {}: {}",
+                path.display(),
+                e
+            ))),
+            Err(e) => Err(NebulaError::Config(format!(
+                "cannot read configuration file {}: {}",
+                path.display(),
+                e
+            ))),
+        }
+    }
+
+    /// Returns the enabled architectures: the native one followed by the foreign ones.
+    pub fn architectures(&self) -> Vec<Arch> {
+        let mut archs = vec![self.arch];
+        for arch in &self.foreign_archs {
+            if !archs.contains(arch) {
+                archs.push(*arch);
+            }
+        }
+        archs
+    }
+
     pub fn from(path: &Path) -> Result<Configuration, NebulaError> {
         // read configuration file
         match read_to_string(path) {
             // deserialize configuration file
-            Ok(s) => match toml::from_str(s.as_str()) {
-                Ok(c) => Ok(c),
-                Err(e) => Err(NebulaError::TomlDe(e)),
-            },
+            Ok(s) => {
+                let mut config: Configuration = s.parse()?;
+                config.source = Some(ConfigSource {
+                    path: path.to_path_buf(),
+                    origin: "given path".to_string(),
+                });
+                Ok(config)
+            }
             Err(e) => Err(NebulaError::Io(e)),
         }
     }
+
+    /// Validates the configuration, returning every problem found: the directories must exist
+    /// (or be creatable), be writable and not overlap, repository URLs must be usable and
+    /// repository names unique. Unknown architectures and components are already rejected when
+    /// the configuration is parsed.
+    pub fn check(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+
+        // directories
+        check_dir(&mut problems, "nebula-dir", &self.nebulahome, true);
+        check_dir(&mut problems, "fakeroot-dir", &self.fakerootdir, true);
+        check_dir(&mut problems, "destination-dir", &self.destdir, false);
+        let (home, fakeroot, dest) = (
+            normalize(&self.nebulahome),
+            normalize(&self.fakerootdir),
+            normalize(&self.destdir),
+        );
+        // the fakeroot can live inside nebula's home, but not the other way around
+        if home.starts_with(&fakeroot) {
+            problems.push(Problem::Error(format!(
+                "nebula-dir ({}) cannot be inside fakeroot-dir ({})",
+                self.nebulahome.display(),
+                self.fakerootdir.display()
+            )));
+        }
+        for (name, path, dir) in &[
+            ("nebula-dir", &self.nebulahome, &home),
+            ("fakeroot-dir", &self.fakerootdir, &fakeroot),
+        ] {
+            if dest.starts_with(dir) || dir.starts_with(&dest) {
+                problems.push(Problem::Error(format!(
+                    "destination-dir ({}) and {} ({}) overlap",
+                    self.destdir.display(),
+                    name,
+                    path.display()
+                )));
+            }
+        }
+
+        // architectures
+        if self.arch == Arch::All {
+            problems.push(Problem::Error(
+                "arch must be a real architecture, not all".to_string(),
+            ));
+        }
+        for (i, arch) in self.foreign_archs.iter().enumerate() {
+            if *arch == self.arch || *arch == Arch::All {
+                problems.push(Problem::Warning(format!(
+                    "foreign architecture {} is always enabled",
+                    arch.to_str()
+                )));
+            } else if self.foreign_archs[..i].contains(arch) {
+                problems.push(Problem::Warning(format!(
+                    "foreign architecture {} is listed more than once",
+                    arch.to_str()
+                )));
+            }
+        }
+
+        // repositories
+        check_network(&mut problems, "[network]", &self.network);
+        let mut names = vec![];
+        if let Some(nebula) = &self.repos.nebula {
+            names.push("nebula");
+            if let Err(e) = check_url(&nebula.repository) {
+                problems.push(Problem::Error(format!("repository nebula: {}", e)));
+            }
+            for key in &nebula.trusted_keys {
+                if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+                    problems.push(Problem::Error(format!(
+                        "repository nebula: malformed trusted key {}",
+                        key
+                    )));
+                }
+            }
+            if let Some(net) = &nebula.network {
+                check_network(&mut problems, "repository nebula", net);
+            }
+        }
+        for deb in &self.repos.debian {
+            let context = format!("repository {}", deb.name);
+            if deb.name.is_empty() || deb.name.contains('/') || deb.name.starts_with('.') {
+                problems.push(Problem::Error(format!(
+                    "invalid repository name '{}', it's used as a directory name",
+                    deb.name
+                )));
+            } else if names.contains(&deb.name.as_str()) {
+                problems.push(Problem::Error(format!(
+                    "there is more than one repository named {}",
+                    deb.name
+                )));
+            }
+            names.push(&deb.name);
+            if let Err(e) = check_url(&deb.repository) {
+                problems.push(Problem::Error(format!("{}: {}", context, e)));
+            }
+            if deb.suite.is_empty() || deb.suite.contains(char::is_whitespace) {
+                problems.push(Problem::Error(format!(
+                    "{}: invalid suite '{}'",
+                    context, deb.suite
+                )));
+            }
+            if deb.components.is_empty() {
+                problems.push(Problem::Error(format!("{}: no components given", context)));
+            }
+            for (i, comp) in deb.components.iter().enumerate() {
+                if deb.components[..i].contains(comp) {
+                    problems.push(Problem::Warning(format!(
+                        "{}: component {} is listed more than once",
+                        context,
+                        comp.to_str()
+                    )));
+                }
+            }
+            if let Some(net) = &deb.network {
+                check_network(&mut problems, &context, net);
+            }
+        }
+
+        // pins
+        for pin in &self.pins {
+            if let Some(repo) = &pin.repository {
+                if !names.contains(&repo.as_str()) {
+                    problems.push(Problem::Warning(format!(
+                        "pin for {} refers to an unknown repository {}",
+                        pin.package, repo
+                    )));
+                }
+            }
+        }
+
+        problems
+    }
+
+    /// Validates the configuration (see `check`). Fails if any error is found, otherwise the
+    /// warnings are returned.
+    pub fn validate(&self) -> Result<Vec<Problem>, NebulaError> {
+        let (errors, warnings): (Vec<Problem>, Vec<Problem>) =
+            self.check().into_iter().partition(|p| p.is_error());
+        if errors.is_empty() {
+            return Ok(warnings);
+        }
+        let mut msg = "invalid configuration".to_string();
+        if let Some(source) = &self.source {
+            msg.push_str(&format!(" {}", source.path.display()));
+        }
+        for e in errors {
+            if let Problem::Error(m) = e {
+                msg.push_str(&format!("\n  {}", m));
+            }
+        }
+        Err(NebulaError::Config(msg))
+    }
+
+    /// Returns a builder for a configuration rooted at `nebula_dir`.
+    pub fn builder<P: Into<PathBuf>>(nebula_dir: P) -> ConfigBuilder {
+        ConfigBuilder::new(nebula_dir)
+    }
+}
+
+impl FromStr for Configuration {
+    type Err = NebulaError;
+
+    /// Parses the content of a configuration file.
+    fn from_str(s: &str) -> Result<Configuration, NebulaError> {
+        match toml::from_str(s) {
+            Ok(c) => Ok(c),
+            Err(e) => Err(NebulaError::TomlDe(e)),
+        }
+    }
+}
+
+/// Builds a `Configuration` programmatically. By default the native architecture is amd64, the
+/// fakeroot directory is `<nebula-dir>/pkgs`, the destination directory is `/usr` and there are
+/// no repositories.
+pub struct ConfigBuilder {
+    config: Configuration,
+}
+
+impl ConfigBuilder {
+    pub fn new<P: Into<PathBuf>>(nebula_dir: P) -> ConfigBuilder {
+        let nebulahome = nebula_dir.into();
+        ConfigBuilder {
+            config: Configuration {
+                arch: Arch::Amd64,
+                foreign_archs: vec![],
+                fakerootdir: nebulahome.join("pkgs"),
+                destdir: PathBuf::from("/usr"),
+                nebulahome,
+                lock_timeout: None,
+                network: NetworkConfig::default(),
+                repos: RepoConfigs::default(),
+                pins: vec![],
+                source: None,
+            },
+        }
+    }
+
+    pub fn arch(mut self, arch: Arch) -> ConfigBuilder {
+        self.config.arch = arch;
+        self
+    }
+
+    pub fn foreign_arch(mut self, arch: Arch) -> ConfigBuilder {
+        self.config.foreign_archs.push(arch);
+        self
+    }
+
+    pub fn fakeroot_dir<P: Into<PathBuf>>(mut self, dir: P) -> ConfigBuilder {
+        self.config.fakerootdir = dir.into();
+        self
+    }
+
+    pub fn dest_dir<P: Into<PathBuf>>(mut self, dir: P) -> ConfigBuilder {
+        self.config.destdir = dir.into();
+        self
+    }
+
+    pub fn lock_timeout(mut self, secs: u64) -> ConfigBuilder {
+        self.config.lock_timeout = Some(secs);
+        self
+    }
+
+    pub fn network(mut self, network: NetworkConfig) -> ConfigBuilder {
+        self.config.network = network;
+        self
+    }
+
+    pub fn nebula_repo(mut self, repo: NebulaConfig) -> ConfigBuilder {
+        self.config.repos.nebula = Some(repo);
+        self
+    }
+
+    pub fn debian_repo(mut self, repo: DebConfig) -> ConfigBuilder {
+        self.config.repos.debian.push(repo);
+        self
+    }
+
+    pub fn pin(mut self, pin: Pin) -> ConfigBuilder {
+        self.config.pins.push(pin);
+        self
+    }
+
+    pub fn build(self) -> Configuration {
+        self.config
+    }
+}
+
+/// Checks that `path` is a writable directory, or that it can be created if `create` is set.
+fn check_dir(problems: &mut Vec<Problem>, name: &str, path: &Path, create: bool) {
+    if path.is_relative() {
+        problems.push(Problem::Warning(format!(
+            "{} ({}) is a relative path, it depends on the working directory",
+            name,
+            path.display()
+        )));
+    }
+    if path.exists() {
+        if !path.is_dir() {
+            problems.push(Problem::Error(format!(
+                "{} ({}) is not a directory",
+                name,
+                path.display()
+            )));
+        } else if !is_writable(path) {
+            problems.push(Problem::Error(format!(
+                "{} ({}) is not writable",
+                name,
+                path.display()
+            )));
+        }
+        return;
+    }
+    if !create {
+        problems.push(Problem::Error(format!(
+            "{} ({}) does not exist, please create it or change the configuration",
+            name,
+            path.display()
+        )));
+        return;
+    }
+    // the directory will be created inside its nearest existing ancestor
+    match path.ancestors().skip(1).find(|a| a.exists()) {
+        Some(parent) if parent.is_dir() && is_writable(parent) => (),
+        Some(parent) => problems.push(Problem::Error(format!(
+            "{} ({}) cannot be created, {} is not a writable directory",
+            name,
+            path.display(),
+            parent.display()
+        ))),
+        None => problems.push(Problem::Error(format!(
+            "{} ({}) cannot be created",
+            name,
+            path.display()
+        ))),
+    }
+}
+
+/// Whether files can be created in the given directory, checked by creating (and removing) a
+/// probe file.
+fn is_writable(dir: &Path) -> bool {
+    let probe = dir.join(format!(".nebula-write-test-{}", process::id()));
+    match OpenOptions::new().write(true).create_new(true).open(&probe) {
+        Ok(_) => {
+            let _ = fs::remove_file(&probe);
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Returns the canonical form of the path, or its cleaned up form if it does not exist.
+fn normalize(path: &Path) -> PathBuf {
+    match path.canonicalize() {
+        Ok(p) => p,
+        Err(_) => path.components().collect(),
+    }
+}
+
+/// Checks that the repository URL is supported by a transport: http(s), file or a local
+/// absolute path.
+fn check_url(url: &str) -> Result<(), String> {
+    if url.contains(char::is_whitespace) {
+        return Err(format!("invalid URL '{}'", url));
+    }
+    if url.starts_with("http://") || url.starts_with("https://") {
+        match transport::url_host(url) {
+            Some(_) => Ok(()),
+            None => Err(format!("URL {} has no host", url)),
+        }
+    } else if let Some(path) = url.strip_prefix("file://") {
+        if path.starts_with('/') {
+            Ok(())
+        } else {
+            Err(format!("URL {} must hold an absolute path", url))
+        }
+    } else if url.starts_with('/') {
+        Ok(())
+    } else if let Some(i) = url.find("://") {
+        Err(format!(
+            "URL {} has an unsupported scheme {}",
+            url,
+            &url[..i]
+        ))
+    } else {
+        Err(format!("'{}' is neither a URL nor an absolute path", url))
+    }
+}
+
+/// Checks that the files referenced by the network settings exist.
+fn check_network(problems: &mut Vec<Problem>, context: &str, net: &NetworkConfig) {
+    let files = [
+        ("ca-bundle", &net.cabundle),
+        ("client-cert", &net.clientcert),
+        ("client-key", &net.clientkey),
+        ("netrc", &net.netrc),
+    ];
+    for (name, file) in files.iter() {
+        if let Some(file) = file {
+            if !file.is_file() {
+                problems.push(Problem::Error(format!(
+                    "{}: {} file {} not found",
+                    context,
+                    name,
+                    file.display()
+                )));
+            }
+        }
+    }
+    if net.clientkey.is_some() && net.clientcert.is_none() {
+        problems.push(Problem::Warning(format!(
+            "{}: client-key is ignored without a client-cert",
+            context
+        )));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Arch, Configuration, Problem};
+    use crate::repos::debian::Component;
+    use crate::repos::DebConfig;
+    use crate::testutil::temp_dir;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn sample_configuration() {
+        let config = Configuration::from(Path::new("config.toml")).unwrap();
+        assert_eq!(config.repos.debian.len(), 2);
+        assert_eq!(config.repos.debian[1].name, "bookworm-security");
+    }
+
+    #[test]
+    fn configuration_builder() {
+        let config = Configuration::builder("/tmp/nebula")
+            .arch(Arch::Arm64)
+            .foreign_arch(Arch::Armhf)
+            .dest_dir("/tmp/nebula-root")
+            .build();
+        assert_eq!(config.fakerootdir, Path::new("/tmp/nebula/pkgs"));
+        assert_eq!(config.architectures(), vec![Arch::Arm64, Arch::Armhf]);
+        assert!(config.repos.debian.is_empty());
+    }
+
+    #[test]
+    fn configuration_problems() {
+        let tmp = temp_dir("config");
+        let dir = tmp.path();
+        fs::create_dir_all(dir).unwrap();
+        let repo = |name: &str, url: &str| DebConfig {
+            name: name.to_string(),
+            repository: url.to_string(),
+            suite: "unstable".to_string(),
+            components: vec![Component::Main],
+            priority: None,
+            network: None,
+        };
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir)
+            .foreign_arch(Arch::Amd64)
+            .debian_repo(repo("unstable", "http://ftp.debian.org/debian"))
+            .debian_repo(repo("unstable", "ftp.debian.org/debian"))
+            .build();
+        let problems = config.check();
+        let errors: Vec<String> = problems
+            .iter()
+            .filter(|p| p.is_error())
+            .map(|p| p.to_string())
+            .collect();
+        assert_eq!(errors.len(), 4, "{:?}", errors);
+        assert!(errors[0].contains("destination-dir"));
+        assert!(errors[2].contains("more than one repository named unstable"));
+        assert!(errors[3].contains("neither a URL nor an absolute path"));
+        assert!(problems.contains(&Problem::Warning(
+            "foreign architecture amd64 is always enabled".to_string()
+        )));
+        assert!(config.validate().is_err());
+
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir.join("root"))
+            .build();
+        assert!(config.check()[0].to_string().contains("does not exist"));
+        fs::create_dir_all(dir.join("root")).unwrap();
+        assert_eq!(config.validate().unwrap(), vec![]);
+    }
 }
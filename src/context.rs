@@ -0,0 +1,157 @@
+use simplelog::*;
+use std::fs::{create_dir_all, OpenOptions};
+use std::path::Path;
+use std::time::Duration;
+
+use crate::config::{self, Configuration};
+use crate::generation::{self, SwitchJournal};
+use crate::lock::{Lock, LockMode};
+use crate::repos::{Registry, RepoConstructor, RepoType, Repositories, Repository};
+use crate::state::InstalledState;
+use crate::NebulaError;
+
+/// Name of the log file, inside nebula's home.
+pub const LOG_FILE: &str = "nebula.log";
+
+/// A nebula context: everything the library does is done through a context, holding the
+/// configuration of the root it manages. Several contexts (roots) can live in the same process.
+///
+/// ```no_run
+/// use nbpm::config::Configuration;
+/// use nbpm::Nebula;
+///
+/// let nebula = Nebula::new(Configuration::builder("/tmp/nebula").dest_dir("/tmp/root").build());
+/// let repos = nebula.repos().unwrap();
+/// nebula.initialize(&repos).unwrap();
+/// ```
+#[derive(Debug)]
+pub struct Nebula {
+    config: Configuration,
+    registry: Registry,
+}
+
+impl Nebula {
+    pub fn new(config: Configuration) -> Nebula {
+        Nebula {
+            config,
+            registry: Registry::default(),
+        }
+    }
+
+    /// Creates a context from the given configuration file.
+    pub fn from_file(path: &Path) -> Result<Nebula, NebulaError> {
+        Ok(Nebula::new(Configuration::from(path)?))
+    }
+
+    /// Creates a context from the content of a configuration file.
+    pub fn from_config_str(s: &str) -> Result<Nebula, NebulaError> {
+        Ok(Nebula::new(s.parse()?))
+    }
+
+    /// Creates a context from the configuration file given with `--config` in the command line
+    /// arguments, or found in the default locations (see `config::find_config`).
+    pub fn from_args() -> Result<Nebula, NebulaError> {
+        Ok(Nebula::new(Configuration::discover(
+            config::config_arg().as_deref(),
+        )?))
+    }
+
+    pub fn config(&self) -> &Configuration {
+        &self.config
+    }
+
+    /// Registers a repository backend, see `repos::Registry`.
+    pub fn register(&mut self, repo_type: RepoType, constructor: RepoConstructor) {
+        self.registry.register(repo_type, constructor);
+    }
+
+    /// Creates the repositories configured in the context, of every registered backend.
+    pub fn repos(&self) -> Result<Repositories<'_>, NebulaError> {
+        self.registry.create(&self.config)
+    }
+
+    /// Takes the lock of nebula's home (see `lock::Lock`), waiting for it up to the configured
+    /// `lock-timeout`. Operations changing the state or the repositories must hold it in write
+    /// mode, the ones reading them in read mode.
+    ///
+    /// Writers first undo a generation switch that was interrupted (see
+    /// `generation::recover_switch`), so they never start from a half switched destination.
+    pub fn lock(&self, mode: LockMode) -> Result<Lock, NebulaError> {
+        // do not create nebula's home anywhere unexpected
+        self.config.validate()?;
+        let timeout = self.config.lock_timeout.map(Duration::from_secs);
+        let lock = Lock::acquire(&self.config.nebulahome, mode, timeout)?;
+        if mode == LockMode::Write && SwitchJournal::path(&self.config).is_file() {
+            let mut state = InstalledState::load(&self.config)?;
+            generation::recover_switch(&self.config, &mut state)?;
+        }
+        Ok(lock)
+    }
+
+    /// Validates the configuration and checks if all nebula directories are present, if not,
+    /// creates the needed directories. It also creates the needed files, such as the logger.
+    pub fn initialize(&self, repos: &[impl Repository]) -> Result<(), NebulaError> {
+        let config = &self.config;
+        let warnings = config.validate()?;
+        // check nebula's home and cache directory (inside home directory)
+        let fs_err = |p: &Path, e: std::io::Error| {
+            NebulaError::Fs(format!("cannot create {}: {}", p.display(), e))
+        };
+        let repo_dir = config.nebulahome.join("repo");
+        if !repo_dir.is_dir() {
+            create_dir_all(&repo_dir).map_err(|e| fs_err(&repo_dir, e))?; // create home/repo
+        }
+
+        // check fakeroot
+        if !config.fakerootdir.is_dir() {
+            create_dir_all(&config.fakerootdir).map_err(|e| fs_err(&config.fakerootdir, e))?;
+        }
+
+        // create the logger, it can only be set once per process
+        let log_path = config.nebulahome.join(LOG_FILE);
+        // other processes may be logging too (readers share the lock), append to the log
+        let log_file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&log_path)
+            .map_err(|e| fs_err(&log_path, e))?;
+        let _ = CombinedLogger::init(vec![
+            TermLogger::new(LevelFilter::Warn, Config::default(), TerminalMode::Mixed),
+            WriteLogger::new(LevelFilter::Debug, Config::default(), log_file),
+        ]);
+        for warning in warnings {
+            warn!("configuration {}", warning);
+        }
+
+        // initi all repos
+        for repo in repos {
+            repo.initialize()?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::testutil::temp_dir;
+    use crate::Nebula;
+    use std::fs;
+
+    #[test]
+    fn initialize_temporary_root() {
+        let tmp = temp_dir("context");
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("root")).unwrap();
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir.join("root"))
+            .build();
+        let nebula = Nebula::new(config);
+        let repos = nebula.repos().unwrap();
+        assert!(repos.is_empty());
+        nebula.initialize(&repos).unwrap();
+        assert!(dir.join("nebula/repo").is_dir());
+        assert!(dir.join("nebula/pkgs").is_dir());
+    }
+}
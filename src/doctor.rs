@@ -0,0 +1,332 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::config::Configuration;
+use crate::install;
+use crate::state::{InstalledPackage, InstalledState};
+use crate::store::Store;
+use crate::NebulaError;
+
+/// A problem of the links of the destination directory.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Problem {
+    /// A link into the store whose target does not exist
+    Broken(PathBuf),
+    /// A link into a tree of the store no installed package uses
+    Unknown(PathBuf),
+    /// A link into the tree of an installed package that is not among its recorded links, left by
+    /// an interrupted link step: (link, package)
+    Unrecorded(PathBuf, String),
+    /// A recorded link of an installed package that does not exist: (link, package)
+    Missing(PathBuf, String),
+    /// A recorded link of an installed package hidden by a folded directory, or whose folded
+    /// directory was unfolded or linked elsewhere in the store: (link, package)
+    Fold(PathBuf, String),
+    /// A recorded link of an installed package replaced by a file nebula does not manage, which is
+    /// left alone: (link, package)
+    Replaced(PathBuf, String),
+    /// The tree of an installed package is no longer in the store, the package must be installed
+    /// again
+    MissingTree(String),
+}
+
+impl Problem {
+    /// Whether `repair` fixes the problem.
+    pub fn fixable(&self) -> bool {
+        !matches!(self, Problem::Replaced(..) | Problem::MissingTree(_))
+    }
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Problem::Broken(l) => write!(f, "broken link {}", l.display()),
+            Problem::Unknown(l) => write!(f, "{} links to an unknown package tree", l.display()),
+            Problem::Unrecorded(l, p) => {
+                write!(f, "{} links to {} but is not recorded", l.display(), p)
+            }
+            Problem::Missing(l, p) => write!(f, "missing link {} of {}", l.display(), p),
+            Problem::Fold(l, p) => write!(
+                f,
+                "link {} of {} does not match the folded directories",
+                l.display(),
+                p
+            ),
+            Problem::Replaced(l, p) => write!(f, "link {} of {} was replaced", l.display(), p),
+            Problem::MissingTree(p) => write!(f, "the package tree of {} is gone", p),
+        }
+    }
+}
+
+/// Scans the destination directory for links into the store that are broken, point to trees no
+/// installed package uses or are not recorded, and checks the recorded links of every installed
+/// package.
+pub fn diagnose(
+    config: &Configuration,
+    state: &InstalledState,
+) -> Result<Vec<Problem>, NebulaError> {
+    let destdir = install::destdir(config)?;
+    let store = Store::new(config);
+    // links point to the canonical store, see `create_links`
+    let store_dir = fs::canonicalize(store.dir()).unwrap_or_else(|_| store.dir().to_path_buf());
+    let home = fs::canonicalize(&config.nebulahome).unwrap_or_else(|_| config.nebulahome.clone());
+    let owners: HashMap<&str, &InstalledPackage> = state
+        .packages
+        .iter()
+        .filter_map(|p| p.store.as_deref().map(|s| (s, p)))
+        .collect();
+
+    let mut problems = vec![];
+    let walker = WalkDir::new(&destdir)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        // nebula's own files could be under the destination directory
+        .filter_entry(|e| !e.path().starts_with(&store_dir) && !e.path().starts_with(&home));
+    for entry in walker {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => {
+                warn!("{}", e);
+                continue;
+            }
+        };
+        if !entry.path_is_symlink() {
+            continue;
+        }
+        let path = entry.path();
+        let target = fs::read_link(path).map_err(NebulaError::Io)?;
+        let tree = match target.strip_prefix(&store_dir) {
+            Ok(t) => t
+                .components()
+                .next()
+                .map(|c| c.as_os_str().to_string_lossy()),
+            // not a link of nebula
+            Err(_) => continue,
+        };
+        match tree.as_deref().and_then(|t| owners.get(t)) {
+            None => problems.push(Problem::Unknown(path.to_path_buf())),
+            Some(_) if !target.exists() => problems.push(Problem::Broken(path.to_path_buf())),
+            Some(owner) if !owner.links.contains(&relative(&destdir, path)) => {
+                problems.push(Problem::Unrecorded(path.to_path_buf(), owner.name.clone()))
+            }
+            Some(_) => (),
+        }
+    }
+
+    for package in &state.packages {
+        let entry = match &package.store {
+            Some(e) => e,
+            None => continue,
+        };
+        if !store.path(entry).is_dir() {
+            problems.push(Problem::MissingTree(package.name.clone()));
+            continue;
+        }
+        let data_dir = fs::canonicalize(store.data_dir(entry)).map_err(NebulaError::Io)?;
+        for link in &package.links {
+            let path = destdir.join(link);
+            let problem = |p: fn(PathBuf, String) -> Problem| p(path.clone(), package.name.clone());
+            let folded_above = path
+                .ancestors()
+                .skip(1)
+                .take_while(|a| *a != destdir)
+                .any(|a| fs::symlink_metadata(a).is_ok_and(|m| m.file_type().is_symlink()));
+            if folded_above {
+                problems.push(problem(Problem::Fold));
+                continue;
+            }
+            let meta = match fs::symlink_metadata(&path) {
+                Ok(m) => m,
+                Err(_) => {
+                    problems.push(problem(Problem::Missing));
+                    continue;
+                }
+            };
+            if meta.file_type().is_symlink() {
+                let target = fs::read_link(&path).map_err(NebulaError::Io)?;
+                if target == data_dir.join(link) {
+                    continue;
+                } else if target.starts_with(&store_dir) {
+                    problems.push(problem(Problem::Fold));
+                } else {
+                    problems.push(problem(Problem::Replaced));
+                }
+            } else if meta.is_dir() && data_dir.join(link).is_dir() {
+                // unfolded without updating the state
+                problems.push(problem(Problem::Fold));
+            } else {
+                problems.push(problem(Problem::Replaced));
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Repairs the problems found by `diagnose`: the broken, unknown and unrecorded links are removed
+/// and the installed packages with missing or inconsistent links are linked again from their
+/// trees. Packages whose tree is gone lose their links, they must be installed again. Replaced
+/// links are left alone. The state is updated, but not saved.
+pub fn repair(
+    config: &Configuration,
+    state: &mut InstalledState,
+    problems: &[Problem],
+) -> Result<(), NebulaError> {
+    let destdir = install::destdir(config)?;
+    let store = Store::new(config);
+    let mut relink = vec![];
+    for problem in problems {
+        match problem {
+            Problem::Broken(link) | Problem::Unknown(link) | Problem::Unrecorded(link, _) => {
+                debug!("removing link {}", link.display());
+                fs::remove_file(link).map_err(|e| {
+                    NebulaError::Fs(format!("cannot remove {}: {}", link.display(), e))
+                })?;
+            }
+            Problem::Missing(_, name) | Problem::Fold(_, name) => relink.push(name.clone()),
+            Problem::MissingTree(name) => {
+                if let Some(mut package) = state.get(name).cloned() {
+                    package.links.clear();
+                    state.record_install(package)?;
+                }
+            }
+            Problem::Replaced(..) => (),
+        }
+    }
+    // broken links of installed packages are dropped from their links as well
+    for package in &state.packages {
+        let broken = problems.iter().any(|p| match p {
+            Problem::Broken(link) => package.links.contains(&relative(&destdir, link)),
+            _ => false,
+        });
+        if broken
+            && package
+                .store
+                .as_ref()
+                .is_some_and(|s| store.path(s).is_dir())
+        {
+            relink.push(package.name.clone());
+        }
+    }
+    relink.sort();
+    relink.dedup();
+
+    for name in relink {
+        let mut package = match state.get(&name) {
+            Some(p) if p.store.is_some() => p.clone(),
+            _ => continue,
+        };
+        let entry = package.store.as_ref().unwrap();
+        let data_dir = fs::canonicalize(store.data_dir(entry)).map_err(NebulaError::Io)?;
+        // only the links still in place are removed before linking the whole tree again
+        package
+            .links
+            .retain(|l| fs::read_link(destdir.join(l)).is_ok_and(|t| t == data_dir.join(l)));
+        state.record_install(package.clone())?;
+        info!("linking {} again", name);
+        package.links.clear();
+        install::activate(config, state, package)?;
+    }
+    Ok(())
+}
+
+fn relative(destdir: &Path, path: &Path) -> String {
+    path.strip_prefix(destdir)
+        .unwrap_or(path)
+        .to_string_lossy()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::doctor::{diagnose, repair, Problem};
+    use crate::install::{activate, destdir};
+    use crate::state::InstalledState;
+    use crate::store::Store;
+    use crate::testutil::{store_tree, temp_dir};
+    use std::fs;
+    use std::os::unix;
+
+    #[test]
+    fn diagnose_and_repair_links() {
+        let tmp = temp_dir("doctor");
+        let dir = tmp.path();
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir.join("root"))
+            .build();
+        fs::create_dir_all(dir.join("root")).unwrap();
+        let store = Store::new(&config);
+        let package = |name: &str, files: &[&str]| {
+            let files: Vec<(&str, &str)> = files.iter().map(|f| (*f, name)).collect();
+            store_tree(&config.fakerootdir, name, "1.0", &files)
+        };
+        let mut state = InstalledState::default();
+        activate(
+            &config,
+            &mut state,
+            package("proba", &["bin/proba", "share/proba/doc"]),
+        )
+        .unwrap();
+        activate(&config, &mut state, package("other", &["bin/other"])).unwrap();
+        activate(&config, &mut state, package("gone", &["lib/gone"])).unwrap();
+        let destdir = destdir(&config).unwrap();
+        assert!(diagnose(&config, &state).unwrap().is_empty());
+
+        // the unfolding of bin was not recorded, share was removed, gone's tree was deleted and
+        // links into an unknown tree and to a missing file were left behind
+        let mut proba = state.get("proba").unwrap().clone();
+        assert_eq!(proba.links, vec!["bin/proba", "share"]);
+        proba.links = vec!["bin".to_string(), "share".to_string()];
+        state.record_install(proba).unwrap();
+        fs::remove_file(destdir.join("share")).unwrap();
+        fs::remove_dir_all(store.path(state.get("gone").unwrap().store.as_ref().unwrap())).unwrap();
+        let ghost = package("ghost", &["bin/ghost"]);
+        let ghost_target = store
+            .data_dir(ghost.store.as_ref().unwrap())
+            .join("bin/ghost");
+        unix::fs::symlink(
+            fs::canonicalize(ghost_target).unwrap(),
+            destdir.join("bin/ghost"),
+        )
+        .unwrap();
+        let proba_tree =
+            fs::canonicalize(store.data_dir(state.get("proba").unwrap().store.as_ref().unwrap()))
+                .unwrap();
+        unix::fs::symlink(proba_tree.join("bin/nothing"), destdir.join("bin/nothing")).unwrap();
+
+        let problems = diagnose(&config, &state).unwrap();
+        let expected = vec![
+            Problem::Unknown(destdir.join("bin/ghost")),
+            Problem::Broken(destdir.join("bin/nothing")),
+            Problem::Unrecorded(destdir.join("bin/proba"), "proba".to_string()),
+            Problem::Broken(destdir.join("lib")),
+            Problem::MissingTree("gone".to_string()),
+            Problem::Fold(destdir.join("bin"), "proba".to_string()),
+            Problem::Missing(destdir.join("share"), "proba".to_string()),
+        ];
+        assert_eq!(problems, expected);
+
+        repair(&config, &mut state, &problems).unwrap();
+        assert_eq!(
+            diagnose(&config, &state).unwrap(),
+            vec![Problem::MissingTree("gone".to_string())]
+        );
+        assert_eq!(
+            state.get("proba").unwrap().links,
+            vec!["bin/proba", "share"]
+        );
+        assert!(state.get("gone").unwrap().links.is_empty());
+        assert!(!destdir.join("bin/ghost").exists());
+        assert_eq!(
+            fs::read_to_string(destdir.join("share/proba/doc")).unwrap(),
+            "proba"
+        );
+        assert_eq!(
+            fs::read_to_string(destdir.join("bin/other")).unwrap(),
+            "other"
+        );
+    }
+}
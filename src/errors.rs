@@ -1,12 +1,61 @@
+use std::fmt;
+
 #[derive(Debug)]
 pub enum NebulaError {
     Io(std::io::Error),
     TomlDe(toml::de::Error),
     RepoConfigNotFound,
     IncorrectHash,
+    /// Signature verification failure: missing or malformed signature, or not signed by any
+    /// trusted key
+    BadSignature(String),
     /// Command execution error
     CmdError(String),
     /// File system related error
     Fs(String),
+    /// Error fetching a resource through a transport (HTTP, file...)
+    Transport(String),
     DependencyParseError,
+    /// Error building a package from a recipe
+    BuildError(String),
+    /// The given package is not installed
+    PackageNotInstalled(String),
+    /// An operation would change a held package, the message names the hold
+    PackageHeld(String),
+    /// Missing or invalid configuration
+    Config(String),
+    /// Error updating an index with deltas (Debian PDiff)
+    Pdiff(String),
+    /// Another nebula process holds the lock, the message names it
+    Locked(String),
+    /// The given generation does not exist (see `generation`)
+    GenerationNotFound(u32),
+    /// A repository served an expired release, or one older than the release already fetched
+    StaleRelease(String),
 }
+
+impl fmt::Display for NebulaError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NebulaError::Io(e) => write!(f, "I/O error: {}", e),
+            NebulaError::TomlDe(e) => write!(f, "TOML error: {}", e),
+            NebulaError::RepoConfigNotFound => write!(f, "repository configuration not found"),
+            NebulaError::IncorrectHash => write!(f, "incorrect hash"),
+            NebulaError::BadSignature(m) => write!(f, "bad signature: {}", m),
+            NebulaError::CmdError(m) => write!(f, "command failed: {}", m),
+            NebulaError::Fs(m) => write!(f, "file system error: {}", m),
+            NebulaError::Transport(m) => write!(f, "download failed: {}", m),
+            NebulaError::DependencyParseError => write!(f, "cannot parse dependencies"),
+            NebulaError::BuildError(m) => write!(f, "build failed: {}", m),
+            NebulaError::PackageNotInstalled(p) => write!(f, "package {} is not installed", p),
+            NebulaError::PackageHeld(m) => write!(f, "held package: {}", m),
+            NebulaError::Config(m) => write!(f, "configuration error: {}", m),
+            NebulaError::Pdiff(m) => write!(f, "cannot apply index delta: {}", m),
+            NebulaError::Locked(m) => write!(f, "nebula is locked: {}", m),
+            NebulaError::GenerationNotFound(n) => write!(f, "generation {} does not exist", n),
+            NebulaError::StaleRelease(m) => write!(f, "stale release: {}", m),
+        }
+    }
+}
+
+impl std::error::Error for NebulaError {}
@@ -0,0 +1,193 @@
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::config::Configuration;
+use crate::generation::Generation;
+use crate::state::InstalledState;
+use crate::store::Store;
+use crate::NebulaError;
+
+/// What a garbage collection removed (or would remove, in a dry run).
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Collected {
+    /// Deleted generations
+    pub generations: Vec<u32>,
+    /// Unreferenced trees of the store
+    pub trees: Vec<String>,
+    /// Leftovers of interrupted operations: partially unpacked trees and downloaded archives
+    pub leftovers: Vec<PathBuf>,
+    /// Space taken by all of them, in bytes
+    pub bytes: u64,
+}
+
+/// Trees of the store referenced by the installed state or by a recorded generation, apart from
+/// the `ignored` ones.
+pub fn referenced(
+    config: &Configuration,
+    state: &InstalledState,
+    ignored: &[u32],
+) -> Result<HashSet<String>, NebulaError> {
+    let mut trees = HashSet::new();
+    let mut generations = Generation::list(config)?;
+    generations.retain(|g| !ignored.contains(&g.number));
+    let states = std::iter::once(state).chain(generations.iter().map(|g| &g.state));
+    for state in states {
+        trees.extend(state.packages.iter().filter_map(|p| p.store.clone()));
+    }
+    Ok(trees)
+}
+
+/// Removes the trees of the store not referenced by the installed state or any generation, and
+/// the leftovers of interrupted installs. With `keep`, the old generations are deleted first (see
+/// `Generation::prune`). Nothing is removed in a dry run.
+pub fn collect(
+    config: &Configuration,
+    state: &InstalledState,
+    keep: Option<usize>,
+    dry_run: bool,
+) -> Result<Collected, NebulaError> {
+    let store = Store::new(config);
+    let mut collected = Collected::default();
+    if let Some(keep) = keep {
+        collected.generations = Generation::prune(config, state, keep, dry_run)?;
+    }
+    let referenced = referenced(config, state, &collected.generations)?;
+    for entry in store.entries()? {
+        if !referenced.contains(&entry) {
+            collected.trees.push(entry);
+        }
+    }
+    // trees whose unpacking was interrupted, and archives left by interrupted installs
+    let downloads = store.dir().join(".downloads");
+    collected.leftovers = list_dir(store.dir())?
+        .into_iter()
+        .filter(|p| {
+            p.file_name()
+                .map(|n| n.to_string_lossy())
+                .is_some_and(|n| n.starts_with('.') && n.ends_with(".tmp"))
+        })
+        .collect();
+    if downloads.is_dir() {
+        collected.leftovers.extend(list_dir(&downloads)?);
+    }
+
+    let paths: Vec<PathBuf> = collected
+        .trees
+        .iter()
+        .map(|t| store.path(t))
+        .chain(collected.leftovers.iter().cloned())
+        .collect();
+    for path in paths {
+        collected.bytes += disk_usage(&path);
+        if dry_run {
+            continue;
+        }
+        debug!("removing {}", path.display());
+        let removed = if path.is_dir() {
+            fs::remove_dir_all(&path)
+        } else {
+            fs::remove_file(&path)
+        };
+        if let Err(e) = removed {
+            return Err(NebulaError::Fs(format!(
+                "cannot remove {}: {}",
+                path.display(),
+                e
+            )));
+        }
+    }
+    Ok(collected)
+}
+
+fn list_dir(dir: &Path) -> Result<Vec<PathBuf>, NebulaError> {
+    let read_err = |e: std::io::Error| NebulaError::Fs(format!("{}: {}", dir.display(), e));
+    let mut paths = vec![];
+    for entry in fs::read_dir(dir).map_err(read_err)? {
+        paths.push(entry.map_err(read_err)?.path());
+    }
+    paths.sort();
+    Ok(paths)
+}
+
+/// Size of the files of a tree, symlinks are not followed.
+pub fn disk_usage(path: &Path) -> u64 {
+    WalkDir::new(path)
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| e.metadata().ok())
+        .filter(|m| !m.is_dir())
+        .map(|m| m.len())
+        .sum()
+}
+
+/// Formats a size in bytes for humans: `512 B`, `1.5 KiB`, `12.0 MiB`...
+pub fn format_size(bytes: u64) -> String {
+    let units = ["KiB", "MiB", "GiB", "TiB"];
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < units.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, units[unit])
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::gc::{collect, format_size};
+    use crate::generation::Generation;
+    use crate::state::InstalledState;
+    use crate::testutil::{store_entry, store_tree, temp_dir};
+    use std::fs;
+
+    #[test]
+    fn collect_unreferenced_trees() {
+        let tmp = temp_dir("gc");
+        let dir = tmp.path();
+        let config = Configuration::builder(dir.join("nebula")).build();
+        let tree =
+            |name: &str| store_tree(&config.fakerootdir, name, "1.0", &[(name, "0123456789")]);
+        let mut state = InstalledState::default();
+        state.record_install(tree("old")).unwrap();
+        Generation::commit(&config, &mut state).unwrap();
+        state.record_remove("old");
+        state.record_install(tree("current")).unwrap();
+        tree("unused");
+        fs::create_dir_all(config.fakerootdir.join(".interrupted.tmp")).unwrap();
+        fs::create_dir_all(config.fakerootdir.join(".downloads")).unwrap();
+        fs::write(config.fakerootdir.join(".downloads/other.nbpkg"), "01234").unwrap();
+        fs::create_dir_all(config.fakerootdir.join("build")).unwrap();
+
+        let collected = collect(&config, &state, None, true).unwrap();
+        assert_eq!(collected.trees, vec![store_entry("unused", "1.0")]);
+        assert_eq!(collected.leftovers.len(), 2);
+        assert_eq!(collected.bytes, 15);
+        assert!(config.fakerootdir.join(&collected.trees[0]).is_dir());
+
+        // trees of recorded generations are kept
+        assert_eq!(collect(&config, &state, None, false).unwrap(), collected);
+        assert!(!config.fakerootdir.join(&collected.trees[0]).exists());
+        assert!(config.fakerootdir.join(tree("old").store.unwrap()).is_dir());
+        assert!(config.fakerootdir.join("build").is_dir());
+        assert_eq!(collect(&config, &state, None, false).unwrap().bytes, 0);
+
+        // once its generation is pruned, the old tree goes too; the current one is always kept
+        assert_eq!(Generation::commit(&config, &mut state).unwrap(), Some(2));
+        let pruned = collect(&config, &state, Some(0), true).unwrap();
+        assert_eq!(pruned.generations, vec![1]);
+        assert_eq!(pruned.trees.len(), 1);
+        assert!(Generation::load(&config, 1).is_ok());
+        assert_eq!(collect(&config, &state, Some(0), false).unwrap(), pruned);
+        assert!(Generation::load(&config, 1).is_err());
+        assert!(Generation::load(&config, 2).is_ok());
+
+        assert_eq!(format_size(512), "512 B");
+        assert_eq!(format_size(3 * 1024 * 1024 / 2), "1.5 MiB");
+    }
+}
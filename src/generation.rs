@@ -0,0 +1,461 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::config::Configuration;
+use crate::doctor;
+use crate::install;
+use crate::state::{InstalledPackage, InstalledState};
+use crate::store::Store;
+use crate::{read_toml, write_toml_atomic, NebulaError};
+
+/// Directory of nebula's home holding the recorded generations.
+pub const GENERATIONS_DIR: &str = "generations";
+/// File of nebula's home recording a generation switch in progress, see `switch_to`.
+pub const SWITCH_JOURNAL: &str = "switch.toml";
+
+/// A numbered snapshot of the installed state, recorded after every install, remove or upgrade.
+/// As package trees stay in the store, the destination directory can be switched back to any
+/// generation (see `switch_to`).
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Generation {
+    pub number: u32,
+    /// Creation time, in seconds since the Unix epoch
+    pub created: u64,
+    pub state: InstalledState,
+}
+
+/// A generation switch in progress. It's written before any link is switched and removed once the
+/// new state is saved, so if it's still there the destination directory may hold a mix of both
+/// generations (see `recover_switch`).
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct SwitchJournal {
+    /// Generation of the state being left, if it was recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<u32>,
+    pub to: u32,
+}
+
+impl SwitchJournal {
+    pub fn path(config: &Configuration) -> PathBuf {
+        config.nebulahome.join(SWITCH_JOURNAL)
+    }
+
+    /// The journal of an interrupted switch, if any.
+    pub fn load(config: &Configuration) -> Result<Option<SwitchJournal>, NebulaError> {
+        let path = Self::path(config);
+        if path.is_file() {
+            read_toml(&path).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn remove(config: &Configuration) -> Result<(), NebulaError> {
+        let path = Self::path(config);
+        fs::remove_file(&path)
+            .map_err(|e| NebulaError::Fs(format!("cannot remove {}: {}", path.display(), e)))
+    }
+}
+
+/// A difference between the packages of two generations.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    Added(InstalledPackage),
+    Removed(InstalledPackage),
+    /// (old version, new version)
+    Changed(InstalledPackage, InstalledPackage),
+}
+
+impl fmt::Display for Change {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Change::Added(p) => write!(f, "+ {} {}", p.name, p.version),
+            Change::Removed(p) => write!(f, "- {} {}", p.name, p.version),
+            Change::Changed(old, new) => {
+                write!(f, "~ {} {} -> {}", new.name, old.version, new.version)
+            }
+        }
+    }
+}
+
+impl Generation {
+    pub fn dir(config: &Configuration) -> PathBuf {
+        config.nebulahome.join(GENERATIONS_DIR)
+    }
+
+    pub fn path(config: &Configuration, number: u32) -> PathBuf {
+        Self::dir(config).join(format!("{}.toml", number))
+    }
+
+    /// Lists the recorded generations, oldest first.
+    pub fn list(config: &Configuration) -> Result<Vec<Generation>, NebulaError> {
+        let dir = Self::dir(config);
+        if !dir.is_dir() {
+            return Ok(vec![]);
+        }
+        let read_err = |e: std::io::Error| NebulaError::Fs(format!("{}: {}", dir.display(), e));
+        let mut generations = vec![];
+        for entry in fs::read_dir(&dir).map_err(read_err)? {
+            let path = entry.map_err(read_err)?.path();
+            let is_generation = path.extension().is_some_and(|e| e == "toml")
+                && path
+                    .file_stem()
+                    .and_then(|s| s.to_str())
+                    .is_some_and(|s| s.parse::<u32>().is_ok());
+            if is_generation {
+                generations.push(read_toml::<Generation>(&path)?);
+            }
+        }
+        generations.sort_by_key(|g| g.number);
+        Ok(generations)
+    }
+
+    pub fn load(config: &Configuration, number: u32) -> Result<Generation, NebulaError> {
+        let path = Self::path(config, number);
+        if !path.is_file() {
+            return Err(NebulaError::GenerationNotFound(number));
+        }
+        read_toml(&path)
+    }
+
+    /// Records the given state as a new generation, unless its packages did not change since the
+    /// generation it comes from. Returns the number of the new generation. The state is updated
+    /// with it, but not saved.
+    pub fn record(
+        config: &Configuration,
+        state: &mut InstalledState,
+    ) -> Result<Option<u32>, NebulaError> {
+        let generations = Self::list(config)?;
+        let current = generations
+            .iter()
+            .find(|g| Some(g.number) == state.generation);
+        if current.is_some_and(|g| g.state.same_packages(state)) {
+            return Ok(None);
+        }
+
+        let number = generations.last().map_or(1, |g| g.number + 1);
+        state.generation = Some(number);
+        let generation = Generation {
+            number,
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+            state: state.clone(),
+        };
+        let dir = Self::dir(config);
+        if let Err(e) = fs::create_dir_all(&dir) {
+            return Err(NebulaError::Fs(format!(
+                "cannot create {}: {}",
+                dir.display(),
+                e
+            )));
+        }
+        // never leave a truncated generation
+        write_toml_atomic(&Self::path(config, number), &generation)?;
+        Ok(Some(number))
+    }
+
+    /// Records the state as a new generation (see `record`) and saves it.
+    pub fn commit(
+        config: &Configuration,
+        state: &mut InstalledState,
+    ) -> Result<Option<u32>, NebulaError> {
+        let number = Self::record(config, state)?;
+        state.save(config)?;
+        Ok(number)
+    }
+
+    /// Deletes the generations older than the `keep` most recent ones, except the current one.
+    /// Returns the numbers of the deleted generations, nothing is deleted in a dry run. Their
+    /// package trees can then be removed from the store (see `gc::collect`).
+    pub fn prune(
+        config: &Configuration,
+        state: &InstalledState,
+        keep: usize,
+        dry_run: bool,
+    ) -> Result<Vec<u32>, NebulaError> {
+        let generations = Self::list(config)?;
+        let old = generations.len().saturating_sub(keep);
+        let mut pruned = vec![];
+        for generation in &generations[..old] {
+            if Some(generation.number) == state.generation {
+                continue;
+            }
+            pruned.push(generation.number);
+            if dry_run {
+                continue;
+            }
+            let path = Self::path(config, generation.number);
+            if let Err(e) = fs::remove_file(&path) {
+                return Err(NebulaError::Fs(format!(
+                    "cannot remove {}: {}",
+                    path.display(),
+                    e
+                )));
+            }
+        }
+        Ok(pruned)
+    }
+
+    /// Lists the changes from the packages of `older` to the packages of this generation.
+    pub fn diff(&self, older: &InstalledState) -> Vec<Change> {
+        let mut changes = vec![];
+        for pkg in &older.packages {
+            match self.state.get(&pkg.name) {
+                None => changes.push(Change::Removed(pkg.clone())),
+                Some(new) if new.version != pkg.version || new.store != pkg.store => {
+                    changes.push(Change::Changed(pkg.clone(), new.clone()))
+                }
+                Some(_) => (),
+            }
+        }
+        for pkg in &self.state.packages {
+            if older.get(&pkg.name).is_none() {
+                changes.push(Change::Added(pkg.clone()));
+            }
+        }
+        changes
+    }
+
+    /// Creation time as `YYYY-MM-DD HH:MM:SS` (UTC).
+    pub fn created_str(&self) -> String {
+        let days = (self.created / 86400) as i64;
+        let secs = self.created % 86400;
+        // days since the epoch to a civil date (proleptic Gregorian calendar)
+        let z = days + 719468;
+        let era = z.div_euclid(146097);
+        let doe = z.rem_euclid(146097);
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+        format!(
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs % 3600 / 60,
+            secs % 60
+        )
+    }
+}
+
+/// Switches the destination directory to the given generation: packages not in it are removed,
+/// and the others are linked from their trees of the store. If anything fails, the links are
+/// switched back to the current state. The state is updated and saved.
+///
+/// The holds of the current state are kept. A held package whose version differs in the
+/// generation (or that is missing from it) fails the switch with a `PackageHeld` error, unless
+/// `force` is given.
+///
+/// The switch is recorded in a `SwitchJournal` until the new state is saved: if the process dies
+/// meanwhile, the saved state still describes the current generation and `recover_switch` links
+/// the destination directory back to it.
+pub fn switch_to(
+    config: &Configuration,
+    state: &mut InstalledState,
+    generation: &Generation,
+    force: bool,
+) -> Result<(), NebulaError> {
+    let store = Store::new(config);
+    for pkg in &generation.state.packages {
+        let in_store = pkg.store.as_ref().is_some_and(|s| store.path(s).is_dir());
+        if !in_store {
+            return Err(NebulaError::Fs(format!(
+                "generation {} needs {} {}, which is no longer in the package store",
+                generation.number, pkg.name, pkg.version
+            )));
+        }
+    }
+
+    for name in &state.held {
+        let current = state.get(name);
+        let target = generation.state.get(name);
+        if current.map(|p| &p.store) == target.map(|p| &p.store) || force {
+            continue;
+        }
+        let version = |p: Option<&InstalledPackage>| match p {
+            Some(p) => p.version.clone(),
+            None => "not installed".to_string(),
+        };
+        return Err(NebulaError::PackageHeld(format!(
+            "{} is held at version {}, generation {} has {} (use --force to switch it anyway)",
+            name,
+            version(current),
+            generation.number,
+            version(target)
+        )));
+    }
+
+    let journal = SwitchJournal {
+        from: state.generation,
+        to: generation.number,
+    };
+    write_toml_atomic(&SwitchJournal::path(config), &journal)?;
+    let original = state.clone();
+    // the held packages to change were checked above
+    let held = std::mem::take(&mut state.held);
+    let result = switch_packages(config, state, &generation.state);
+    match result {
+        Ok(()) => {
+            state.held = held;
+            state.generation = Some(generation.number);
+            state.save(config)?;
+            SwitchJournal::remove(config)
+        }
+        Err(e) => {
+            error!("cannot switch to generation {}: {}", generation.number, e);
+            let switched_back = switch_packages(config, state, &original);
+            state.held = held;
+            state.generation = original.generation;
+            match switched_back {
+                // the saved state was not changed
+                Ok(()) => SwitchJournal::remove(config)?,
+                Err(e) => error!("cannot switch back to the current state: {}", e),
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Finishes an interrupted generation switch by undoing it: the saved state still describes the
+/// generation that was being left, so the links of the destination directory are repaired against
+/// it (see `doctor::repair`) and the journal is removed. Returns the journal of the switch, if one
+/// was interrupted. The state is updated and saved.
+pub fn recover_switch(
+    config: &Configuration,
+    state: &mut InstalledState,
+) -> Result<Option<SwitchJournal>, NebulaError> {
+    let journal = match SwitchJournal::load(config)? {
+        Some(j) => j,
+        None => return Ok(None),
+    };
+    warn!(
+        "the switch to generation {} was interrupted, undoing it",
+        journal.to
+    );
+    let problems = doctor::diagnose(config, state)?;
+    let result = doctor::repair(config, state, &problems);
+    // links changed even if the repair stopped halfway
+    state.save(config)?;
+    result?;
+    SwitchJournal::remove(config)?;
+    Ok(Some(journal))
+}
+
+fn switch_packages(
+    config: &Configuration,
+    state: &mut InstalledState,
+    target: &InstalledState,
+) -> Result<(), NebulaError> {
+    let installed: Vec<String> = state.packages.iter().map(|p| p.name.clone()).collect();
+    for name in installed {
+        if target.get(&name).is_none() {
+            install::remove(config, state, &name)?;
+        }
+    }
+    for pkg in &target.packages {
+        if state.get(&pkg.name).is_some_and(|p| p.store == pkg.store) {
+            continue;
+        }
+        let mut pkg = pkg.clone();
+        // the links are created again, the folding of the destination may differ
+        pkg.links.clear();
+        install::activate(config, state, pkg)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::doctor::diagnose;
+    use crate::generation::{recover_switch, switch_to, Change, Generation, SwitchJournal};
+    use crate::install::{activate, remove};
+    use crate::state::InstalledState;
+    use crate::testutil::{store_tree, temp_dir};
+    use crate::NebulaError;
+    use std::fs;
+
+    #[test]
+    fn record_and_switch_generations() {
+        let tmp = temp_dir("generations");
+        let dir = tmp.path();
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir.join("root"))
+            .build();
+        fs::create_dir_all(dir.join("root")).unwrap();
+        let package = |name: &str, version: &str| {
+            let bin = format!("bin/{}", name);
+            store_tree(&config.fakerootdir, name, version, &[(&bin, version)])
+        };
+        let installed = |name: &str| fs::read_to_string(dir.join("root/bin").join(name)).ok();
+
+        let mut state = InstalledState::default();
+        activate(&config, &mut state, package("proba", "1.0")).unwrap();
+        activate(&config, &mut state, package("other", "1.0")).unwrap();
+        assert_eq!(Generation::commit(&config, &mut state).unwrap(), Some(1));
+        activate(&config, &mut state, package("proba", "2.0")).unwrap();
+        remove(&config, &mut state, "other").unwrap();
+        assert_eq!(Generation::commit(&config, &mut state).unwrap(), Some(2));
+        // nothing changed, no new generation
+        assert_eq!(Generation::commit(&config, &mut state).unwrap(), None);
+        assert_eq!(installed("proba").unwrap(), "2.0");
+        assert!(installed("other").is_none());
+
+        let generations = Generation::list(&config).unwrap();
+        let changes = generations[1].diff(&generations[0].state);
+        assert_eq!(changes.len(), 2);
+        assert!(matches!(&changes[0], Change::Removed(p) if p.name == "other"));
+        assert_eq!(changes[1].to_string(), "~ proba 1.0 -> 2.0");
+
+        switch_to(&config, &mut state, &generations[0], false).unwrap();
+        assert_eq!(state.generation, Some(1));
+        assert_eq!(installed("proba").unwrap(), "1.0");
+        assert_eq!(installed("other").unwrap(), "1.0");
+        assert!(Generation::load(&config, 3).is_err());
+        assert_eq!(InstalledState::load(&config).unwrap(), state);
+        assert!(SwitchJournal::load(&config).unwrap().is_none());
+
+        // a switch back to generation 2 killed halfway is undone, the saved state is generation 1
+        let journal = SwitchJournal {
+            from: Some(1),
+            to: 2,
+        };
+        crate::write_toml_atomic(&SwitchJournal::path(&config), &journal).unwrap();
+        let mut partial = state.clone();
+        activate(&config, &mut partial, package("proba", "2.0")).unwrap();
+        remove(&config, &mut partial, "other").unwrap();
+        assert_eq!(installed("proba").unwrap(), "2.0");
+        let mut saved = InstalledState::load(&config).unwrap();
+        assert_eq!(recover_switch(&config, &mut saved).unwrap(), Some(journal));
+        assert_eq!(installed("proba").unwrap(), "1.0");
+        assert_eq!(installed("other").unwrap(), "1.0");
+        assert!(diagnose(&config, &saved).unwrap().is_empty());
+        assert!(SwitchJournal::load(&config).unwrap().is_none());
+        assert_eq!(recover_switch(&config, &mut saved).unwrap(), None);
+
+        // holds are kept across switches, and held packages only change with force
+        assert!(state.hold("proba").unwrap());
+        match switch_to(&config, &mut state, &generations[1], false) {
+            Err(NebulaError::PackageHeld(msg)) => assert!(msg.contains("generation 2 has 2.0")),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(installed("proba").unwrap(), "1.0");
+        assert_eq!(state.generation, Some(1));
+        switch_to(&config, &mut state, &generations[1], true).unwrap();
+        assert_eq!(installed("proba").unwrap(), "2.0");
+        assert!(installed("other").is_none());
+        assert!(state.is_held("proba"));
+
+        let mut generation = generations[0].clone();
+        generation.created = 1_700_000_000;
+        assert_eq!(generation.created_str(), "2023-11-14 22:13:20");
+    }
+}
@@ -0,0 +1,230 @@
+use md5::Md5;
+use sha2::{Digest, Sha256, Sha512};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use crate::NebulaError;
+
+/// Size of the chunks files are read in while hashing.
+const BUFFER_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    Sha256,
+    Sha512,
+    /// Only for legacy indices, MD5 is not secure
+    Md5,
+}
+
+impl HashAlgorithm {
+    pub fn to_str(&self) -> &str {
+        match self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::Sha512 => "sha512",
+            HashAlgorithm::Md5 => "md5",
+        }
+    }
+}
+
+/// An expected hash, used to verify files.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: HashAlgorithm,
+    /// Hex encoded (lowercase) hash
+    pub hash: String,
+}
+
+impl Checksum {
+    pub fn new(algorithm: HashAlgorithm, hash: &str) -> Checksum {
+        Checksum {
+            algorithm,
+            hash: hash.to_lowercase(),
+        }
+    }
+
+    pub fn sha256(hash: &str) -> Checksum {
+        Checksum::new(HashAlgorithm::Sha256, hash)
+    }
+
+    /// Whether the given hex encoded hash matches the expected one.
+    pub fn matches(&self, hash: &str) -> bool {
+        self.hash.eq_ignore_ascii_case(hash)
+    }
+
+    /// Checks that the file at `path` has the expected hash.
+    pub fn verify_file(&self, path: &Path) -> Result<(), NebulaError> {
+        let hash = hash_file(self.algorithm, path)?;
+        if self.matches(&hash) {
+            Ok(())
+        } else {
+            error!(
+                "Expected and real hash of {} differ: {} != {}",
+                path.display(),
+                self,
+                hash
+            );
+            Err(NebulaError::IncorrectHash)
+        }
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm.to_str(), self.hash)
+    }
+}
+
+/// Incremental hasher for any of the supported algorithms.
+pub struct Hasher {
+    state: State,
+}
+
+enum State {
+    Sha256(Sha256),
+    Sha512(Sha512),
+    Md5(Md5),
+}
+
+impl Hasher {
+    pub fn new(algorithm: HashAlgorithm) -> Hasher {
+        let state = match algorithm {
+            HashAlgorithm::Sha256 => State::Sha256(Sha256::new()),
+            HashAlgorithm::Sha512 => State::Sha512(Sha512::new()),
+            HashAlgorithm::Md5 => State::Md5(Md5::new()),
+        };
+        Hasher { state }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.state {
+            State::Sha256(h) => h.update(data),
+            State::Sha512(h) => h.update(data),
+            State::Md5(h) => h.update(data),
+        }
+    }
+
+    /// Returns the hex encoded hash of all the data given.
+    pub fn finalize(self) -> String {
+        match self.state {
+            State::Sha256(h) => format!("{:x}", h.finalize()),
+            State::Sha512(h) => format!("{:x}", h.finalize()),
+            State::Md5(h) => format!("{:x}", h.finalize()),
+        }
+    }
+}
+
+impl Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A writer hashing everything written through it, so data can be verified while it's being
+/// stored (e.g. downloaded) without reading it again.
+pub struct HashingWriter<W: Write> {
+    inner: W,
+    hasher: Hasher,
+}
+
+impl<W: Write> HashingWriter<W> {
+    pub fn new(inner: W, algorithm: HashAlgorithm) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Hasher::new(algorithm),
+        }
+    }
+
+    /// Returns the inner writer and the hex encoded hash of the data written.
+    pub fn finalize(self) -> (W, String) {
+        (self.inner, self.hasher.finalize())
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // only hash what the inner writer accepted
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Hashes everything read from `reader`.
+pub fn hash_reader<R: Read>(algorithm: HashAlgorithm, mut reader: R) -> io::Result<String> {
+    let mut hasher = Hasher::new(algorithm);
+    let mut buffer = vec![0u8; BUFFER_SIZE];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => hasher.update(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// Hashes the given file, reading it in chunks.
+pub fn hash_file(algorithm: HashAlgorithm, path: &Path) -> Result<String, NebulaError> {
+    let fs_err = |e: io::Error| NebulaError::Fs(format!("cannot hash {}: {}", path.display(), e));
+    let file = File::open(path).map_err(fs_err)?;
+    hash_reader(algorithm, file).map_err(fs_err)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::hash::{hash_reader, HashAlgorithm, Hasher, HashingWriter};
+    use std::io::Write;
+
+    #[test]
+    fn hash_algorithms() {
+        let hash = |alg, data: &str| hash_reader(alg, data.as_bytes()).unwrap();
+        assert_eq!(
+            hash(HashAlgorithm::Sha256, "abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(hash(HashAlgorithm::Sha512, "abc").starts_with("ddaf35a193617aba"));
+        // RFC 1321 test suite
+        assert_eq!(
+            hash(HashAlgorithm::Md5, ""),
+            "d41d8cd98f00b204e9800998ecf8427e"
+        );
+        assert_eq!(
+            hash(HashAlgorithm::Md5, "message digest"),
+            "f96b697d7cb7938d525a2f31aaf161d0"
+        );
+        let digits = "1234567890".repeat(8);
+        assert_eq!(
+            hash(HashAlgorithm::Md5, &digits),
+            "57edf4a22be3c955ac49da2e2107b67a"
+        );
+
+        // hashing in pieces gives the same result
+        let mut hasher = Hasher::new(HashAlgorithm::Md5);
+        for chunk in digits.as_bytes().chunks(7) {
+            hasher.update(chunk);
+        }
+        assert_eq!(hasher.finalize(), "57edf4a22be3c955ac49da2e2107b67a");
+
+        let mut writer = HashingWriter::new(vec![], HashAlgorithm::Sha256);
+        writer.write_all(b"ab").unwrap();
+        writer.write_all(b"c").unwrap();
+        let (data, hash) = writer.finalize();
+        assert_eq!(data, b"abc");
+        assert_eq!(
+            hash,
+            hash_reader(HashAlgorithm::Sha256, &b"abc"[..]).unwrap()
+        );
+    }
+}
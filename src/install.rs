@@ -0,0 +1,271 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Configuration;
+use crate::policy::Candidate;
+use crate::state::{InstalledPackage, InstalledState};
+use crate::store::Store;
+use crate::{create_links, remove_links, NebulaError, Repository};
+
+/// Installs a candidate: fetches its archive from its repository, adds it to the package store
+/// and switches the links of the destination directory to it (see `activate`). Returns false if
+/// that version was already installed.
+pub fn install(
+    config: &Configuration,
+    repos: &[impl Repository],
+    state: &mut InstalledState,
+    candidate: &Candidate,
+) -> Result<bool, NebulaError> {
+    let package = &candidate.package;
+    let store = Store::new(config);
+    if let Some(installed) = state.get(&package.name) {
+        let in_store = installed
+            .store
+            .as_ref()
+            .is_some_and(|s| store.path(s).is_dir());
+        if installed.version == package.version && in_store {
+            return Ok(false);
+        }
+    }
+    state.check_change(&package.name, &package.version)?;
+
+    let repo = match repos.iter().find(|r| r.name() == candidate.repository) {
+        Some(r) => r,
+        None => {
+            return Err(NebulaError::Fs(format!(
+                "repository {} of {} is not configured",
+                candidate.repository, package.name
+            )))
+        }
+    };
+    let downloads = store.dir().join(".downloads");
+    if let Err(e) = fs::create_dir_all(&downloads) {
+        return Err(NebulaError::Fs(format!(
+            "cannot create {}: {}",
+            downloads.display(),
+            e
+        )));
+    }
+    info!("fetching {} {}", package.name, package.version);
+    let archive = repo.fetch(package, &downloads)?;
+    let entry = store.add(&archive, package);
+    // the archive is not needed once unpacked
+    let _ = fs::remove_file(&archive);
+
+    activate(
+        config,
+        state,
+        InstalledPackage {
+            name: package.name.clone(),
+            version: package.version.clone(),
+            repository: Some(candidate.repository.clone()),
+            store: Some(entry?),
+            links: vec![],
+        },
+    )?;
+    Ok(true)
+}
+
+/// Makes `package`, whose tree is already in the store, the installed version: removes the links
+/// of the previously installed version and links the package's tree into the destination
+/// directory, recording the links in the state. If linking fails, the previous version is linked
+/// back.
+pub fn activate(
+    config: &Configuration,
+    state: &mut InstalledState,
+    package: InstalledPackage,
+) -> Result<(), NebulaError> {
+    state.check_change(&package.name, &package.version)?;
+    let destdir = destdir(config)?;
+    let previous = state.get(&package.name).cloned();
+    if let Some(previous) = &previous {
+        unlink(config, &destdir, previous)?;
+    }
+    match link(config, &destdir, state, package) {
+        Ok(package) => state.record_install(package),
+        Err(e) => {
+            if let Some(previous) = previous {
+                match link(config, &destdir, state, previous) {
+                    Ok(previous) => state.record_install(previous)?,
+                    Err(e) => error!("cannot link back the previous version: {}", e),
+                }
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Removes an installed package: its links are removed from the destination directory, its tree
+/// stays in the store.
+pub fn remove(
+    config: &Configuration,
+    state: &mut InstalledState,
+    name: &str,
+) -> Result<(), NebulaError> {
+    let package = match state.get(name) {
+        Some(p) => p.clone(),
+        None => return Err(NebulaError::PackageNotInstalled(name.to_string())),
+    };
+    if state.is_held(name) {
+        return Err(NebulaError::PackageHeld(format!(
+            "{} is held at version {}, cannot remove it",
+            name, package.version
+        )));
+    }
+    unlink(config, &destdir(config)?, &package)?;
+    state.record_remove(name);
+    Ok(())
+}
+
+/// Removes the links of an installed package from the destination directory. The package's tree
+/// stays in the store.
+pub fn unlink(
+    config: &Configuration,
+    destdir: &Path,
+    package: &InstalledPackage,
+) -> Result<(), NebulaError> {
+    let entry = match &package.store {
+        Some(e) => e,
+        // installed before the store existed, nothing was linked
+        None => return Ok(()),
+    };
+    let links: Vec<PathBuf> = package.links.iter().map(|l| destdir.join(l)).collect();
+    remove_links(&links, &Store::new(config).path(entry))
+}
+
+/// Links the store tree of `package` into the destination directory and returns the package
+/// with its links. Folded directories unfolded meanwhile are recorded in the state.
+fn link(
+    config: &Configuration,
+    destdir: &Path,
+    state: &mut InstalledState,
+    mut package: InstalledPackage,
+) -> Result<InstalledPackage, NebulaError> {
+    let store = Store::new(config);
+    let entry = match &package.store {
+        Some(e) => e,
+        None => {
+            return Err(NebulaError::Fs(format!(
+                "{} {} is not in the package store",
+                package.name, package.version
+            )))
+        }
+    };
+    let changes = create_links(&store.data_dir(entry), destdir, store.dir())?;
+    state.record_unfolds(destdir, &changes.unfolded);
+    package.links = changes
+        .created
+        .iter()
+        .map(|l| {
+            l.strip_prefix(destdir)
+                .unwrap_or(l)
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect();
+    package.links.sort();
+    Ok(package)
+}
+
+/// The destination directory, in the canonical form links are recorded relative to.
+pub fn destdir(config: &Configuration) -> Result<PathBuf, NebulaError> {
+    fs::canonicalize(&config.destdir).map_err(|e| {
+        NebulaError::Fs(format!(
+            "cannot resolve {}: {}",
+            config.destdir.display(),
+            e
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::install::{activate, install};
+    use crate::policy::{self, Candidate};
+    use crate::repos::nebula::index_directory;
+    use crate::repos::NebulaConfig;
+    use crate::state::InstalledState;
+    use crate::store::Store;
+    use crate::testutil::temp_dir;
+    use crate::{create_repos, nbpkg, Package, Repository};
+    use std::fs;
+    use std::path::Path;
+
+    fn build(dir: &Path, name: &str, version: &str, files: &[&str]) {
+        let staged = dir.join(format!("stage-{}-{}", name, version));
+        for file in files {
+            let path = staged.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, version).unwrap();
+        }
+        nbpkg::build(&staged, Package::new(name, version), &dir.join("repo")).unwrap();
+    }
+
+    #[test]
+    fn versions_side_by_side() {
+        let tmp = temp_dir("install");
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("root")).unwrap();
+        build(
+            dir,
+            "proba",
+            "1.0",
+            &["usr/bin/proba", "usr/share/proba/doc"],
+        );
+        build(dir, "proba", "2.0", &["usr/bin/proba"]);
+        build(dir, "other", "1.0", &["usr/bin/other"]);
+        index_directory(&dir.join("repo"), None).unwrap();
+
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir.join("root"))
+            .nebula_repo(NebulaConfig {
+                repository: dir.join("repo").to_str().unwrap().to_string(),
+                trusted_keys: vec![],
+                priority: None,
+                network: None,
+            })
+            .build();
+        fs::create_dir_all(&config.fakerootdir).unwrap();
+        fs::create_dir_all(config.nebulahome.join("repo")).unwrap();
+        let repos = create_repos(&config).unwrap();
+        repos[0].initialize().unwrap();
+        repos[0].update().unwrap();
+        let mut state = InstalledState::default();
+        let candidate = |name: &str, version: &str| Candidate {
+            package: repos[0].search(name, Some(version)).unwrap().unwrap()[0].clone(),
+            repository: "nebula".to_string(),
+            priority: 500,
+        };
+        let installed = |path: &str| fs::read_to_string(dir.join("root").join(path)).unwrap();
+
+        // the whole tree of the first package is folded into a single link
+        assert!(install(&config, &repos, &mut state, &candidate("proba", "1.0")).unwrap());
+        assert!(!install(&config, &repos, &mut state, &candidate("proba", "1.0")).unwrap());
+        assert_eq!(state.get("proba").unwrap().links, vec!["usr"]);
+
+        // linking another package unfolds the directories it shares with it
+        let other = policy::select_candidate(&repos, &[], "other")
+            .unwrap()
+            .unwrap();
+        install(&config, &repos, &mut state, &other).unwrap();
+        assert_eq!(
+            state.get("proba").unwrap().links,
+            vec!["usr/bin/proba", "usr/share"]
+        );
+        assert_eq!(installed("usr/bin/other"), "1.0");
+        assert_eq!(installed("usr/share/proba/doc"), "1.0");
+
+        let v1 = state.get("proba").unwrap().clone();
+        install(&config, &repos, &mut state, &candidate("proba", "2.0")).unwrap();
+        assert_eq!(installed("usr/bin/proba"), "2.0");
+        assert!(!dir.join("root/usr/share").exists());
+        assert_eq!(Store::new(&config).entries().unwrap().len(), 3);
+
+        // both versions are kept in the store, going back only switches the links
+        activate(&config, &mut state, v1).unwrap();
+        assert_eq!(installed("usr/bin/proba"), "1.0");
+        assert_eq!(installed("usr/bin/other"), "1.0");
+        assert_eq!(state.get("proba").unwrap().version, "1.0");
+    }
+}
@@ -1,175 +1,348 @@
 #[macro_use]
 extern crate log;
-#[macro_use]
-extern crate lazy_static;
-
-use curl::easy::Easy;
-use fs::OpenOptions;
-use sha2::{Digest, Sha256};
-use simplelog::*;
-use std::fs::{self, create_dir, create_dir_all, File};
-use std::io::{Read, Write};
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
 use std::os::unix;
 use std::path::{Path, PathBuf};
-use std::process;
-use std::process::Command;
-use walkdir::WalkDir;
+use std::process::{Command, Stdio};
 
 pub mod config;
+pub mod context;
+pub mod doctor;
 pub mod errors;
+pub mod gc;
+pub mod generation;
+pub mod hash;
+pub mod install;
+pub mod lock;
+pub mod nbpkg;
 pub mod pkg;
+pub mod policy;
+pub mod recipe;
 pub mod repos;
+pub mod sign;
+pub mod state;
+pub mod store;
+pub mod transport;
+pub mod upgrade;
+pub mod verify;
+
+#[cfg(test)]
+mod testutil;
 
+pub use context::Nebula;
 pub use errors::NebulaError;
 pub use pkg::{Dependency, Package};
-pub use repos::{create_repos, RepoType, Repository};
+pub use repos::{create_repos, RepoType, Repositories, Repository};
 
 // pub mod nebula;
-use config::Configuration;
+use config::NetworkConfig;
+use hash::{Checksum, HashAlgorithm};
+use transport::{Fetched, Validators};
+
+/// Downloads the resource at `url` into `outfile`, using the transport that matches the URL
+/// (HTTP(S), `file://` or a plain path). HTTP(S) transfers use the given network settings.
+pub fn download(url: &str, outfile: &Path, net: &NetworkConfig) -> Result<(), NebulaError> {
+    clean_outfile(outfile)?;
+    debug!("downloading {} into {}", url, outfile.display());
+    transport::transport_for(url, net).fetch(url, outfile)
+}
 
-lazy_static! {
-    pub static ref CONFIG: Configuration = Configuration::from(Path::new("config.toml")).unwrap();
+/// Like `download`, but the resource is only downloaded if it changed since it was fetched with
+/// the given validators (see `transport::Validators`).
+pub fn download_if_modified(
+    url: &str,
+    outfile: &Path,
+    net: &NetworkConfig,
+    validators: &Validators,
+) -> Result<Fetched, NebulaError> {
+    clean_outfile(outfile)?;
+    debug!("downloading {} into {} if modified", url, outfile.display());
+    transport::transport_for(url, net).fetch_if_modified(url, outfile, validators)
 }
 
-/// Checks if all nebula directories are present, if not, creates the needed directories. It also
-/// creates the needed files, such as the logger.
-pub fn initialize(repos: &[impl Repository]) -> Result<(), NebulaError> {
-    // check nebula's home and cache directory (inside home directory)
-    if !CONFIG.nebulahome.is_dir() {
-        create_dir_all(&CONFIG.nebulahome).unwrap(); // create home
-        create_dir(&CONFIG.nebulahome.join("repo")).unwrap(); // create home/repo
+/// Like `download`, but the resource is hashed while it is being written and must match the
+/// given checksum. On mismatch the downloaded file is removed.
+pub fn download_verified(
+    url: &str,
+    outfile: &Path,
+    net: &NetworkConfig,
+    checksum: &Checksum,
+) -> Result<(), NebulaError> {
+    clean_outfile(outfile)?;
+    debug!(
+        "downloading {} into {} ({})",
+        url,
+        outfile.display(),
+        checksum
+    );
+    let hash = transport::transport_for(url, net).fetch_hashed(url, outfile, checksum.algorithm)?;
+    if checksum.matches(&hash) {
+        Ok(())
+    } else {
+        error!(
+            "Expected and real hash of {} differ: {} != {}",
+            url, checksum, hash
+        );
+        let _ = fs::remove_file(outfile);
+        Err(NebulaError::IncorrectHash)
     }
+}
 
-    // check fakeroot
-    if !CONFIG.fakerootdir.is_dir() {
-        create_dir_all(&CONFIG.fakerootdir).unwrap();
+fn clean_outfile(outfile: &Path) -> Result<(), NebulaError> {
+    // delete the file/dir to download if it already exists
+    if outfile.is_dir() {
+        if let Err(e) = fs::remove_dir_all(outfile) {
+            return Err(NebulaError::Fs(format!(
+                "cannot clean {}: {}",
+                outfile.display(),
+                e
+            )));
+        }
+    }
+    if outfile.is_file() {
+        if let Err(e) = fs::remove_file(outfile) {
+            return Err(NebulaError::Fs(format!(
+                "cannot clean {}: {}",
+                outfile.display(),
+                e
+            )));
+        }
     }
 
-    // check destdir, if it does not exists, fatal error: panic!
-    if !CONFIG.destdir.is_dir() {
-        eprintln!(
-            "Fatal: The destination directory for packages does not exist, 
-            please create or change the destination directory: {}",
-            CONFIG.destdir.display()
-        );
+    Ok(())
+}
+
+/// Changes made to a destination tree by `create_links`.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct LinkChanges {
+    /// Links created, pointing into the linked tree
+    pub created: Vec<PathBuf>,
+    /// Directory links into other trees of the store replaced by real directories, in order
+    pub unfolded: Vec<Unfold>,
+}
+
+/// A link to a directory of another package tree (a folded directory) replaced by a real
+/// directory holding links to each entry of that directory.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Unfold {
+    pub link: PathBuf,
+    pub target: PathBuf,
+    pub children: Vec<PathBuf>,
+}
+
+/// Links the entries of the `src` tree into `dest`. Directories that do not exist in `dest` are
+/// linked as a whole (folded), otherwise their entries are linked one by one. A folded directory
+/// of another tree of the package store `store` is unfolded first, so store trees are never
+/// written into. Existing entries of `dest` are kept.
+///
+/// If a link cannot be created, every change made is undone.
+pub fn create_links(src: &Path, dest: &Path, store: &Path) -> Result<LinkChanges, NebulaError> {
+    let canonical = |p: &Path| {
+        fs::canonicalize(p)
+            .map_err(|e| NebulaError::Fs(format!("cannot resolve {}: {}", p.display(), e)))
+    };
+    let src = canonical(src)?;
+    let dest = canonical(dest)?;
+    let store = canonical(store)?;
+
+    let mut changes = LinkChanges::default();
+    match link_dir(&src, &dest, &store, &mut changes) {
+        Ok(()) => Ok(changes),
+        Err(e) => {
+            error!(
+                "cannot link {} into {}: {}",
+                src.display(),
+                dest.display(),
+                e
+            );
+            info!("Cleaning created symlinks");
+            undo_links(&changes);
+            Err(e)
+        }
     }
+}
 
-    // create the logger
-    CombinedLogger::init(vec![
-        TermLogger::new(LevelFilter::Warn, Config::default(), TerminalMode::Mixed),
-        WriteLogger::new(
-            LevelFilter::Debug,
-            Config::default(),
-            File::create("nebula.log").unwrap(),
-        ),
-    ])
-    .unwrap();
-
-    // initi all repos
-    for repo in repos {
-        repo.initialize()?;
+fn link_dir(
+    src: &Path,
+    dest: &Path,
+    store: &Path,
+    changes: &mut LinkChanges,
+) -> Result<(), NebulaError> {
+    let fs_err = |p: &Path, e: std::io::Error| NebulaError::Fs(format!("{}: {}", p.display(), e));
+    let mut entries = vec![];
+    for entry in fs::read_dir(src).map_err(|e| fs_err(src, e))? {
+        entries.push(entry.map_err(|e| fs_err(src, e))?);
     }
+    entries.sort_by_key(|e| e.file_name());
 
+    for entry in entries {
+        let src_path = entry.path();
+        let new_path = dest.join(entry.file_name());
+        // symlinks of the tree are linked as they are, not followed
+        let src_is_dir = entry
+            .file_type()
+            .map_err(|e| fs_err(&src_path, e))?
+            .is_dir();
+        match fs::symlink_metadata(&new_path) {
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                unix::fs::symlink(&src_path, &new_path).map_err(|e| fs_err(&new_path, e))?;
+                debug!("new link: {} -> {}", src_path.display(), new_path.display());
+                changes.created.push(new_path);
+            }
+            Err(e) => return Err(fs_err(&new_path, e)),
+            Ok(meta) if src_is_dir && meta.file_type().is_symlink() && new_path.is_dir() => {
+                let target = fs::read_link(&new_path).map_err(|e| fs_err(&new_path, e))?;
+                if target.starts_with(store) {
+                    unfold(&new_path, &target, changes)?;
+                }
+                link_dir(&src_path, &new_path, store, changes)?;
+            }
+            Ok(meta) if src_is_dir && meta.is_dir() => {
+                link_dir(&src_path, &new_path, store, changes)?;
+            }
+            Ok(_) => warn!(
+                "{} already exists, not linking {}",
+                new_path.display(),
+                src_path.display()
+            ),
+        }
+    }
     Ok(())
 }
 
-pub fn download(url: String, outfile: &Path) {
-    // delete the file/dir to download if it already exists
-    if outfile.is_dir() && outfile.exists() {
-        fs::remove_dir_all(&outfile).unwrap();
-    }
-    if outfile.is_file() && outfile.exists() {
-        fs::remove_file(&outfile).unwrap();
+/// Replaces the folded directory `link` (pointing to `target`) by a real directory.
+fn unfold(link: &Path, target: &Path, changes: &mut LinkChanges) -> Result<(), NebulaError> {
+    let fs_err = |p: &Path, e: std::io::Error| NebulaError::Fs(format!("{}: {}", p.display(), e));
+    debug!("unfolding {} -> {}", link.display(), target.display());
+    let mut names = vec![];
+    for entry in fs::read_dir(target).map_err(|e| fs_err(target, e))? {
+        names.push(entry.map_err(|e| fs_err(target, e))?.file_name());
     }
+    names.sort();
+
+    fs::remove_file(link).map_err(|e| fs_err(link, e))?;
+    let mut unfold = Unfold {
+        link: link.to_path_buf(),
+        target: target.to_path_buf(),
+        children: vec![],
+    };
+    let result = fs::create_dir(link)
+        .map_err(|e| fs_err(link, e))
+        .and_then(|_| {
+            for name in names {
+                let child = link.join(&name);
+                unix::fs::symlink(target.join(&name), &child).map_err(|e| fs_err(&child, e))?;
+                unfold.children.push(child);
+            }
+            Ok(())
+        });
+    // even a partial unfold must be recorded, so it can be undone
+    changes.unfolded.push(unfold);
+    result
+}
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .create(true)
-        .append(true)
-        .open(&outfile)
-        .unwrap();
-    let mut handle = Easy::new();
-    handle.url(&url).unwrap();
-    {
-        let mut transfer = handle.transfer();
-        transfer
-            .write_function(|new_data| {
-                file.write_all(new_data).unwrap();
-                Ok(new_data.len())
-            })
-            .unwrap();
-        transfer.perform().unwrap();
+/// Undoes the changes made by `create_links`: removes the created links and folds back the
+/// unfolded directories.
+fn undo_links(changes: &LinkChanges) {
+    let mut clean = true;
+    for link in changes.created.iter().rev() {
+        // if the link can not be removed, notify the user and continue removing other links
+        if fs::remove_file(link).is_err() {
+            error!(
+                "Could not unlink: {}, manual removal needed!",
+                link.display()
+            );
+            clean = false;
+        }
+    }
+    for unfold in changes.unfolded.iter().rev() {
+        for child in &unfold.children {
+            let _ = fs::remove_file(child);
+        }
+        let refolded = fs::remove_dir(&unfold.link)
+            .and_then(|_| unix::fs::symlink(&unfold.target, &unfold.link));
+        if refolded.is_err() {
+            error!(
+                "Could not fold back: {}, manual repair needed!",
+                unfold.link.display()
+            );
+            clean = false;
+        }
+    }
+    if clean {
+        info!("Links cleaned successfully");
+    } else {
+        warn!("Could not create links successfully and some links could not be cleaned");
     }
 }
 
-pub fn create_links(src: &Path, dest: &Path) {
-    // get absolute form of paths
-    let src = fs::canonicalize(src).unwrap();
-    let dest = fs::canonicalize(dest).unwrap();
-
-    let mut links = Vec::<PathBuf>::new();
-    for src_entry in WalkDir::new(&src) {
-        let src_entry = src_entry.unwrap();
-        // remove src directory pat from entry
-        let path = src_entry.path().strip_prefix(&src).unwrap();
-
-        //println!("{}", path.display());
-        let new_path = dest.join(path);
-
-        // check if the new path is inside a created link
-        if links.iter().find(|l| new_path.starts_with(l)).is_none() {
-            // check if the new file already exists in the source dir
-            if !new_path.exists() {
-                // try to create the symlink
-                match unix::fs::symlink(src_entry.path(), &new_path) {
-                    Ok(()) => {
-                        debug!(
-                            "new link: {} -> {}",
-                            src_entry.path().display(),
-                            new_path.display()
-                        );
-                        links.push(new_path);
-                    }
-                    Err(e) => {
-                        error!("symlink error: {}", e);
-                        error!(
-                            "can't link: {} -> {}",
-                            src_entry.path().display(),
-                            new_path.display()
-                        );
-                        info!("Cleaning created symlinks");
-                        eprintln!("[!] An error occurred while creating the links");
-                        let mut exit_ok = true;
-                        // An error occurred, destoy every link created until now
-                        links.iter().for_each(|l| {
-                            // if the link can not be removed, notify the user and continue
-                            // removing other links
-                            if fs::remove_file(l).is_err() {
-                                error!("Could not unlik: {}, manual removal needed!", l.display());
-                                exit_ok = false;
-                            }
-                        });
-                        if exit_ok {
-                            eprintln!("[!] Exiting successfully...");
-                            info!("Links cleaned successfully");
-                        } else {
-                            eprintln!("[!] Fatal: Could not create links successfully and some links could not be cleaned...");
-                            warn!("Could not create links successfully and some links could not be cleaned");
-                        }
-                        process::exit(1);
-                    }
-                }
+/// Removes the given links of a destination tree, only if they still point into `tree`.
+pub fn remove_links(links: &[PathBuf], tree: &Path) -> Result<(), NebulaError> {
+    // links are created to canonical paths, the tree might be gone though
+    let tree = fs::canonicalize(tree).unwrap_or_else(|_| tree.to_path_buf());
+    for link in links {
+        let target = match fs::read_link(link) {
+            Ok(t) => t,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                warn!("{} is already gone", link.display());
+                continue;
             }
+            Err(e) => return Err(NebulaError::Fs(format!("{}: {}", link.display(), e))),
+        };
+        if !target.starts_with(&tree) {
+            warn!(
+                "{} does not point into {}, keeping it",
+                link.display(),
+                tree.display()
+            );
+            continue;
         }
+        debug!("removing link {}", link.display());
+        fs::remove_file(link)
+            .map_err(|e| NebulaError::Fs(format!("cannot remove {}: {}", link.display(), e)))?;
     }
+    Ok(())
 }
 
 /// Computes the Sha256 hash of the given file.
-pub fn file2hash(filepath: &Path) -> Result<String, ()> {
-    let mut file = fs::File::open(filepath).unwrap();
-    let mut buffer = Vec::<u8>::new();
-    file.read_to_end(&mut buffer).unwrap();
-    Ok(format!("{:x}", Sha256::digest(&buffer)))
+pub fn file2hash(filepath: &Path) -> Result<String, NebulaError> {
+    hash::hash_file(HashAlgorithm::Sha256, filepath)
+}
+
+/// Reads and deserializes a TOML file.
+pub fn read_toml<T: serde::de::DeserializeOwned>(path: &Path) -> Result<T, NebulaError> {
+    match fs::read_to_string(path) {
+        Ok(s) => match toml::from_str(&s) {
+            Ok(v) => Ok(v),
+            Err(e) => Err(NebulaError::TomlDe(e)),
+        },
+        Err(e) => Err(NebulaError::Io(e)),
+    }
+}
+
+/// Serializes a value and writes it into a TOML file.
+pub fn write_toml<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), NebulaError> {
+    let content = match toml::to_string(value) {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(NebulaError::Fs(format!(
+                "cannot serialize {}: {}",
+                path.display(),
+                e
+            )))
+        }
+    };
+    fs::write(path, content).map_err(NebulaError::Io)
+}
+
+/// Like `write_toml`, but the value is written into a temporary file first and then renamed, so
+/// an interrupted write never leaves a truncated file behind.
+pub fn write_toml_atomic<T: serde::Serialize>(path: &Path, value: &T) -> Result<(), NebulaError> {
+    let tmp_path = path.with_extension("toml.tmp");
+    write_toml(&tmp_path, value)?;
+    fs::rename(&tmp_path, path).map_err(NebulaError::Io)
 }
 
 pub fn run_cmd(cmd: &str, args: &[&str]) -> Result<(), NebulaError> {
@@ -196,3 +369,56 @@ pub fn run_cmd(cmd: &str, args: &[&str]) -> Result<(), NebulaError> {
         Err(NebulaError::CmdError(message.to_string()))
     }
 }
+
+/// Runs a command inside `dir` with the given extra environment variables. Both stdout and
+/// stderr of the command are streamed (appended) to `log`, so long running builds can be
+/// followed and inspected afterwards.
+pub fn run_cmd_logged(
+    cmd: &str,
+    args: &[&str],
+    dir: &Path,
+    env: &[(&str, &str)],
+    log: &Path,
+) -> Result<(), NebulaError> {
+    let open_log = || {
+        OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log)
+            .map_err(|e| NebulaError::Fs(format!("cannot open log {}: {}", log.display(), e)))
+    };
+    let mut log_file = open_log()?;
+    let _ = writeln!(log_file, "==> {} {}", cmd, args.join(" "));
+    let stderr_file = match log_file.try_clone() {
+        Ok(f) => f,
+        Err(e) => return Err(NebulaError::Io(e)),
+    };
+
+    let mut command = Command::new(cmd);
+    command
+        .args(args)
+        .current_dir(dir)
+        .envs(env.iter().cloned())
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(log_file))
+        .stderr(Stdio::from(stderr_file));
+    let status = match command.status() {
+        Ok(s) => s,
+        Err(e) => {
+            return Err(NebulaError::CmdError(format!(
+                "failed to start {}: {}",
+                cmd, e
+            )));
+        }
+    };
+    if status.success() {
+        Ok(())
+    } else {
+        Err(NebulaError::CmdError(format!(
+            "{} exited with {}, see {}",
+            cmd,
+            status,
+            log.display()
+        )))
+    }
+}
@@ -0,0 +1,261 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::NebulaError;
+
+/// Name of the lock file, inside nebula's home.
+pub const LOCK_FILE: &str = "nebula.lock";
+
+/// Time between attempts while waiting for the lock.
+const RETRY_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LockMode {
+    /// Shared lock, for operations that only read nebula's state and repositories
+    Read,
+    /// Exclusive lock, for operations that change them (updating, installing...)
+    Write,
+}
+
+/// An advisory lock (`flock`) on nebula's home, released when dropped. Any number of readers can
+/// hold it at the same time, a writer holds it alone. The lock file lists the PIDs of its holders,
+/// one `<pid> read` or `<pid> write` line each, so the processes waiting for it can tell who holds
+/// it.
+#[derive(Debug)]
+pub struct Lock {
+    file: File,
+    path: PathBuf,
+    mode: LockMode,
+}
+
+impl Lock {
+    /// Takes the lock of the given nebula directory. If another process holds it, the lock is
+    /// retried until `timeout` expires, or fails right away if there is no timeout.
+    pub fn acquire(
+        nebula_dir: &Path,
+        mode: LockMode,
+        timeout: Option<Duration>,
+    ) -> Result<Lock, NebulaError> {
+        let path = nebula_dir.join(LOCK_FILE);
+        let fs_err = |e: std::io::Error| NebulaError::Fs(format!("{}: {}", path.display(), e));
+        fs::create_dir_all(nebula_dir).map_err(fs_err)?;
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(&path)
+            .map_err(fs_err)?;
+
+        let operation = match mode {
+            LockMode::Read => libc::LOCK_SH,
+            LockMode::Write => libc::LOCK_EX,
+        };
+        let start = Instant::now();
+        let mut waiting = false;
+        loop {
+            if unsafe { libc::flock(file.as_raw_fd(), operation | libc::LOCK_NB) } == 0 {
+                break;
+            }
+            let err = std::io::Error::last_os_error();
+            if err.raw_os_error() != Some(libc::EWOULDBLOCK) {
+                return Err(fs_err(err));
+            }
+            let expired = timeout.is_none_or(|t| start.elapsed() >= t);
+            if expired {
+                return Err(NebulaError::Locked(Self::holder(&path)));
+            }
+            if !waiting {
+                info!("waiting for the lock, {}", Self::holder(&path));
+                waiting = true;
+            }
+            thread::sleep(RETRY_INTERVAL);
+        }
+
+        let mut lock = Lock {
+            file,
+            path: path.clone(),
+            mode,
+        };
+        let entry = lock.entry();
+        lock.edit(|holders| holders.push(entry)).map_err(fs_err)?;
+        Ok(lock)
+    }
+
+    pub fn mode(&self) -> LockMode {
+        self.mode
+    }
+
+    /// The line of the lock file naming this holder.
+    fn entry(&self) -> String {
+        let mode = match self.mode {
+            LockMode::Read => "read",
+            LockMode::Write => "write",
+        };
+        format!("{} {}", process::id(), mode)
+    }
+
+    /// Rewrites the list of holders of the lock file. Readers edit it concurrently, so the edit
+    /// is done under a record lock (an open file description lock, which doesn't interfere with
+    /// `flock`). The entries of processes that exited without removing them are dropped.
+    fn edit<F: FnOnce(&mut Vec<String>)>(&mut self, f: F) -> std::io::Result<()> {
+        self.record_lock(libc::F_WRLCK)?;
+        let result = (|| {
+            let mut content = String::new();
+            self.file.seek(SeekFrom::Start(0))?;
+            self.file.read_to_string(&mut content)?;
+            let mut holders: Vec<String> = content
+                .lines()
+                .filter(|l| Self::parse_entry(l).is_some_and(|(pid, _)| Self::is_alive(pid)))
+                .map(|l| l.to_string())
+                .collect();
+            f(&mut holders);
+            self.file.set_len(0)?;
+            // in append mode, the truncated file is written from its start
+            let content: String = holders.iter().map(|h| format!("{}\n", h)).collect();
+            self.file.write_all(content.as_bytes())?;
+            self.file.flush()
+        })();
+        self.record_lock(libc::F_UNLCK)?;
+        result
+    }
+
+    fn record_lock(&self, kind: libc::c_int) -> std::io::Result<()> {
+        let mut record: libc::flock = unsafe { std::mem::zeroed() };
+        record.l_type = kind as libc::c_short;
+        record.l_whence = libc::SEEK_SET as libc::c_short;
+        // a zero length covers the whole file
+        let r = unsafe { libc::fcntl(self.file.as_raw_fd(), libc::F_OFD_SETLKW, &record) };
+        if r == 0 {
+            Ok(())
+        } else {
+            Err(std::io::Error::last_os_error())
+        }
+    }
+
+    fn parse_entry(line: &str) -> Option<(u32, &str)> {
+        let mut fields = line.split_whitespace();
+        let pid = fields.next()?.parse().ok()?;
+        Some((pid, fields.next().unwrap_or("write")))
+    }
+
+    /// Describes the processes holding the lock at `path`.
+    fn holder(path: &Path) -> String {
+        let content = fs::read_to_string(path).unwrap_or_default();
+        let mut writer = None;
+        let mut readers: Vec<u32> = vec![];
+        // the entries of processes that exited without removing them are stale
+        for (pid, mode) in content.lines().filter_map(Self::parse_entry) {
+            if !Self::is_alive(pid) {
+                continue;
+            }
+            if mode == "read" {
+                if !readers.contains(&pid) {
+                    readers.push(pid);
+                }
+            } else {
+                writer = Some(pid);
+            }
+        }
+        if let Some(pid) = writer {
+            return format!("{} is held by process {}", path.display(), pid);
+        }
+        match readers.len() {
+            0 => format!(
+                "{} is held by processes reading nebula's state",
+                path.display()
+            ),
+            1 => format!(
+                "{} is held by process {}, reading nebula's state",
+                path.display(),
+                readers[0]
+            ),
+            _ => {
+                let pids: Vec<String> = readers.iter().map(|p| p.to_string()).collect();
+                format!(
+                    "{} is held by processes {}, reading nebula's state",
+                    path.display(),
+                    pids.join(", ")
+                )
+            }
+        }
+    }
+
+    fn is_alive(pid: u32) -> bool {
+        let r = unsafe { libc::kill(pid as libc::pid_t, 0) };
+        r == 0 || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        // the lock itself is released when the file is closed
+        let entry = self.entry();
+        let result = self.edit(|holders| {
+            if let Some(i) = holders.iter().position(|h| *h == entry) {
+                holders.remove(i);
+            }
+        });
+        if let Err(e) = result {
+            warn!("cannot update {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::{Lock, LockMode, LOCK_FILE};
+    use crate::testutil::temp_dir;
+    use crate::NebulaError;
+    use std::fs;
+    use std::thread;
+    use std::time::Duration;
+
+    #[test]
+    fn read_and_write_locks() {
+        let tmp = temp_dir("lock");
+        let dir = tmp.path();
+        let writer = Lock::acquire(dir, LockMode::Write, None).unwrap();
+        match Lock::acquire(dir, LockMode::Read, None) {
+            Err(NebulaError::Locked(msg)) => {
+                assert!(msg.contains(&format!("process {}", std::process::id())))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        drop(writer);
+
+        let reader = Lock::acquire(dir, LockMode::Read, None).unwrap();
+        let other_reader = Lock::acquire(dir, LockMode::Read, None).unwrap();
+        match Lock::acquire(dir, LockMode::Write, None) {
+            Err(NebulaError::Locked(msg)) => {
+                assert!(msg.contains(&format!("process {}, reading", std::process::id())))
+            }
+            r => panic!("unexpected result: {:?}", r),
+        }
+        // a reader leaving removes one entry only
+        let path = dir.join(LOCK_FILE);
+        let read_entry = format!("{} read\n", std::process::id());
+        assert_eq!(fs::read_to_string(&path).unwrap(), read_entry.repeat(2));
+        drop(other_reader);
+        assert_eq!(fs::read_to_string(&path).unwrap(), read_entry);
+
+        // wait until the reader is done
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            drop(reader);
+        });
+        let timeout = Some(Duration::from_secs(10));
+        let writer = Lock::acquire(dir, LockMode::Write, timeout).unwrap();
+        assert_eq!(writer.mode(), LockMode::Write);
+        handle.join().unwrap();
+        let write_entry = format!("{} write\n", std::process::id());
+        assert_eq!(fs::read_to_string(&path).unwrap(), write_entry);
+
+        drop(writer);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+}
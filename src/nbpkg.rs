@@ -0,0 +1,218 @@
+use serde_derive::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+use crate::{file2hash, read_toml, NebulaError, Package};
+
+/// Extension of nebula package archives.
+pub const NBPKG_EXTENSION: &str = "nbpkg";
+/// Name of the manifest inside a nebula package.
+pub const MANIFEST_FILE: &str = "package.toml";
+/// Name of the directory holding the payload tree inside a nebula package.
+pub const DATA_DIR: &str = "data";
+
+/// The manifest of a nebula package: the package's metadata and the list of files it installs.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct Manifest {
+    pub package: Package,
+    pub files: Vec<FileEntry>,
+}
+
+/// A file of the payload tree, with its path relative to the payload root.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct FileEntry {
+    pub path: String,
+    /// Sha256 hash of the file, empty for symlinks
+    pub hash: String,
+    /// Target of the entry if it's a symlink
+    pub link: Option<String>,
+}
+
+impl Manifest {
+    /// Creates the manifest of the given payload tree.
+    pub fn from_tree(package: Package, tree: &Path) -> Result<Manifest, NebulaError> {
+        let mut files = vec![];
+        for entry in WalkDir::new(tree).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+            let entry = match entry {
+                Ok(e) => e,
+                Err(e) => return Err(NebulaError::Fs(e.to_string())),
+            };
+            let path = entry
+                .path()
+                .strip_prefix(tree)
+                .unwrap()
+                .to_string_lossy()
+                .to_string();
+            let file_type = entry.file_type();
+            if file_type.is_symlink() {
+                let target = match fs::read_link(entry.path()) {
+                    Ok(t) => t,
+                    Err(e) => return Err(NebulaError::Io(e)),
+                };
+                files.push(FileEntry {
+                    path,
+                    hash: String::new(),
+                    link: Some(target.to_string_lossy().to_string()),
+                });
+            } else if file_type.is_file() {
+                files.push(FileEntry {
+                    path,
+                    hash: file2hash(entry.path())?,
+                    link: None,
+                });
+            }
+        }
+        Ok(Manifest { package, files })
+    }
+
+    /// Name of the archive the package is stored in: `<name>-<version>.nbpkg`.
+    pub fn archive_name(&self) -> String {
+        format!(
+            "{}-{}.{}",
+            self.package.name, self.package.version, NBPKG_EXTENSION
+        )
+    }
+}
+
+/// Builds a nebula package from a staged directory. The package is written to `outdir` and the
+/// path of the created archive is returned.
+///
+/// The archive is a xz compressed tarball containing the `package.toml` manifest and the staged
+/// tree under `data/`.
+pub fn build(staged: &Path, package: Package, outdir: &Path) -> Result<PathBuf, NebulaError> {
+    if !staged.is_dir() {
+        return Err(NebulaError::Fs(format!(
+            "staged directory {} does not exist",
+            staged.display()
+        )));
+    }
+    let manifest = Manifest::from_tree(package, staged)?;
+    let out_path = outdir.join(manifest.archive_name());
+
+    // write the manifest in a temporary directory so that it can be added to the tarball
+    let tmp_dir = outdir.join(format!(".{}.build", manifest.archive_name()));
+    if tmp_dir.exists() {
+        if let Err(e) = fs::remove_dir_all(&tmp_dir) {
+            return Err(NebulaError::Fs(format!(
+                "cannot clean {}: {}",
+                tmp_dir.display(),
+                e
+            )));
+        }
+    }
+    if let Err(e) = fs::create_dir_all(&tmp_dir) {
+        return Err(NebulaError::Fs(format!(
+            "cannot create {}: {}",
+            tmp_dir.display(),
+            e
+        )));
+    }
+    let manifest_str = match toml::to_string(&manifest) {
+        Ok(s) => s,
+        Err(e) => return Err(NebulaError::Fs(format!("cannot serialize manifest: {}", e))),
+    };
+    if let Err(e) = fs::write(tmp_dir.join(MANIFEST_FILE), manifest_str) {
+        return Err(NebulaError::Io(e));
+    }
+
+    debug!("creating nebula package {}", out_path.display());
+    let result = crate::run_cmd(
+        "/usr/bin/tar",
+        &[
+            "--owner=0",
+            "--group=0",
+            "--numeric-owner",
+            "-cJf",
+            out_path.to_str().unwrap(),
+            "-C",
+            tmp_dir.to_str().unwrap(),
+            MANIFEST_FILE,
+            // place every entry of the staged tree under data/
+            &format!("--transform=s,^\\.,{},", DATA_DIR),
+            "-C",
+            staged.to_str().unwrap(),
+            ".",
+        ],
+    );
+    let _ = fs::remove_dir_all(&tmp_dir);
+    result.map(|_| out_path)
+}
+
+/// Extracts a nebula package into `out_dir` and returns its manifest. The payload tree ends up
+/// in `out_dir/data`.
+pub fn extract_nbpkg(pkg_path: &Path, out_dir: &Path) -> Result<Manifest, NebulaError> {
+    if let Err(e) = fs::create_dir_all(out_dir) {
+        return Err(NebulaError::Fs(format!(
+            "cannot create {}: {}",
+            out_dir.display(),
+            e
+        )));
+    }
+    crate::run_cmd(
+        "/usr/bin/tar",
+        &[
+            "-xf",
+            pkg_path.to_str().unwrap(),
+            "-C",
+            out_dir.to_str().unwrap(),
+        ],
+    )?;
+    read_toml(&out_dir.join(MANIFEST_FILE))
+}
+
+/// Reads the manifest of a nebula package without extracting its payload. `tmp_dir` is used to
+/// extract the manifest into and it's removed afterwards.
+pub fn read_manifest(pkg_path: &Path, tmp_dir: &Path) -> Result<Manifest, NebulaError> {
+    if let Err(e) = fs::create_dir_all(tmp_dir) {
+        return Err(NebulaError::Fs(format!(
+            "cannot create {}: {}",
+            tmp_dir.display(),
+            e
+        )));
+    }
+    let result = crate::run_cmd(
+        "/usr/bin/tar",
+        &[
+            "-xf",
+            pkg_path.to_str().unwrap(),
+            "-C",
+            tmp_dir.to_str().unwrap(),
+            MANIFEST_FILE,
+        ],
+    )
+    .and_then(|_| read_toml(&tmp_dir.join(MANIFEST_FILE)));
+    let _ = fs::remove_dir_all(tmp_dir);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::nbpkg::{build, extract_nbpkg, DATA_DIR};
+    use crate::testutil::temp_dir;
+    use crate::Package;
+    use std::fs;
+
+    #[test]
+    fn build_and_extract() {
+        let tmp = temp_dir("nbpkg");
+        let dir = tmp.path();
+        let staged = dir.join("stage");
+        fs::create_dir_all(staged.join("usr/bin")).unwrap();
+        fs::write(staged.join("usr/bin/proba"), "#!/bin/sh\n").unwrap();
+        std::os::unix::fs::symlink("proba", staged.join("usr/bin/proba2")).unwrap();
+
+        let pkg_path = build(&staged, Package::new("proba", "1.0"), dir).unwrap();
+        assert_eq!(pkg_path, dir.join("proba-1.0.nbpkg"));
+
+        let manifest = extract_nbpkg(&pkg_path, &dir.join("out")).unwrap();
+        assert_eq!(manifest.package, Package::new("proba", "1.0"));
+        assert_eq!(manifest.files.len(), 2);
+        assert_eq!(manifest.files[1].link, Some("proba".to_string()));
+        assert!(dir
+            .join("out")
+            .join(DATA_DIR)
+            .join("usr/bin/proba")
+            .is_file());
+    }
+}
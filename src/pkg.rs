@@ -1,9 +1,12 @@
+use crate::config::NetworkConfig;
+use crate::hash::Checksum;
+use crate::{download, download_verified, NebulaError, RepoType};
 use serde_derive::{Deserialize, Serialize};
-// use version_compare::version::Version;
-use crate::RepoType;
+use std::cmp::Ordering;
 use std::fmt;
+use std::path::{Path, PathBuf};
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub struct Package {
     pub name: String,
     pub version: String,
@@ -11,6 +14,41 @@ pub struct Package {
     pub source: Option<PkgSource>,
     #[serde(rename = "dependencies")]
     pub depends: Option<Vec<Vec<Dependency>>>,
+    #[serde(rename = "architecture")]
+    pub arch: Option<String>,
+    #[serde(rename = "multi-arch")]
+    pub multi_arch: Option<MultiArch>,
+    /// SHA256 hash of the package's archive, if the repository lists it
+    pub sha256: Option<String>,
+}
+
+/// Debian's Multi-Arch field, deciding how a package satisfies dependencies of packages of other
+/// architectures and whether it can be installed for several architectures at once.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy)]
+pub enum MultiArch {
+    #[serde(rename = "no")]
+    No,
+    /// Co-installable with the same package of other architectures (same version)
+    #[serde(rename = "same")]
+    Same,
+    /// Satisfies dependencies of packages of any architecture
+    #[serde(rename = "foreign")]
+    Foreign,
+    /// Satisfies `pkg:any` dependencies of packages of any architecture
+    #[serde(rename = "allowed")]
+    Allowed,
+}
+
+impl MultiArch {
+    pub fn parse(s: &str) -> Option<MultiArch> {
+        match s {
+            "no" => Some(MultiArch::No),
+            "same" => Some(MultiArch::Same),
+            "foreign" => Some(MultiArch::Foreign),
+            "allowed" => Some(MultiArch::Allowed),
+            _ => None,
+        }
+    }
 }
 
 impl Package {
@@ -20,7 +58,67 @@ impl Package {
             version: version.to_string(),
             source: None,
             depends: None,
+            arch: None,
+            multi_arch: None,
+            sha256: None,
+        }
+    }
+
+    /// Architecture of the package, `all` packages are considered of the native architecture.
+    pub fn effective_arch<'a>(&'a self, native: &'a str) -> &'a str {
+        match self.arch.as_deref() {
+            None | Some("all") => native,
+            Some(a) => a,
+        }
+    }
+
+    /// Whether this package can be installed at the same time as `other`: only packages with
+    /// different names, or `Multi-Arch: same` packages of the same version built for different
+    /// architectures.
+    pub fn coinstallable_with(&self, other: &Package, native: &str) -> bool {
+        if self.name != other.name {
+            return true;
+        }
+        self.multi_arch == Some(MultiArch::Same)
+            && other.multi_arch == Some(MultiArch::Same)
+            && self.version == other.version
+            && self.effective_arch(native) != other.effective_arch(native)
+    }
+
+    /// Fetches the package's archive from its source into `dir`, returning the path of the
+    /// downloaded file. The transport is chosen from the source URL, so packages can be fetched
+    /// from HTTP(S) mirrors as well as from local directories. If a checksum is given, the
+    /// archive is verified while it is downloaded.
+    pub fn fetch(
+        &self,
+        dir: &Path,
+        net: &NetworkConfig,
+        checksum: Option<&Checksum>,
+    ) -> Result<PathBuf, NebulaError> {
+        let url = match &self.source {
+            Some(src) => src.url(),
+            None => {
+                return Err(NebulaError::Transport(format!(
+                    "package {} has no source",
+                    self.name
+                )))
+            }
+        };
+        let filename = match url.rsplit('/').next() {
+            Some(f) if !f.is_empty() => f,
+            _ => {
+                return Err(NebulaError::Transport(format!(
+                    "cannot get the archive name from {}",
+                    url
+                )))
+            }
+        };
+        let outfile = dir.join(filename);
+        match checksum {
+            Some(checksum) => download_verified(url, &outfile, net, checksum)?,
+            None => download(url, &outfile, net)?,
         }
+        Ok(outfile)
     }
     /*
     pub fn set_version(&mut self, ver: &str) {
@@ -43,16 +141,24 @@ impl fmt::Display for Package {
     }
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub struct PkgSource(RepoType, String);
 
 impl PkgSource {
     pub fn from(repo_type: RepoType, url: &str) -> PkgSource {
         PkgSource(repo_type, url.to_string())
     }
+
+    pub fn repo_type(&self) -> &RepoType {
+        &self.0
+    }
+
+    pub fn url(&self) -> &str {
+        &self.1
+    }
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
 pub struct Dependency(String, String);
 
 impl Dependency {
@@ -64,12 +170,161 @@ impl Dependency {
             None => Dependency(name.to_string(), "".to_string()),
         }
     }
+
+    pub fn name(&self) -> &str {
+        &self.0
+    }
+
+    /// Version requirement of the dependency, e.g. `>=2.28`. Empty if there's no requirement.
+    pub fn version_req(&self) -> &str {
+        &self.1
+    }
+
+    /// Returns the name of the dependency without its architecture qualifier, and the qualifier
+    /// (`any`, `native` or an architecture) if there's one: `libc6:any` -> `(libc6, Some(any))`.
+    pub fn split_arch(&self) -> (&str, Option<&str>) {
+        match self.0.find(':') {
+            Some(i) => (&self.0[..i], Some(&self.0[i + 1..])),
+            None => (&self.0, None),
+        }
+    }
+
+    /// Checks if the package satisfies this dependency of a package of architecture
+    /// `depender_arch`, honouring the Multi-Arch semantics:
+    ///
+    /// - `pkg` is satisfied by a package of the same architecture or a `Multi-Arch: foreign` one
+    /// - `pkg:any` is satisfied by a package of the same architecture or a `Multi-Arch: allowed`
+    ///   one (not a `Multi-Arch: foreign` one of another architecture, like dpkg)
+    /// - `pkg:native` is satisfied by a package of the native architecture
+    /// - `pkg:<arch>` is satisfied by a package of the given architecture
+    pub fn is_satisfied_by(&self, package: &Package, depender_arch: &str, native: &str) -> bool {
+        let (name, qualifier) = self.split_arch();
+        if package.name != name || !self.satisfied_by(&package.version) {
+            return false;
+        }
+        let depender_arch = if depender_arch == "all" {
+            native
+        } else {
+            depender_arch
+        };
+        let arch = package.effective_arch(native);
+        let multi_arch = package.multi_arch.unwrap_or(MultiArch::No);
+        match qualifier {
+            None => arch == depender_arch || multi_arch == MultiArch::Foreign,
+            Some("any") => arch == depender_arch || multi_arch == MultiArch::Allowed,
+            Some("native") => arch == native,
+            Some(q) => arch == q,
+        }
+    }
+
+    /// Checks if the given version satisfies the version requirement of the dependency.
+    /// Supported operators are the debian ones (`<<`, `<=`, `=`, `>=`, `>>`) plus `<` and `>`.
+    pub fn satisfied_by(&self, version: &str) -> bool {
+        let req = self.1.trim();
+        if req.is_empty() {
+            return true;
+        }
+        let op_len = req
+            .find(|c: char| !matches!(c, '<' | '>' | '='))
+            .unwrap_or(req.len());
+        let (op, req_version) = (&req[..op_len], req[op_len..].trim());
+        let ord = compare_versions(version, req_version);
+        match op {
+            "<<" | "<" => ord == Ordering::Less,
+            "<=" => ord != Ordering::Greater,
+            "=" | "" => ord == Ordering::Equal,
+            ">=" => ord != Ordering::Less,
+            ">>" | ">" => ord == Ordering::Greater,
+            _ => false,
+        }
+    }
+}
+
+/// Compares two versions following the debian ordering (see deb-version(7)): the epoch first,
+/// then the upstream version and then the debian revision. `~` sorts before anything, even the
+/// end of the version, so `1.0~rc1` comes before `1.0`.
+pub fn compare_versions(a: &str, b: &str) -> Ordering {
+    let (epoch_a, upstream_a, revision_a) = split_version(a);
+    let (epoch_b, upstream_b, revision_b) = split_version(b);
+    epoch_a
+        .cmp(&epoch_b)
+        .then_with(|| compare_fragment(upstream_a, upstream_b))
+        .then_with(|| compare_fragment(revision_a, revision_b))
+}
+
+/// Splits a version into its epoch, upstream version and debian revision: `1:2.31-13` ->
+/// `(1, 2.31, 13)`. Missing epochs are 0 and missing revisions empty.
+fn split_version(version: &str) -> (u64, &str, &str) {
+    let version = version.trim();
+    let (epoch, rest) = match version.split_once(':') {
+        Some((e, rest)) if !e.is_empty() && e.bytes().all(|b| b.is_ascii_digit()) => {
+            (e.parse().unwrap_or(u64::MAX), rest)
+        }
+        _ => (0, version),
+    };
+    match rest.rfind('-') {
+        Some(i) => (epoch, &rest[..i], &rest[i + 1..]),
+        None => (epoch, rest, ""),
+    }
+}
+
+/// Compares upstream versions or revisions the way dpkg does: alternating non-digit parts,
+/// compared character by character with letters before other characters, and numeric parts.
+fn compare_fragment(a: &str, b: &str) -> Ordering {
+    // weight of a character of a non-digit part, the end of the part (None or a digit) is 0
+    let order = |c: Option<&u8>| match c {
+        Some(b'~') => -1,
+        Some(c) if c.is_ascii_digit() => 0,
+        Some(c) if c.is_ascii_alphabetic() => i32::from(*c),
+        Some(c) => i32::from(*c) + 256,
+        None => 0,
+    };
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    let (mut i, mut j) = (0, 0);
+    while i < a.len() || j < b.len() {
+        while a.get(i).is_some_and(|c| !c.is_ascii_digit())
+            || b.get(j).is_some_and(|c| !c.is_ascii_digit())
+        {
+            let (ac, bc) = (order(a.get(i)), order(b.get(j)));
+            if ac != bc {
+                return ac.cmp(&bc);
+            }
+            i += 1;
+            j += 1;
+        }
+        while a.get(i) == Some(&b'0') {
+            i += 1;
+        }
+        while b.get(j) == Some(&b'0') {
+            j += 1;
+        }
+        let mut first_diff = Ordering::Equal;
+        while a.get(i).is_some_and(u8::is_ascii_digit) && b.get(j).is_some_and(u8::is_ascii_digit) {
+            if first_diff == Ordering::Equal {
+                first_diff = a[i].cmp(&b[j]);
+            }
+            i += 1;
+            j += 1;
+        }
+        // the longer number is the greater
+        if a.get(i).is_some_and(u8::is_ascii_digit) {
+            return Ordering::Greater;
+        }
+        if b.get(j).is_some_and(u8::is_ascii_digit) {
+            return Ordering::Less;
+        }
+        if first_diff != Ordering::Equal {
+            return first_diff;
+        }
+    }
+    Ordering::Equal
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::pkg::{Dependency, Package, PkgSource};
+    use crate::pkg::{compare_versions, Dependency, MultiArch, Package, PkgSource};
     use crate::RepoType;
+    use std::cmp::Ordering;
     use toml;
     #[test]
     fn package_seralization_deserialization() {
@@ -87,6 +342,9 @@ mod tests {
                     Dependency("dep3".to_string(), "5.1".to_string()),
                 ],
             ]),
+            arch: None,
+            multi_arch: None,
+            sha256: None,
         };
         let pkg_str_ser = toml::to_string(&package).unwrap();
         println!("{}", pkg_str_ser);
@@ -98,8 +356,83 @@ mod tests {
         dependencies = [[['dep1', '3.1']], [['dep2', ''], ['dep3', '5.1']]]
         "#;
 
-        let pkg_de: Package = toml::from_str(&pkg_str).unwrap();
+        let pkg_de: Package = toml::from_str(pkg_str).unwrap();
 
         assert_eq!(pkg_de, package);
     }
+
+    #[test]
+    fn dependency_version_requirements() {
+        assert!(Dependency::from("libc6", None).satisfied_by("2.31"));
+        assert!(Dependency::from("libc6", Some(">=2.28")).satisfied_by("2.31"));
+        assert!(!Dependency::from("libc6", Some(">=2.28")).satisfied_by("2.27"));
+        assert!(Dependency::from("libc6", Some("<<2.28")).satisfied_by("2.27"));
+        assert!(!Dependency::from("libc6", Some("=2.28")).satisfied_by("2.31"));
+    }
+
+    #[test]
+    fn debian_version_ordering() {
+        let cmp = compare_versions;
+        assert_eq!(cmp("1.2.10", "1.2.9"), Ordering::Greater);
+        assert_eq!(cmp("1.0", "1.0-0"), Ordering::Equal);
+        assert_eq!(cmp("0:1.0", "1.0"), Ordering::Equal);
+        // epochs win over everything else
+        assert_eq!(cmp("1:2.30-1", "2.31-1"), Ordering::Greater);
+        assert_eq!(cmp("1:1.0", "2:0.1"), Ordering::Less);
+        // ~ sorts before the end of the version
+        assert_eq!(cmp("1.0~rc1", "1.0"), Ordering::Less);
+        assert_eq!(cmp("1.0~rc1", "1.0~rc2"), Ordering::Less);
+        assert_eq!(cmp("1.0~~", "1.0~"), Ordering::Less);
+        assert_eq!(cmp("2.31-13~bpo1", "2.31-13"), Ordering::Less);
+        // stable updates and binary rebuilds come after the revision they patch
+        assert_eq!(cmp("2.31-13+deb11u5", "2.31-13"), Ordering::Greater);
+        assert_eq!(
+            cmp("2.31-13+deb11u10", "2.31-13+deb11u5"),
+            Ordering::Greater
+        );
+        assert_eq!(cmp("2.31-13+deb11u5", "2.31-14"), Ordering::Less);
+        assert_eq!(cmp("1.0+b1", "1.0"), Ordering::Greater);
+        // letters sort before other characters
+        assert_eq!(cmp("1.0a", "1.0+"), Ordering::Less);
+        assert!(Dependency::from("libc6", Some(">=2.31-13")).satisfied_by("2.31-13+deb11u5"));
+        assert!(Dependency::from("bash", Some(">>5.0")).satisfied_by("1:4.4"));
+    }
+
+    #[test]
+    fn multiarch_dependencies() {
+        let pkg = |name: &str, arch: &str, ma: Option<MultiArch>| {
+            let mut p = Package::new(name, "1.0");
+            p.arch = Some(arch.to_string());
+            p.multi_arch = ma;
+            p
+        };
+        let native = "amd64";
+        let libc_i386 = pkg("libc6", "i386", Some(MultiArch::Same));
+        let python_arm64 = pkg("python3", "arm64", Some(MultiArch::Allowed));
+        let make_arm64 = pkg("make", "arm64", Some(MultiArch::Foreign));
+        let data = pkg("tzdata", "all", None);
+
+        // plain dependencies need the same architecture unless the package is foreign
+        assert!(Dependency::from("libc6", None).is_satisfied_by(&libc_i386, "i386", native));
+        assert!(!Dependency::from("libc6", None).is_satisfied_by(&libc_i386, "amd64", native));
+        assert!(Dependency::from("make", None).is_satisfied_by(&make_arm64, "amd64", native));
+        assert!(Dependency::from("tzdata", None).is_satisfied_by(&data, "amd64", native));
+
+        // :any needs Multi-Arch: allowed
+        assert!(Dependency::from("python3:any", None).is_satisfied_by(
+            &python_arm64,
+            "amd64",
+            native
+        ));
+        assert!(!Dependency::from("libc6:any", None).is_satisfied_by(&libc_i386, "amd64", native));
+        // foreign packages of another architecture do not satisfy :any, but native ones do
+        assert!(!Dependency::from("make:any", None).is_satisfied_by(&make_arm64, "amd64", native));
+        assert!(Dependency::from("make:any", None).is_satisfied_by(&make_arm64, "arm64", native));
+
+        // explicit architecture qualifiers
+        assert!(Dependency::from("libc6:i386", None).is_satisfied_by(&libc_i386, "amd64", native));
+        assert!(
+            !Dependency::from("libc6:native", None).is_satisfied_by(&libc_i386, "amd64", native)
+        );
+    }
 }
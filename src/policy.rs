@@ -0,0 +1,171 @@
+use serde_derive::{Deserialize, Serialize};
+use std::cmp::Ordering;
+
+use crate::pkg::compare_versions;
+use crate::{Dependency, NebulaError, Package, Repository};
+
+/// Priority of repositories that do not set one.
+pub const DEFAULT_PRIORITY: i32 = 500;
+
+/// A pin rule, similar to apt preferences. Candidates whose name matches `package` (and, if set,
+/// come from `repository` and have a version matching `version`) get the pin's priority instead
+/// of their repository's one. The first matching pin applies. Candidates with a negative priority
+/// are never selected.
+///
+/// ```toml
+/// [[pins]]
+/// package = "gcc-*"
+/// version = "10.*"
+/// priority = 1000
+/// ```
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct Pin {
+    /// Glob matching package names, `*` and `?` are supported
+    pub package: String,
+    /// Name of the repository the candidate must come from
+    pub repository: Option<String>,
+    /// Glob the candidate's version must match
+    pub version: Option<String>,
+    pub priority: i32,
+}
+
+impl Pin {
+    pub fn matches(&self, package: &Package, repo_name: &str) -> bool {
+        glob_match(&self.package, &package.name)
+            && self.repository.as_ref().is_none_or(|r| r == repo_name)
+            && self
+                .version
+                .as_ref()
+                .is_none_or(|v| glob_match(v, &package.version))
+    }
+}
+
+/// A package available in a repository, with the priority assigned by the policy.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub package: Package,
+    pub repository: String,
+    pub priority: i32,
+}
+
+/// Returns the priority of a package coming from the given repository.
+pub fn priority_of(package: &Package, repo: &dyn Repository, pins: &[Pin]) -> i32 {
+    match pins.iter().find(|p| p.matches(package, repo.name())) {
+        Some(pin) => pin.priority,
+        None => repo.priority(),
+    }
+}
+
+/// Lists every candidate for the package named `name` across all repositories, sorted from the
+/// preferred candidate to the least preferred one: higher priority first, and higher version
+/// first among candidates with the same priority. Candidates with a negative priority are
+/// dropped.
+pub fn candidates(
+    repos: &[impl Repository],
+    pins: &[Pin],
+    name: &str,
+) -> Result<Vec<Candidate>, NebulaError> {
+    let mut candidates = vec![];
+    for repo in repos {
+        for package in repo.search(name, None)?.unwrap_or_default() {
+            if package.name != name {
+                continue;
+            }
+            let priority = priority_of(&package, repo, pins);
+            if priority < 0 {
+                continue;
+            }
+            candidates.push(Candidate {
+                package,
+                repository: repo.name().to_string(),
+                priority,
+            });
+        }
+    }
+    candidates.sort_by(|a, b| match b.priority.cmp(&a.priority) {
+        Ordering::Equal => compare_versions(&b.package.version, &a.package.version),
+        ord => ord,
+    });
+    Ok(candidates)
+}
+
+/// Selects the candidate to install (or upgrade to) for the package named `name`, honouring
+/// repository priorities and pin rules.
+pub fn select_candidate(
+    repos: &[impl Repository],
+    pins: &[Pin],
+    name: &str,
+) -> Result<Option<Candidate>, NebulaError> {
+    Ok(candidates(repos, pins, name)?.into_iter().next())
+}
+
+/// Selects the preferred candidate satisfying a dependency of a package of architecture
+/// `depender_arch`. Architecture qualified dependencies (`pkg:any`, `pkg:arm64`...) are resolved
+/// following the Multi-Arch semantics.
+pub fn resolve_dependency(
+    repos: &[impl Repository],
+    pins: &[Pin],
+    dependency: &Dependency,
+    depender_arch: &str,
+    native: &str,
+) -> Result<Option<Candidate>, NebulaError> {
+    let (name, _) = dependency.split_arch();
+    Ok(candidates(repos, pins, name)?
+        .into_iter()
+        .find(|c| dependency.is_satisfied_by(&c.package, depender_arch, native)))
+}
+
+/// Matches `text` against a glob pattern supporting `*` (any sequence) and `?` (any character).
+pub fn glob_match(pattern: &str, text: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let text: Vec<char> = text.chars().collect();
+    let (mut p, mut t) = (0, 0);
+    // position of the last `*` seen and the text position it matched up to
+    let mut star: Option<(usize, usize)> = None;
+    while t < text.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, t));
+            p += 1;
+        } else if let Some((sp, st)) = star {
+            // let the last `*` consume one more character
+            p = sp + 1;
+            t = st + 1;
+            star = Some((sp, st + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::policy::{glob_match, Pin};
+    use crate::Package;
+
+    #[test]
+    fn glob_patterns() {
+        assert!(glob_match("gcc-*", "gcc-10"));
+        assert!(glob_match("*", ""));
+        assert!(glob_match("lib?", "libc"));
+        assert!(glob_match("*-dev", "libssl-dev"));
+        assert!(!glob_match("gcc-*", "g++-10"));
+        assert!(!glob_match("lib?", "libcc"));
+    }
+
+    #[test]
+    fn pin_matching() {
+        let pin = Pin {
+            package: "gcc*".to_string(),
+            repository: Some("bookworm".to_string()),
+            version: Some("12.*".to_string()),
+            priority: 1000,
+        };
+        assert!(pin.matches(&Package::new("gcc-12", "12.2.0-14"), "bookworm"));
+        assert!(!pin.matches(&Package::new("gcc-12", "12.2.0-14"), "unstable"));
+        assert!(!pin.matches(&Package::new("gcc-13", "13.1.0-1"), "bookworm"));
+    }
+}
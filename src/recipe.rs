@@ -0,0 +1,313 @@
+use serde_derive::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Configuration;
+use crate::hash::Checksum;
+use crate::install;
+use crate::policy::{self, Candidate};
+use crate::state::InstalledState;
+use crate::Repository;
+use crate::{download_verified, nbpkg, run_cmd_logged, Dependency, NebulaError, Package};
+
+/// A declarative build recipe. It describes where to get the sources from and the steps to
+/// build and install them into a staging directory, from which a nebula package is created.
+///
+/// ```toml
+/// name = "hello"
+/// version = "2.10"
+/// build-depends = ["gcc", "make"]
+/// workdir = "hello-2.10"
+///
+/// [[sources]]
+/// url = "https://ftp.gnu.org/gnu/hello/hello-2.10.tar.gz"
+/// sha256 = "31e066137a962676e89f69d1b65382de95a7ef7d914b8cb956f41ea72e0f516b"
+///
+/// [steps]
+/// configure = ["./configure --prefix=/usr"]
+/// build = ["make"]
+/// install = ["make DESTDIR=$DESTDIR install"]
+/// ```
+#[derive(Deserialize, Debug, Clone)]
+pub struct Recipe {
+    pub name: String,
+    pub version: String,
+    /// Runtime dependencies of the resulting package
+    #[serde(rename = "dependencies")]
+    pub depends: Option<Vec<Vec<Dependency>>>,
+    /// Packages needed to build the recipe, the missing ones are installed from the repositories
+    #[serde(rename = "build-depends", default)]
+    pub build_depends: Vec<String>,
+    #[serde(default)]
+    pub sources: Vec<Source>,
+    /// Directory (relative to the sources directory) the steps are run in
+    pub workdir: Option<String>,
+    #[serde(default)]
+    pub steps: Steps,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Source {
+    pub url: String,
+    pub sha256: String,
+    /// Whether to extract the source (if it's a tarball), defaults to true
+    #[serde(default = "default_extract")]
+    pub extract: bool,
+}
+
+fn default_extract() -> bool {
+    true
+}
+
+/// Shell commands of each build step, run in order with `sh -c`.
+#[derive(Deserialize, Debug, Clone, Default)]
+pub struct Steps {
+    #[serde(default)]
+    pub configure: Vec<String>,
+    #[serde(default)]
+    pub build: Vec<String>,
+    #[serde(default)]
+    pub install: Vec<String>,
+}
+
+impl Recipe {
+    pub fn from(path: &Path) -> Result<Recipe, NebulaError> {
+        crate::read_toml(path)
+    }
+
+    /// Directory where the recipe is built: `fakeroot-dir/build/<name>-<version>`.
+    pub fn build_dir(&self, config: &Configuration) -> PathBuf {
+        config
+            .fakerootdir
+            .join("build")
+            .join(format!("{}-{}", self.name, self.version))
+    }
+
+    /// Path of the file the output of every build step is logged to.
+    pub fn log_file(&self, config: &Configuration) -> PathBuf {
+        config
+            .nebulahome
+            .join("logs")
+            .join(format!("{}-{}.log", self.name, self.version))
+    }
+
+    /// Selects a candidate, honouring repository priorities and pins, for every build dependency
+    /// that is not installed yet.
+    pub fn resolve_build_depends(
+        &self,
+        config: &Configuration,
+        repos: &[impl Repository],
+        state: &InstalledState,
+    ) -> Result<Vec<Candidate>, NebulaError> {
+        let mut resolved = vec![];
+        for dep in &self.build_depends {
+            if state.get(dep).is_some() {
+                debug!("build dependency {} is installed", dep);
+                continue;
+            }
+            match policy::select_candidate(repos, &config.pins, dep)? {
+                Some(c) => resolved.push(c),
+                None => {
+                    return Err(NebulaError::BuildError(format!(
+                        "build dependency {} of {} not found in any repository",
+                        dep, self.name
+                    )))
+                }
+            }
+        }
+        Ok(resolved)
+    }
+
+    /// Builds the recipe: installs the missing build dependencies, fetches and verifies the
+    /// sources, runs the build steps installing into the staging directory and creates a nebula
+    /// package from it. Returns the path of the created package. The installed dependencies are
+    /// recorded in the state, which is not saved.
+    pub fn build(
+        &self,
+        config: &Configuration,
+        repos: &[impl Repository],
+        state: &mut InstalledState,
+    ) -> Result<PathBuf, NebulaError> {
+        println!("[*] building {}-{}", self.name, self.version);
+        for candidate in self.resolve_build_depends(config, repos, state)? {
+            println!(
+                "[*] installing build dependency {} {} from {}",
+                candidate.package.name, candidate.package.version, candidate.repository
+            );
+            install::install(config, repos, state, &candidate)?;
+        }
+
+        let net = &config.network;
+        let build_dir = self.build_dir(config);
+        let src_dir = build_dir.join("src");
+        let stage_dir = build_dir.join("stage");
+        if build_dir.exists() {
+            if let Err(e) = fs::remove_dir_all(&build_dir) {
+                return Err(NebulaError::Fs(format!(
+                    "cannot clean {}: {}",
+                    build_dir.display(),
+                    e
+                )));
+            }
+        }
+        for dir in &[&src_dir, &stage_dir, &config.nebulahome.join("logs")] {
+            if let Err(e) = fs::create_dir_all(dir) {
+                return Err(NebulaError::Fs(format!(
+                    "cannot create {}: {}",
+                    dir.display(),
+                    e
+                )));
+            }
+        }
+
+        // fetch, verify and extract sources
+        for source in &self.sources {
+            let filename = source.url.rsplit('/').next().unwrap_or(&self.name);
+            let src_path = build_dir.join(filename);
+            info!("fetching source {}", source.url);
+            download_verified(
+                &source.url,
+                &src_path,
+                net,
+                &Checksum::sha256(&source.sha256),
+            )?;
+            if source.extract && filename.contains(".tar") {
+                crate::run_cmd(
+                    "/usr/bin/tar",
+                    &[
+                        "-xf",
+                        src_path.to_str().unwrap(),
+                        "-C",
+                        src_dir.to_str().unwrap(),
+                    ],
+                )?;
+            } else if let Err(e) = fs::copy(&src_path, src_dir.join(filename)) {
+                return Err(NebulaError::Io(e));
+            }
+        }
+
+        // run the build steps, streaming their output to the log file
+        let workdir = match &self.workdir {
+            Some(w) => src_dir.join(w),
+            None => src_dir.clone(),
+        };
+        let log_file = self.log_file(config);
+        let _ = fs::remove_file(&log_file);
+        let env = [
+            ("DESTDIR", stage_dir.to_str().unwrap()),
+            ("SRCDIR", src_dir.to_str().unwrap()),
+        ];
+        let steps = [
+            ("configure", &self.steps.configure),
+            ("build", &self.steps.build),
+            ("install", &self.steps.install),
+        ];
+        for (step, cmds) in steps.iter() {
+            for cmd in cmds.iter() {
+                info!("[{}] {}: {}", self.name, step, cmd);
+                if let Err(e) = run_cmd_logged("/bin/sh", &["-c", cmd], &workdir, &env, &log_file) {
+                    eprintln!(
+                        "[!] {} step of {} failed, see {}",
+                        step,
+                        self.name,
+                        log_file.display()
+                    );
+                    return Err(e);
+                }
+            }
+        }
+
+        let mut package = Package::new(&self.name, &self.version);
+        package.depends = self.depends.clone();
+        nbpkg::build(&stage_dir, package, &build_dir)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::nbpkg::{extract_nbpkg, DATA_DIR};
+    use crate::recipe::Recipe;
+    use crate::repos::Repositories;
+    use crate::state::InstalledState;
+    use crate::testutil::{installed, temp_dir};
+    use crate::{file2hash, run_cmd, NebulaError};
+    use std::fs;
+
+    const RECIPE: &str = r#"
+name = "hello"
+version = "1.0"
+build-depends = ["make"]
+workdir = "hello-1.0"
+
+[[sources]]
+url = "file://{url}"
+sha256 = "{sha256}"
+
+[steps]
+configure = ["echo configuring in $(basename $PWD)"]
+build = ["cat hello.sh"]
+install = ["mkdir -p $DESTDIR/usr/bin", "cp hello.sh $DESTDIR/usr/bin/hello"]
+"#;
+
+    #[test]
+    fn build_recipe() {
+        let tmp = temp_dir("recipe");
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("src/hello-1.0")).unwrap();
+        fs::write(dir.join("src/hello-1.0/hello.sh"), "echo hello\n").unwrap();
+        let tarball = dir.join("hello-1.0.tar.gz");
+        run_cmd(
+            "/usr/bin/tar",
+            &[
+                "-czf",
+                tarball.to_str().unwrap(),
+                "-C",
+                dir.join("src").to_str().unwrap(),
+                "hello-1.0",
+            ],
+        )
+        .unwrap();
+        let write_recipe = |sha256: &str| {
+            let path = dir.join("hello.toml");
+            let recipe = RECIPE
+                .replace("{url}", tarball.to_str().unwrap())
+                .replace("{sha256}", sha256);
+            fs::write(&path, recipe).unwrap();
+            Recipe::from(&path).unwrap()
+        };
+        let config = Configuration::builder(dir.join("nebula")).build();
+        let repos: Repositories = vec![];
+        let mut state = InstalledState::default();
+
+        let recipe = write_recipe(&"0".repeat(64));
+        assert_eq!(recipe.build_depends, vec!["make"]);
+        assert!(recipe.sources[0].extract);
+        assert_eq!(recipe.steps.install.len(), 2);
+
+        // build dependencies are neither installed nor available
+        match recipe.build(&config, &repos, &mut state) {
+            Err(NebulaError::BuildError(e)) => assert!(e.contains("make")),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        state
+            .record_install(installed("make", "4.3", None))
+            .unwrap();
+        assert!(matches!(
+            recipe.build(&config, &repos, &mut state),
+            Err(NebulaError::IncorrectHash)
+        ));
+
+        let recipe = write_recipe(&file2hash(&tarball).unwrap());
+        let package = recipe.build(&config, &repos, &mut state).unwrap();
+        let manifest = extract_nbpkg(&package, &dir.join("out")).unwrap();
+        assert_eq!(manifest.package.name, "hello");
+        assert_eq!(
+            fs::read_to_string(dir.join("out").join(DATA_DIR).join("usr/bin/hello")).unwrap(),
+            "echo hello\n"
+        );
+        let log = fs::read_to_string(recipe.log_file(&config)).unwrap();
+        assert!(log.contains("configuring in hello-1.0"));
+        assert!(log.contains("echo hello"));
+    }
+}
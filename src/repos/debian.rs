@@ -1,17 +1,26 @@
 use regex::Regex;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use std::fs;
-use std::io::{self, BufRead, BufReader};
+use std::io::{BufRead, BufReader};
 use std::path::{Path, PathBuf};
 
+use crate::config::{Arch, Configuration, NetworkConfig};
+use crate::hash::{Checksum, HashAlgorithm};
+use crate::nbpkg;
+use crate::pkg::MultiArch;
+use crate::policy::DEFAULT_PRIORITY;
+use crate::repos;
+use crate::repos::pdiff::{self, PdiffIndex};
+use crate::transport::{Fetched, Validators};
 use crate::{
-    download, file2hash, pkg, Dependency, NebulaError, Package, RepoType, Repository, CONFIG,
+    download_if_modified, download_verified, file2hash, pkg, Dependency, NebulaError, Package,
+    RepoType, Repository,
 };
 
 // ------------------------------------------------------------------ //
 //                          Configuration
 // ------------------------------------------------------------------ //
-#[derive(Deserialize, Clone, Debug)]
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum Component {
     #[serde(rename = "main")]
     Main,
@@ -19,6 +28,8 @@ pub enum Component {
     Contrib,
     #[serde(rename = "non-free")]
     NonFree,
+    #[serde(rename = "non-free-firmware")]
+    NonFreeFirmware,
 }
 
 impl Component {
@@ -27,15 +38,25 @@ impl Component {
             Component::Main => "main",
             Component::Contrib => "contrib",
             Component::NonFree => "non-free",
+            Component::NonFreeFirmware => "non-free-firmware",
         }
     }
 }
 
-/// Struct containing all configuration related to debian packages
-#[derive(Deserialize, Clone, Debug)]
+/// Struct containing all configuration related to debian packages. Several debian-family
+/// repositories can be configured, each with its own name, suite and components.
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct DebConfig {
+    /// Name of the repository, its index is stored in `nebula-dir/repo/<name>`
+    pub name: String,
+    /// Root of the archive, e.g. `http://ftp.debian.org/debian`
     pub repository: String,
+    /// Suite (distribution) to use, e.g. `unstable` or `bookworm-security`
+    pub suite: String,
     pub components: Vec<Component>,
+    /// Priority of the repository's packages, defaults to `policy::DEFAULT_PRIORITY`
+    pub priority: Option<i32>,
+    pub network: Option<NetworkConfig>,
 }
 
 // ------------------------------------------------------------------ //
@@ -44,89 +65,49 @@ pub struct DebConfig {
 
 pub struct Debian<'d> {
     conf: &'d DebConfig,
+    config: &'d Configuration,
     // here debian configuration independent variables are defined
     repo_dir: PathBuf,
 }
 
 impl<'d> Repository for Debian<'d> {
-    /*
-    fn get_type(&self) -> RepoType {
+    fn repo_type(&self) -> RepoType {
         RepoType::Debian
-    }_
-    */
+    }
+
+    fn name(&self) -> &str {
+        &self.conf.name
+    }
+
+    fn priority(&self) -> i32 {
+        self.conf.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
 
     fn initialize(&self) -> Result<(), NebulaError> {
+        // finish an interrupted update before using the index
+        repos::recover_update(&self.repo_dir)?;
         if !self.repo_dir.is_dir() {
-            fs::create_dir(&self.repo_dir).unwrap(); // create home/repo/debian
+            if let Err(e) = fs::create_dir(&self.repo_dir) {
+                return Err(NebulaError::Fs(format!(
+                    "cannot create {}: {}",
+                    self.repo_dir.display(),
+                    e
+                )));
+            }
         }
         Ok(())
     }
 
     fn update(&self) -> Result<(), NebulaError> {
-        println!("[*] updating debian repositories");
-        // remove old files from debian/repo
-        for entry in self.repo_dir.read_dir().expect("read_dir call failed") {
-            if let Ok(entry) = entry {
-                if let Err(err) = fs::remove_file(entry.path()) {
-                    return Err(NebulaError::Fs(format!(
-                        "Cannot clean deb repo file: {}",
-                        err
-                    )));
-                }
-            }
-        }
-
-        info!("Downloading relase file...");
-        download(
-            format!("{}/InRelease", self.conf.repository),
-            &self.repo_dir.join("InRelease"),
-        );
-
-        for component in &self.conf.components {
-            info!("updating debian component {}...", component.to_str());
-            // parse InRelease to get the sha256 hash of Packages.xz
-            let expected_hash = Self::package_file_hash(
-                &self.repo_dir.join("InRelease"),
-                &component.to_str(),
-                CONFIG.arch.to_str(),
-            )
-            .unwrap();
-
-            // download package list for the component
-            let pkgs_filename = self
-                .repo_dir
-                .join(format!("Packages-{}.xz", component.to_str()));
-            download(
-                format!(
-                    "{}/{}/binary-{}/Packages.xz",
-                    self.conf.repository,
-                    component.to_str(),
-                    CONFIG.arch.to_str()
-                ),
-                &pkgs_filename,
-            );
-
-            // compare expected and computed hash of the downloaded file
-            let real_hash = file2hash(Path::new(&pkgs_filename)).unwrap();
-            if !real_hash.eq(&expected_hash) {
-                error!("Expected and real hash of {}", pkgs_filename.display());
-                return Err(NebulaError::IncorrectHash);
-            }
-
-            // extract Packages.xz file in place
-            debug!("extracting {} with unxz", pkgs_filename.display());
-            crate::run_cmd(
-                "/usr/bin/unxz",
-                &["--force", pkgs_filename.to_str().unwrap()],
-            )?;
-        }
-        Ok(())
+        println!("[*] updating debian repository {}", self.conf.name);
+        // the new index is downloaded next to the current one, which is kept if anything fails
+        repos::staged_update(&self.repo_dir, |dir| self.fetch_index(dir))
     }
 
     fn search(
         &self,
         name: &str,
-        version: Option<&str>,
+        _version: Option<&str>,
     ) -> Result<Option<Vec<Package>>, NebulaError> {
         fn read_line(buff: &mut dyn BufRead, line: &mut String) -> Result<usize, NebulaError> {
             line.clear();
@@ -137,22 +118,51 @@ impl<'d> Repository for Debian<'d> {
         }
 
         // regex
-        let re = Regex::new(format!(r"^Package: ({}.*)", name).as_str()).unwrap();
+        // the name is matched literally and entirely: names like libstdc++6 have regex
+        // metacharacters and hello must not match hello-doc
+        let re = Regex::new(&format!(r"^Package: ({})\s*$", regex::escape(name))).unwrap();
         let re_version = Regex::new(r"^Version: (.+)").unwrap();
         let re_src = Regex::new(r"^Filename: (.+)").unwrap();
         let re_depends = Regex::new(r"^Depends: (.+)").unwrap();
+        let re_arch = Regex::new(r"^Architecture: (.+)").unwrap();
+        let re_multiarch = Regex::new(r"^Multi-Arch: (.+)").unwrap();
+        let re_sha256 = Regex::new(r"^SHA256: (.+)").unwrap();
 
         // let mut matches = vec![];
         let mut pkgs_list = vec![];
+        // index files of every component and architecture, binary-all is only there if the
+        // archive lists arch:all packages separately (see `separate_arch_all`), and foreign
+        // architectures only if the archive carries them
+        let mut index_files = vec![];
         for component in &self.conf.components {
-            let mut buff = BufReader::new(
-                fs::File::open(format!(
-                    "{}/Packages-{}",
-                    self.repo_dir.display(),
-                    component.to_str()
-                ))
-                .expect("Package file for component not found"),
-            );
+            for arch in self.config.architectures() {
+                let path = self
+                    .repo_dir
+                    .join(Self::packages_filename(component, &arch));
+                if arch == self.config.arch || path.is_file() {
+                    index_files.push(path);
+                }
+            }
+            let all = self
+                .repo_dir
+                .join(Self::packages_filename(component, &Arch::All));
+            if all.is_file() {
+                index_files.push(all);
+            }
+        }
+
+        for index_file in index_files {
+            let mut buff = match fs::File::open(&index_file) {
+                Ok(f) => BufReader::new(f),
+                Err(e) => {
+                    return Err(NebulaError::Fs(format!(
+                        "cannot read the index of repository {} ({}), run nb-update: {}",
+                        self.conf.name,
+                        index_file.display(),
+                        e
+                    )))
+                }
+            };
 
             //let mut match_info = String::new();
             let mut line = String::new();
@@ -205,6 +215,16 @@ impl<'d> Repository for Debian<'d> {
                                 package.source =
                                     Some(pkg::PkgSource::from(RepoType::Debian, &pkg_url));
                             }
+                            // get architecture and multi-arch info
+                            if let Some(cap) = re_arch.captures(&line) {
+                                package.arch = Some(cap[1].trim().to_string());
+                            }
+                            if let Some(cap) = re_multiarch.captures(&line) {
+                                package.multi_arch = MultiArch::parse(cap[1].trim());
+                            }
+                            if let Some(cap) = re_sha256.captures(&line) {
+                                package.sha256 = Some(cap[1].trim().to_string());
+                            }
                             // get dependencies
                             if re_depends.is_match(&line) {
                                 let cap = re_depends
@@ -215,7 +235,7 @@ impl<'d> Repository for Debian<'d> {
                                     .get(1)
                                     .expect("Cannot gather dependencies list")
                                     .as_str();
-                                let pkg_deps = Self::parse_dependecies_str(&deps_str)?;
+                                let pkg_deps = Self::parse_dependecies_str(deps_str)?;
                                 package.depends = Some(pkg_deps);
                             }
                         }
@@ -230,39 +250,349 @@ impl<'d> Repository for Debian<'d> {
             Ok(Some(pkgs_list))
         }
     }
+
+    fn fetch(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError> {
+        let net = self.config.network.merge(self.conf.network.as_ref());
+        // the archive is verified against the hash listed in the package index
+        let checksum = package.sha256.as_deref().map(Checksum::sha256);
+        package.fetch(dir, &net, checksum.as_ref())
+    }
 }
 
 impl<'d> Debian<'d> {
-    pub fn new() -> Result<Debian<'d>, NebulaError> {
-        let conf = match &CONFIG.repos.debian {
-            Some(c) => c,
-            None => return Err(NebulaError::RepoConfigNotFound),
+    pub fn new(config: &'d Configuration, conf: &'d DebConfig) -> Result<Debian<'d>, NebulaError> {
+        let repo_dir = config.nebulahome.join("repo").join(&conf.name);
+        Ok(Debian {
+            conf,
+            config,
+            repo_dir,
+        })
+    }
+
+    /// Downloads and verifies the release file and the package lists of every component into
+    /// `dir`. Returns false if the release file did not change since the last update, nothing is
+    /// downloaded then. Package lists that did not change are reused, and the ones that changed
+    /// are updated applying deltas (PDiff) if possible.
+    fn fetch_index(&self, dir: &Path) -> Result<bool, NebulaError> {
+        let net = self.config.network.merge(self.conf.network.as_ref());
+        let release_url = format!("{}/InRelease", self.dists_url());
+        let release = dir.join("InRelease");
+        let old_release = self.repo_dir.join("InRelease");
+
+        let lists = |archs: &[Arch]| -> Vec<(&Component, Arch)> {
+            let mut lists = vec![];
+            for component in &self.conf.components {
+                for arch in archs {
+                    lists.push((component, *arch));
+                }
+            }
+            lists
+        };
+
+        info!("Downloading relase file...");
+        let mut validators = repos::load_validators(&self.repo_dir);
+        // a conditional request only makes sense if the current index is complete
+        let complete = old_release.is_file()
+            && lists(&self.release_architectures(&old_release)?)
+                .iter()
+                .all(|(c, a)| self.repo_dir.join(Self::packages_filename(c, a)).is_file());
+        let previous = match validators.get(&release_url) {
+            Some(v) if complete => v.clone(),
+            _ => Validators::default(),
+        };
+        match download_if_modified(&release_url, &release, &net, &previous)? {
+            Fetched::NotModified => {
+                info!("repository {} is up to date", self.conf.name);
+                return Ok(false);
+            }
+            Fetched::Modified(v) => validators.insert(release_url.clone(), v),
+        };
+
+        let mut archs = self.release_architectures(&release)?;
+        for arch in self.config.architectures() {
+            if !archs.contains(&arch) {
+                warn!(
+                    "repository {} has no {} packages, skipping that architecture",
+                    self.conf.name,
+                    arch.to_str()
+                );
+            }
+        }
+        // architecture independent packages are only listed on their own if the archive says so
+        if Self::separate_arch_all(&release)? {
+            archs.push(Arch::All);
+        }
+        // fetching the lists by hash avoids races with mirror updates
+        let by_hash = Self::release_field(&release, "Acquire-By-Hash")?.as_deref() == Some("yes");
+
+        for (component, arch) in lists(&archs) {
+            let list_path = format!("{}/binary-{}/Packages", component.to_str(), arch.to_str());
+            let filename = Self::packages_filename(component, &arch);
+            let current = self.repo_dir.join(&filename);
+            // parse InRelease to get the hash of Packages.xz
+            let expected_hash = match Self::release_hash(&release, &format!("{}.xz", list_path))? {
+                Some(h) => h,
+                // a suite can carry a foreign architecture in some components only
+                None if arch != self.config.arch => {
+                    warn!(
+                        "{}.xz is not listed in {}, skipping it",
+                        list_path, release_url
+                    );
+                    continue;
+                }
+                None => {
+                    return Err(NebulaError::Fs(format!(
+                        "{}.xz is not listed in {}",
+                        list_path, release_url
+                    )))
+                }
+            };
+
+            // reuse the current list if it did not change
+            if current.is_file()
+                && old_release.is_file()
+                && Self::release_hash(&old_release, &format!("{}.xz", list_path))?
+                    == Some(expected_hash.clone())
+            {
+                debug!("{} did not change", list_path);
+                repos::link_or_copy(&current, &dir.join(&filename))?;
+                continue;
+            }
+
+            // otherwise try to update the current list applying deltas
+            if current.is_file() {
+                let dest = dir.join(&filename);
+                match self.fetch_pdiff(&net, &release, by_hash, &list_path, &current, &dest) {
+                    Ok(()) => continue,
+                    Err(e) => info!("cannot update {} with deltas: {}", list_path, e),
+                }
+            }
+
+            info!(
+                "updating debian component {} ({})...",
+                component.to_str(),
+                arch.to_str()
+            );
+            // download package list for the component, verified while it is downloaded
+            let pkgs_filename = dir.join(format!("{}.xz", filename));
+            self.download_listed(
+                &net,
+                &format!("{}.xz", list_path),
+                &expected_hash,
+                by_hash,
+                &pkgs_filename,
+            )?;
+
+            // extract Packages.xz file in place
+            debug!("extracting {} with unxz", pkgs_filename.display());
+            crate::run_cmd(
+                "/usr/bin/unxz",
+                &["--force", pkgs_filename.to_str().unwrap()],
+            )?;
+        }
+        repos::save_validators(dir, &validators)?;
+        Ok(true)
+    }
+
+    /// Brings the package list `current` up to date applying the deltas published in
+    /// `<list_path>.diff/` (Debian's PDiff), writing the result into `dest`. The result must
+    /// match the hash of the list in the release file.
+    fn fetch_pdiff(
+        &self,
+        net: &NetworkConfig,
+        release: &Path,
+        by_hash: bool,
+        list_path: &str,
+        current: &Path,
+        dest: &Path,
+    ) -> Result<(), NebulaError> {
+        let expected = match Self::release_hash(release, list_path)? {
+            Some(h) => h,
+            None => return Err(NebulaError::Pdiff(format!("{} is not listed", list_path))),
+        };
+        let diff_url = format!("{}/{}.diff", self.dists_url(), list_path);
+        let index_path = format!("{}.diff/Index", list_path);
+        let index_hash = match Self::release_hash(release, &index_path)? {
+            Some(h) => h,
+            None => return Err(NebulaError::Pdiff("no deltas published".to_string())),
         };
 
-        let repo_dir = CONFIG.nebulahome.join("repo/debian");
-        Ok(Debian { conf, repo_dir })
+        // the patches are downloaded into a temporary directory, removed whatever happens
+        let tmp = dest.with_extension("pdiff");
+        fs::create_dir_all(&tmp).map_err(NebulaError::Io)?;
+        let result = (|| {
+            let index_file = tmp.join("Index");
+            self.download_listed(net, &index_path, &index_hash, by_hash, &index_file)?;
+            let content = fs::read_to_string(&index_file).map_err(NebulaError::Io)?;
+            let index = PdiffIndex::parse(&content)?;
+            // deltas are described with SHA256 hashes only
+            let listed_sha256 = Self::release_checksums(release, list_path)?
+                .into_iter()
+                .any(|c| c.algorithm == HashAlgorithm::Sha256 && c.matches(&index.current));
+            if !listed_sha256 {
+                return Err(NebulaError::Pdiff(
+                    "deltas do not match the release".to_string(),
+                ));
+            }
+            let current_hash = file2hash(current).unwrap_or_default();
+            let names = match index.patches_for(&current_hash) {
+                Some(n) => n,
+                None => return Err(NebulaError::Pdiff("local list is too old".to_string())),
+            };
+
+            let mut patches = vec![];
+            for name in &names {
+                let unlisted = || NebulaError::Pdiff(format!("{} is not listed", name));
+                let gz = tmp.join(format!("{}.gz", name));
+                let gz_hash = index.download_hash(name).ok_or_else(unlisted)?;
+                download_verified(
+                    &format!("{}/{}.gz", diff_url, name),
+                    &gz,
+                    net,
+                    &Checksum::sha256(gz_hash),
+                )?;
+                crate::run_cmd("/bin/gzip", &["-d", "--force", gz.to_str().unwrap()])?;
+                let patch = tmp.join(name);
+                let patch_hash = index.patch_hash(name).ok_or_else(unlisted)?;
+                Checksum::sha256(patch_hash).verify_file(&patch)?;
+                patches.push(patch);
+            }
+            let patches: Vec<&Path> = patches.iter().map(|p| p.as_path()).collect();
+            pdiff::patch_file(current, &patches, dest)?;
+            if let Err(e) = expected.verify_file(dest) {
+                let _ = fs::remove_file(dest);
+                return Err(e);
+            }
+            info!("{} updated applying {} deltas", list_path, names.len());
+            Ok(())
+        })();
+        if let Err(e) = fs::remove_dir_all(&tmp) {
+            warn!("cannot remove {}: {}", tmp.display(), e);
+        }
+        result
+    }
+
+    /// Downloads a file of the suite listed in the release file, verifying it against the given
+    /// checksum while it is downloaded. If the archive supports it (`Acquire-By-Hash: yes`) the
+    /// file is fetched from `<dir>/by-hash/<algorithm>/<hash>`, which does not change while the
+    /// mirror is being updated, falling back to its plain path.
+    fn download_listed(
+        &self,
+        net: &NetworkConfig,
+        path: &str,
+        checksum: &Checksum,
+        by_hash: bool,
+        outfile: &Path,
+    ) -> Result<(), NebulaError> {
+        if by_hash {
+            let hash_dir = format!(
+                "by-hash/{}/{}",
+                Self::checksum_field(checksum.algorithm),
+                checksum.hash
+            );
+            let url = match path.rsplit_once('/') {
+                Some((dir, _)) => format!("{}/{}/{}", self.dists_url(), dir, hash_dir),
+                None => format!("{}/{}", self.dists_url(), hash_dir),
+            };
+            match download_verified(&url, outfile, net, checksum) {
+                Ok(()) => return Ok(()),
+                Err(e) => warn!("cannot fetch {} by hash, using its path: {}", path, e),
+            }
+        }
+        let url = format!("{}/{}", self.dists_url(), path);
+        download_verified(&url, outfile, net, checksum)
     }
-    pub fn extract_deb(deb_path: &Path) -> Result<(), NebulaError> {
-        // create a directory (with the same name of the deb to extract the deb into)
-        // if it exists delete the old directory first
-        let parent_dir = deb_path.parent().expect("Cannot extract root");
-        let out_dir = parent_dir.join(deb_path.file_stem().unwrap());
+
+    /// Name of the release file's checksum list (and by-hash directory) of an algorithm.
+    fn checksum_field(algorithm: HashAlgorithm) -> &'static str {
+        match algorithm {
+            HashAlgorithm::Sha256 => "SHA256",
+            HashAlgorithm::Sha512 => "SHA512",
+            HashAlgorithm::Md5 => "MD5Sum",
+        }
+    }
+
+    /// Name of the local (extracted) index of a component and architecture.
+    fn packages_filename(component: &Component, arch: &Arch) -> String {
+        format!("Packages-{}-{}", component.to_str(), arch.to_str())
+    }
+
+    /// Returns the value of a field of the release file's header, if present.
+    pub fn release_field(releasepath: &Path, field: &str) -> Result<Option<String>, NebulaError> {
+        let file = fs::File::open(releasepath).map_err(NebulaError::Io)?;
+        let prefix = format!("{}:", field);
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(NebulaError::Io)?;
+            // the header ends where the checksum lists start
+            if ["MD5Sum:", "SHA1:", "SHA256:", "SHA512:"]
+                .iter()
+                .any(|l| line.starts_with(l))
+            {
+                break;
+            }
+            if let Some(value) = line.strip_prefix(&prefix) {
+                return Ok(Some(value.trim().to_string()));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether arch:all packages are listed in their own `binary-all` lists. Like APT, these lists
+    /// are only fetched if the release lists `all` in its `Architectures`, and does not set
+    /// `No-Support-for-Architecture-all`, which means arch:all packages are already listed in the
+    /// lists of every architecture.
+    pub fn separate_arch_all(releasepath: &Path) -> Result<bool, NebulaError> {
+        let all_listed = Self::release_field(releasepath, "Architectures")?
+            .is_some_and(|archs| archs.split_whitespace().any(|a| a == "all"));
+        Ok(all_listed
+            && Self::release_field(releasepath, "No-Support-for-Architecture-all")?.is_none())
+    }
+
+    /// The enabled architectures the release carries packages for: the native one, whose lists are
+    /// required, and the foreign ones its `Architectures` field lists (all of them if it has no
+    /// such field). Third-party archives often carry only some architectures.
+    fn release_architectures(&self, releasepath: &Path) -> Result<Vec<Arch>, NebulaError> {
+        let listed = Self::release_field(releasepath, "Architectures")?;
+        Ok(self
+            .config
+            .architectures()
+            .into_iter()
+            .filter(|arch| {
+                *arch == self.config.arch
+                    || listed
+                        .as_ref()
+                        .is_none_or(|l| l.split_whitespace().any(|a| a == arch.to_str()))
+            })
+            .collect())
+    }
+
+    /// Returns the URL of the suite's directory: `<repository>/dists/<suite>`.
+    pub fn dists_url(&self) -> String {
+        format!("{}/dists/{}", self.conf.repository, self.conf.suite)
+    }
+
+    /// Extracts a debian package into `out_dir`, which must not exist: the control files end up
+    /// in `out_dir/control` (its `md5sums` is used by `verify`) and the payload tree in
+    /// `out_dir/data`.
+    pub fn extract_deb(deb_path: &Path, out_dir: &Path) -> Result<(), NebulaError> {
         if out_dir.exists() {
-            if let Err(e) = fs::remove_dir_all(&out_dir) {
+            return Err(NebulaError::Fs(format!(
+                "cannot extract {}, {} already exists",
+                deb_path.display(),
+                out_dir.display()
+            )));
+        }
+        let control_dir = out_dir.join("control");
+        let data_dir = out_dir.join(nbpkg::DATA_DIR);
+        for dir in &[&control_dir, &data_dir] {
+            if let Err(e) = fs::create_dir_all(dir) {
                 return Err(NebulaError::Fs(format!(
-                    "cannot clean {}: {}",
-                    out_dir.display(),
+                    "cannot create {}: {}",
+                    dir.display(),
                     e
                 )));
             }
         }
-        if let Err(e) = fs::create_dir(&out_dir) {
-            return Err(NebulaError::Fs(format!(
-                "cannot create {}: {}",
-                out_dir.display(),
-                e
-            )));
-        }
         // extract main deb archive
         crate::run_cmd(
             "/usr/bin/ar",
@@ -274,69 +604,80 @@ impl<'d> Debian<'d> {
             ],
         )?;
 
-        // extract control.tar.xz
-        crate::run_cmd(
-            "/usr/bin/tar",
-            &[
-                "-xf",
-                &format!("{}/control.tar.xz", out_dir.display()),
-                "-C",
-                out_dir.to_str().unwrap(),
-            ],
-        )?;
-
-        // create a new directory to extract the data tarball into
-        if let Err(e) = fs::create_dir(&format!("{}/data", out_dir.display())) {
-            return Err(NebulaError::Fs(e.to_string()));
+        // the control and data tarballs could be compressed with gz, xz, zstd or (older packages)
+        // bzip2, or not at all
+        let tarball = |name: &str| {
+            ["tar.xz", "tar.gz", "tar.zst", "tar.bz2", "tar"]
+                .iter()
+                .map(|ext| out_dir.join(format!("{}.{}", name, ext)))
+                .find(|p| p.is_file())
+                .ok_or_else(|| {
+                    NebulaError::Fs(format!("{} has no {} tarball", deb_path.display(), name))
+                })
+        };
+        for (name, dir) in &[("control", &control_dir), ("data", &data_dir)] {
+            let archive = tarball(name)?;
+            crate::run_cmd(
+                "/usr/bin/tar",
+                &[
+                    "-xf",
+                    archive.to_str().unwrap(),
+                    "-C",
+                    dir.to_str().unwrap(),
+                ],
+            )?;
+            let _ = fs::remove_file(&archive);
         }
+        let _ = fs::remove_file(out_dir.join("debian-binary"));
+        Ok(())
+    }
 
-        // get the data tarball name, could be data.tar.{gz, xz, bz2}
-        let extension = ["gz", "xz", "bz2"]
+    /// Returns the checksum of a file of the suite (e.g. `main/binary-amd64/Packages.xz`) as
+    /// listed in the release file, if listed. The strongest algorithm listed is preferred, MD5 is
+    /// only used for legacy archives not listing anything else.
+    pub fn release_hash(releasepath: &Path, file: &str) -> Result<Option<Checksum>, NebulaError> {
+        let checksums = Self::release_checksums(releasepath, file)?;
+        let preference = [
+            HashAlgorithm::Sha512,
+            HashAlgorithm::Sha256,
+            HashAlgorithm::Md5,
+        ];
+        Ok(preference
             .iter()
-            .find(|e| out_dir.join(format!("data.tar.{}", e)).exists())
-            .expect("Cannot find data tarball inside extracted deb");
-
-        crate::run_cmd(
-            "/usr/bin/tar",
-            &[
-                "-xf",
-                &format!("{}/data.tar.{}", out_dir.display(), extension),
-                "-C",
-                &format!("{}/data", out_dir.display()),
-            ],
-        )
+            .find_map(|alg| checksums.iter().find(|c| c.algorithm == *alg))
+            .cloned())
     }
 
-    /// Return's the hash of the Packages.xz archive. The hash is parsed from the InRelease file
-    pub fn package_file_hash(
-        releasepath: &Path,
-        component: &str,
-        arch: &str,
-    ) -> Result<String, io::Error> {
-        let packages_file = format!("{}/binary-{}/Packages.xz", component, arch);
-        let file = fs::File::open(releasepath)?;
-        let reader = BufReader::new(file);
-
-        let mut sha256 = false;
+    /// Returns every checksum of a file of the suite listed in the release file, one per
+    /// supported algorithm.
+    fn release_checksums(releasepath: &Path, file: &str) -> Result<Vec<Checksum>, NebulaError> {
+        let reader = BufReader::new(fs::File::open(releasepath).map_err(NebulaError::Io)?);
+        let mut algorithm = None;
+        let mut checksums = vec![];
         for line in reader.lines() {
-            let line = line?;
-            // find the SHA256 hashes, skip md5
-            if line.contains("SHA256:") {
-                sha256 = true;
+            let line = line.map_err(NebulaError::Io)?;
+            // checksum lists are indented, under the name of their algorithm (SHA1 is skipped)
+            if !line.starts_with(' ') {
+                let field = line.trim_end().trim_end_matches(':');
+                algorithm = [
+                    HashAlgorithm::Sha256,
+                    HashAlgorithm::Sha512,
+                    HashAlgorithm::Md5,
+                ]
+                .iter()
+                .find(|alg| Self::checksum_field(**alg) == field)
+                .copied();
+                continue;
             }
-
-            // find the line where the hash of the package appears
-            if sha256 && line.contains(&packages_file) {
-                return match line.split(' ').nth(1) {
-                    Some(h) => Ok(h.to_string()),
-                    None => Err(io::Error::new(
-                        io::ErrorKind::InvalidData,
-                        "Not able to parse the hash",
-                    )),
-                };
+            // <hash> <size> <path>, the path must match exactly
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if let Some(alg) = algorithm {
+                if fields.len() == 3 && fields[2] == file {
+                    checksums.push(Checksum::new(alg, fields[0]));
+                }
             }
         }
-        Err(io::Error::new(io::ErrorKind::Other, "hash not found"))
+        Ok(checksums)
     }
 
     fn parse_dependecies_str(deps_str: &str) -> Result<Vec<Vec<Dependency>>, NebulaError> {
@@ -375,3 +716,318 @@ impl<'d> Debian<'d> {
         Ok(dependencies_list)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Arch, Configuration};
+    use crate::hash::{Checksum, HashAlgorithm};
+    use crate::repos::debian::{Component, DebConfig, Debian};
+    use crate::testutil::temp_dir;
+    use crate::{file2hash, run_cmd, Repository};
+    use std::fs;
+    use std::path::Path;
+
+    const PACKAGES_V1: &str = "Package: hello\nVersion: 1.0\nArchitecture: amd64\n\
+                               Filename: pool/h/hello_1.0_amd64.deb\n\n";
+    const PACKAGES_V2: &str = "Package: hello\nVersion: 2.0\nArchitecture: amd64\n\
+                               Filename: pool/h/hello_2.0_amd64.deb\n\n";
+
+    /// Writes the package list of the archive and a release file listing it.
+    fn publish(dists: &Path, packages: &str, extra: &[&str]) {
+        let list = dists.join("main/binary-amd64");
+        fs::write(list.join("Packages"), packages).unwrap();
+        run_cmd(
+            "/usr/bin/xz",
+            &["-k", "-f", list.join("Packages").to_str().unwrap()],
+        )
+        .unwrap();
+        let mut release = "Suite: sid\nSHA256:\n".to_string();
+        // a list with a similar path must not be mistaken for the one we want
+        release.push_str(" 0000 1 main/debian-installer/binary-amd64/Packages.xz\n");
+        for file in ["Packages", "Packages.xz"].iter().chain(extra) {
+            let hash = file2hash(&list.join(file)).unwrap();
+            release.push_str(&format!(" {} 1 main/binary-amd64/{}\n", hash, file));
+        }
+        fs::write(dists.join("InRelease"), release).unwrap();
+    }
+
+    #[test]
+    fn incremental_update() {
+        let tmp = temp_dir("debian");
+        let dir = tmp.path();
+        let dists = dir.join("archive/dists/sid");
+        let list = dists.join("main/binary-amd64");
+        fs::create_dir_all(list.join("Packages.diff")).unwrap();
+        fs::create_dir_all(dir.join("nebula/repo")).unwrap();
+        publish(&dists, PACKAGES_V1, &[]);
+
+        let conf = DebConfig {
+            name: "sid".to_string(),
+            repository: dir.join("archive").to_str().unwrap().to_string(),
+            suite: "sid".to_string(),
+            components: vec![Component::Main],
+            priority: None,
+            network: None,
+        };
+        let config = Configuration::builder(dir.join("nebula"))
+            .arch(Arch::Amd64)
+            .debian_repo(conf.clone())
+            .build();
+        let debian = Debian::new(&config, &conf).unwrap();
+        debian.initialize().unwrap();
+        debian.update().unwrap();
+        let found = debian.search("hello", None).unwrap().unwrap();
+        assert_eq!(found[0].version, "1.0");
+
+        // publish version 2 as a delta only: the full list is not available
+        let v1_hash = file2hash(&list.join("Packages")).unwrap();
+        let patch = list.join("Packages.diff/2024-01-01-0000.00");
+        fs::write(
+            &patch,
+            "4c\nFilename: pool/h/hello_2.0_amd64.deb\n.\n2c\nVersion: 2.0\n.\n",
+        )
+        .unwrap();
+        let patch_hash = file2hash(&patch).unwrap();
+        run_cmd("/bin/gzip", &["-n", "-f", patch.to_str().unwrap()]).unwrap();
+        let gz_hash = file2hash(&patch.with_extension("00.gz")).unwrap();
+        publish(&dists, PACKAGES_V2, &[]);
+        fs::write(
+            list.join("Packages.diff/Index"),
+            format!(
+                "SHA256-Current: {} 1\nSHA256-History:\n {} 1 2024-01-01-0000.00\n\
+                 SHA256-Patches:\n {} 1 2024-01-01-0000.00\n\
+                 SHA256-Download:\n {} 1 2024-01-01-0000.00.gz\n",
+                file2hash(&list.join("Packages")).unwrap(),
+                v1_hash,
+                patch_hash,
+                gz_hash
+            ),
+        )
+        .unwrap();
+        publish(&dists, PACKAGES_V2, &["Packages.diff/Index"]);
+        fs::remove_file(list.join("Packages.xz")).unwrap();
+
+        debian.update().unwrap();
+        let found = debian.search("hello", None).unwrap().unwrap();
+        assert_eq!(found[0].version, "2.0");
+
+        // the release did not change: nothing to download
+        debian.update().unwrap();
+        assert_eq!(
+            debian.search("hello", None).unwrap().unwrap()[0].version,
+            "2.0"
+        );
+    }
+
+    #[test]
+    fn search_exact_name() {
+        let tmp = temp_dir("search");
+        let dir = tmp.path();
+        let dists = dir.join("archive/dists/sid");
+        fs::create_dir_all(dists.join("main/binary-amd64")).unwrap();
+        fs::create_dir_all(dir.join("nebula/repo")).unwrap();
+        let packages = format!(
+            "{}Package: hello-doc\nVersion: 1.1\nArchitecture: amd64\n\
+             Filename: pool/h/hello-doc_1.1_amd64.deb\n\n\
+             Package: libstdc++6\nVersion: 12.2\nArchitecture: amd64\n\
+             Filename: pool/g/libstdc++6_12.2_amd64.deb\n\n",
+            PACKAGES_V1
+        );
+        publish(&dists, &packages, &[]);
+        let conf = DebConfig {
+            name: "sid".to_string(),
+            repository: dir.join("archive").to_str().unwrap().to_string(),
+            suite: "sid".to_string(),
+            components: vec![Component::Main],
+            priority: None,
+            network: None,
+        };
+        let config = Configuration::builder(dir.join("nebula"))
+            .arch(Arch::Amd64)
+            .debian_repo(conf.clone())
+            .build();
+        let debian = Debian::new(&config, &conf).unwrap();
+        debian.initialize().unwrap();
+        debian.update().unwrap();
+
+        let found = debian.search("hello", None).unwrap().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].name, "hello");
+        let found = debian.search("libstdc++6", None).unwrap().unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].version, "12.2");
+        assert!(debian.search("libstdc", None).unwrap().is_none());
+    }
+
+    #[test]
+    fn release_checksums() {
+        let tmp = temp_dir("release");
+        let dir = tmp.path();
+        fs::create_dir_all(dir).unwrap();
+        let release = dir.join("Release");
+        fs::write(
+            &release,
+            "Suite: etch\nMD5Sum:\n 0123ABCD 1 main/binary-i386/Packages.gz\n \
+             4567 1 main/binary-amd64/Packages.gz\nSHA256:\n 89ab 1 main/binary-amd64/Packages.gz\n",
+        )
+        .unwrap();
+        // legacy archives only list MD5 sums, otherwise the strongest hash is used
+        assert_eq!(
+            Debian::release_hash(&release, "main/binary-i386/Packages.gz").unwrap(),
+            Some(Checksum::new(HashAlgorithm::Md5, "0123abcd"))
+        );
+        assert_eq!(
+            Debian::release_hash(&release, "main/binary-amd64/Packages.gz").unwrap(),
+            Some(Checksum::sha256("89ab"))
+        );
+        assert_eq!(Debian::release_hash(&release, "Packages.gz").unwrap(), None);
+
+        // binary-all lists are only fetched if the archive has them and does not also list
+        // arch:all packages in the lists of every architecture
+        let separate = |fields: &str| {
+            fs::write(&release, format!("Suite: sid\n{}SHA256:\n", fields)).unwrap();
+            Debian::separate_arch_all(&release).unwrap()
+        };
+        assert!(separate("Architectures: all amd64\n"));
+        assert!(!separate("Architectures: amd64 arm64\n"));
+        assert!(!separate(
+            "Architectures: all amd64\nNo-Support-for-Architecture-all: Packages\n"
+        ));
+    }
+
+    #[test]
+    fn missing_foreign_architecture() {
+        let tmp = temp_dir("foreign-arch");
+        let dir = tmp.path();
+        let dists = dir.join("archive/dists/sid");
+        fs::create_dir_all(dists.join("main/binary-amd64")).unwrap();
+        fs::create_dir_all(dir.join("nebula/repo")).unwrap();
+        publish(&dists, PACKAGES_V1, &[]);
+        let conf = DebConfig {
+            name: "sid".to_string(),
+            repository: dir.join("archive").to_str().unwrap().to_string(),
+            suite: "sid".to_string(),
+            components: vec![Component::Main],
+            priority: None,
+            network: None,
+        };
+
+        // the archive only carries amd64: i386 is skipped, whether the release lists its
+        // architectures or not
+        let config = Configuration::builder(dir.join("nebula"))
+            .arch(Arch::Amd64)
+            .foreign_arch(Arch::I386)
+            .debian_repo(conf.clone())
+            .build();
+        let debian = Debian::new(&config, &conf).unwrap();
+        debian.initialize().unwrap();
+        debian.update().unwrap();
+        let found = debian.search("hello", None).unwrap().unwrap();
+        assert_eq!(found[0].version, "1.0");
+        let release = fs::read_to_string(dists.join("InRelease")).unwrap();
+        fs::write(
+            dists.join("InRelease"),
+            format!("Architectures: amd64\n{}", release),
+        )
+        .unwrap();
+        debian.update().unwrap();
+        let found = debian.search("hello", None).unwrap().unwrap();
+        assert_eq!(found[0].version, "1.0");
+
+        // the lists of the native architecture are required
+        let config = Configuration::builder(dir.join("nebula"))
+            .arch(Arch::Arm64)
+            .foreign_arch(Arch::Amd64)
+            .debian_repo(conf.clone())
+            .build();
+        let debian = Debian::new(&config, &conf).unwrap();
+        match debian.update() {
+            Err(e) => assert!(e
+                .to_string()
+                .contains("main/binary-arm64/Packages.xz is not listed")),
+            Ok(_) => panic!("updated without the lists of the native architecture"),
+        }
+    }
+
+    #[test]
+    fn extract_deb_tarballs() {
+        let tmp = temp_dir("extract-deb");
+        let dir = tmp.path();
+        fs::create_dir_all(dir.join("stage/usr/bin")).unwrap();
+        fs::write(dir.join("stage/usr/bin/hello"), "hello").unwrap();
+        fs::create_dir_all(dir.join("control")).unwrap();
+        fs::write(dir.join("control/control"), "Package: hello\n").unwrap();
+        let path = |p: &Path| p.to_str().unwrap().to_string();
+
+        // older packages compress their payload with bzip2
+        for (data, flags) in &[("data.tar.xz", "-cJf"), ("data.tar.bz2", "-cjf")] {
+            let build = dir.join(format!("build-{}", data));
+            fs::create_dir_all(&build).unwrap();
+            fs::write(build.join("debian-binary"), "2.0\n").unwrap();
+            let control = path(&build.join("control.tar.gz"));
+            let data = path(&build.join(data));
+            let tar = |flags: &str, tarball: &str, from: &str| {
+                run_cmd("/usr/bin/tar", &[flags, tarball, "-C", from, "."]).unwrap()
+            };
+            tar("-czf", &control, &path(&dir.join("control")));
+            tar(flags, &data, &path(&dir.join("stage")));
+            let deb = path(&build.join("hello.deb"));
+            let debian_binary = path(&build.join("debian-binary"));
+            run_cmd(
+                "/usr/bin/ar",
+                &["rc", &deb, &debian_binary, &control, &data],
+            )
+            .unwrap();
+
+            let out = build.join("out");
+            Debian::extract_deb(Path::new(&deb), &out).unwrap();
+            assert_eq!(
+                fs::read_to_string(out.join("data/usr/bin/hello")).unwrap(),
+                "hello"
+            );
+            assert!(out.join("control/control").is_file());
+        }
+    }
+
+    #[test]
+    fn acquire_by_hash() {
+        let tmp = temp_dir("by-hash");
+        let dir = tmp.path();
+        let dists = dir.join("archive/dists/sid");
+        let list = dists.join("main/binary-amd64");
+        fs::create_dir_all(list.join("by-hash/SHA256")).unwrap();
+        fs::create_dir_all(dir.join("nebula/repo")).unwrap();
+        publish(&dists, PACKAGES_V1, &[]);
+        let release = fs::read_to_string(dists.join("InRelease")).unwrap();
+        fs::write(
+            dists.join("InRelease"),
+            format!("Acquire-By-Hash: yes\n{}", release),
+        )
+        .unwrap();
+        // the mirror is being updated: the plain path already holds a newer list
+        let hash = file2hash(&list.join("Packages.xz")).unwrap();
+        fs::rename(
+            list.join("Packages.xz"),
+            list.join("by-hash/SHA256").join(&hash),
+        )
+        .unwrap();
+        fs::write(list.join("Packages.xz"), "newer list").unwrap();
+
+        let conf = DebConfig {
+            name: "sid".to_string(),
+            repository: format!("file://{}", dir.join("archive").display()),
+            suite: "sid".to_string(),
+            components: vec![Component::Main],
+            priority: None,
+            network: None,
+        };
+        let config = Configuration::builder(dir.join("nebula"))
+            .debian_repo(conf.clone())
+            .build();
+        let debian = Debian::new(&config, &conf).unwrap();
+        debian.initialize().unwrap();
+        debian.update().unwrap();
+        let found = debian.search("hello", None).unwrap().unwrap();
+        assert_eq!(found[0].version, "1.0");
+    }
+}
@@ -1,14 +1,30 @@
 use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
 
 pub mod debian;
 pub mod nebula;
+pub mod pdiff;
 
 pub use debian::{DebConfig, Debian};
-pub use nebula::NebulaConfig;
+pub use nebula::{Nebula, NebulaConfig};
 
-use crate::{NebulaError, Package, CONFIG};
+use crate::config::Configuration;
+use crate::transport::Validators;
+use crate::{read_toml, write_toml, NebulaError, Package};
+
+/// File of a repository directory holding the validators (see `transport::Validators`) of the
+/// fetched release files, keyed by URL.
+pub const VALIDATORS_FILE: &str = "validators.toml";
 
 pub trait Repository {
+    fn repo_type(&self) -> RepoType;
+    /// Name of the repository, as used in pin rules
+    fn name(&self) -> &str;
+    /// Priority of the packages of the repository (see `policy`)
+    fn priority(&self) -> i32;
     fn initialize(&self) -> Result<(), NebulaError>;
     fn update(&self) -> Result<(), NebulaError>;
     fn search(
@@ -16,9 +32,48 @@ pub trait Repository {
         name: &str,
         version: Option<&str>,
     ) -> Result<Option<Vec<Package>>, NebulaError>;
+    /// Fetches the archive of a package of this repository into `dir`, verified as the
+    /// repository allows, and returns its path.
+    fn fetch(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError>;
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
+/// Boxed repositories are repositories too, so a list of repositories of different backends can
+/// be used wherever a list of repositories is expected.
+impl<R: Repository + ?Sized> Repository for Box<R> {
+    fn repo_type(&self) -> RepoType {
+        (**self).repo_type()
+    }
+
+    fn name(&self) -> &str {
+        (**self).name()
+    }
+
+    fn priority(&self) -> i32 {
+        (**self).priority()
+    }
+
+    fn initialize(&self) -> Result<(), NebulaError> {
+        (**self).initialize()
+    }
+
+    fn update(&self) -> Result<(), NebulaError> {
+        (**self).update()
+    }
+
+    fn search(
+        &self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<Option<Vec<Package>>, NebulaError> {
+        (**self).search(name, version)
+    }
+
+    fn fetch(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError> {
+        (**self).fetch(package, dir)
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Eq, Hash, Clone, Copy)]
 pub enum RepoType {
     #[serde(rename = "debian")]
     Debian,
@@ -26,13 +81,298 @@ pub enum RepoType {
     Nebula,
 }
 
-pub fn create_repos() -> Result<Vec<impl Repository>, NebulaError> {
-    let mut repos = vec![];
-    // TODO: Nebula repository init
-    // debian repo
-    if CONFIG.repos.debian.is_some() {
-        repos.push(Debian::new()?);
+/// A list of repositories of any backend, borrowing the configuration they were created from.
+pub type Repositories<'c> = Vec<Box<dyn Repository + 'c>>;
+
+/// Creates the repositories of a backend configured in the given configuration (none if the
+/// backend is not configured).
+pub type RepoConstructor = for<'c> fn(&'c Configuration) -> Result<Repositories<'c>, NebulaError>;
+
+/// The repository backends, keyed by their type. Repositories are created backend by backend, in
+/// the order the backends were registered.
+#[derive(Clone)]
+pub struct Registry {
+    backends: Vec<(RepoType, RepoConstructor)>,
+}
+
+impl Registry {
+    /// Creates a registry without any backend.
+    pub fn empty() -> Registry {
+        Registry { backends: vec![] }
+    }
+
+    /// Registers the constructor of a backend, replacing the one registered for the same type.
+    pub fn register(&mut self, repo_type: RepoType, constructor: RepoConstructor) {
+        match self.backends.iter_mut().find(|(t, _)| *t == repo_type) {
+            Some(backend) => backend.1 = constructor,
+            None => self.backends.push((repo_type, constructor)),
+        }
+    }
+
+    /// Returns the registered backend types.
+    pub fn types(&self) -> Vec<RepoType> {
+        self.backends.iter().map(|(t, _)| *t).collect()
+    }
+
+    /// Creates the repositories of every registered backend.
+    pub fn create<'c>(&self, config: &'c Configuration) -> Result<Repositories<'c>, NebulaError> {
+        let mut repos = vec![];
+        for (_, constructor) in &self.backends {
+            repos.append(&mut constructor(config)?);
+        }
+        Ok(repos)
+    }
+}
+
+impl Default for Registry {
+    /// A registry with the nebula and debian backends.
+    fn default() -> Registry {
+        let mut registry = Registry::empty();
+        registry.register(RepoType::Nebula, nebula_repos);
+        registry.register(RepoType::Debian, debian_repos);
+        registry
+    }
+}
+
+impl fmt::Debug for Registry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Registry")
+            .field("backends", &self.types())
+            .finish()
     }
+}
+
+fn nebula_repos(config: &Configuration) -> Result<Repositories<'_>, NebulaError> {
+    let mut repos: Repositories = vec![];
+    if config.repos.nebula.is_some() {
+        repos.push(Box::new(Nebula::new(config)?));
+    }
+    Ok(repos)
+}
 
+fn debian_repos(config: &Configuration) -> Result<Repositories<'_>, NebulaError> {
+    let mut repos: Repositories = vec![];
+    for conf in &config.repos.debian {
+        repos.push(Box::new(Debian::new(config, conf)?));
+    }
     Ok(repos)
 }
+
+/// Creates the repositories of the given configuration, with the default backends.
+pub fn create_repos(config: &Configuration) -> Result<Repositories<'_>, NebulaError> {
+    Registry::default().create(config)
+}
+
+/// Returns the staging directory of an update of `repo_dir` and the directory the previous index
+/// is moved to while the new one is swapped in: `.<name>.update` and `.<name>.old`, next to
+/// `repo_dir`.
+fn update_dirs(repo_dir: &Path) -> (PathBuf, PathBuf) {
+    let name = repo_dir.file_name().unwrap().to_string_lossy();
+    (
+        repo_dir.with_file_name(format!(".{}.update", name)),
+        repo_dir.with_file_name(format!(".{}.old", name)),
+    )
+}
+
+/// Updates the index stored in `repo_dir` without ever leaving it empty or half updated. `fetch`
+/// downloads and verifies the new index into a staging directory, which replaces `repo_dir` only
+/// if `fetch` succeeds and returns true. It returns false if the current index is up to date. On
+/// failure the staging directory is removed and the previous index is kept.
+pub fn staged_update<F>(repo_dir: &Path, fetch: F) -> Result<(), NebulaError>
+where
+    F: FnOnce(&Path) -> Result<bool, NebulaError>,
+{
+    let fs_err = |p: &Path, e: std::io::Error| NebulaError::Fs(format!("{}: {}", p.display(), e));
+    let (staging, old) = update_dirs(repo_dir);
+    recover_update(repo_dir)?;
+    fs::create_dir_all(&staging).map_err(|e| fs_err(&staging, e))?;
+
+    let result = fetch(&staging);
+    if !matches!(result, Ok(true)) {
+        if let Err(err) = fs::remove_dir_all(&staging) {
+            warn!("cannot remove {}: {}", staging.display(), err);
+        }
+        return result.map(|_| ());
+    }
+
+    // swap the directories, an interrupted swap is finished by `recover_update`
+    if repo_dir.exists() {
+        fs::rename(repo_dir, &old).map_err(|e| fs_err(repo_dir, e))?;
+    }
+    if let Err(e) = fs::rename(&staging, repo_dir) {
+        // put the previous index back
+        if old.exists() {
+            let _ = fs::rename(&old, repo_dir);
+        }
+        return Err(fs_err(repo_dir, e));
+    }
+    if old.exists() {
+        fs::remove_dir_all(&old).map_err(|e| fs_err(&old, e))?;
+    }
+    Ok(())
+}
+
+/// Cleans up after an interrupted `staged_update`: the previous index is restored if it was
+/// moved away but not replaced, and stale staging directories are removed.
+pub fn recover_update(repo_dir: &Path) -> Result<(), NebulaError> {
+    let fs_err = |p: &Path, e: std::io::Error| NebulaError::Fs(format!("{}: {}", p.display(), e));
+    let (staging, old) = update_dirs(repo_dir);
+    if old.exists() {
+        if repo_dir.exists() {
+            fs::remove_dir_all(&old).map_err(|e| fs_err(&old, e))?;
+        } else {
+            warn!("restoring the previous index of {}", repo_dir.display());
+            fs::rename(&old, repo_dir).map_err(|e| fs_err(&old, e))?;
+        }
+    }
+    if staging.exists() {
+        fs::remove_dir_all(&staging).map_err(|e| fs_err(&staging, e))?;
+    }
+    Ok(())
+}
+
+/// Reads the validators saved in a repository directory, none if there are none or they can't be
+/// read.
+pub fn load_validators(repo_dir: &Path) -> HashMap<String, Validators> {
+    let path = repo_dir.join(VALIDATORS_FILE);
+    if !path.is_file() {
+        return HashMap::new();
+    }
+    read_toml(&path).unwrap_or_else(|e| {
+        warn!("ignoring {}: {}", path.display(), e);
+        HashMap::new()
+    })
+}
+
+pub fn save_validators(
+    repo_dir: &Path,
+    validators: &HashMap<String, Validators>,
+) -> Result<(), NebulaError> {
+    write_toml(&repo_dir.join(VALIDATORS_FILE), validators)
+}
+
+/// Hard links `src` into `dest`, copying it if it can't be linked.
+pub fn link_or_copy(src: &Path, dest: &Path) -> Result<(), NebulaError> {
+    if fs::hard_link(src, dest).is_ok() {
+        return Ok(());
+    }
+    match fs::copy(src, dest) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(NebulaError::Fs(format!(
+            "cannot copy {} to {}: {}",
+            src.display(),
+            dest.display(),
+            e
+        ))),
+    }
+}
+
+/// Searches a package in all the given repositories, aggregating the results.
+pub fn search_all(
+    repos: &[impl Repository],
+    name: &str,
+    version: Option<&str>,
+) -> Result<Option<Vec<Package>>, NebulaError> {
+    let mut pkgs_list = vec![];
+    for repo in repos {
+        if let Some(mut pkgs) = repo.search(name, version)? {
+            pkgs_list.append(&mut pkgs);
+        }
+    }
+    if pkgs_list.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(pkgs_list))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::{Configuration, NetworkConfig};
+    use crate::repos::{
+        create_repos, search_all, DebConfig, NebulaConfig, Registry, RepoType, Repositories,
+        Repository,
+    };
+    use crate::{NebulaError, Package};
+    use std::path::{Path, PathBuf};
+
+    struct Fixed(Vec<Package>);
+
+    impl Repository for Fixed {
+        fn repo_type(&self) -> RepoType {
+            RepoType::Debian
+        }
+        fn name(&self) -> &str {
+            "fixed"
+        }
+        fn priority(&self) -> i32 {
+            500
+        }
+        fn initialize(&self) -> Result<(), NebulaError> {
+            Ok(())
+        }
+        fn update(&self) -> Result<(), NebulaError> {
+            Ok(())
+        }
+        fn search(
+            &self,
+            name: &str,
+            _version: Option<&str>,
+        ) -> Result<Option<Vec<Package>>, NebulaError> {
+            Ok(Some(
+                self.0.iter().filter(|p| p.name == name).cloned().collect(),
+            ))
+        }
+        fn fetch(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError> {
+            package.fetch(dir, &NetworkConfig::default(), None)
+        }
+    }
+
+    fn fixed_repos(_config: &Configuration) -> Result<Repositories<'_>, NebulaError> {
+        let fixed = Fixed(vec![Package::new("neofetch", "7.1.0")]);
+        Ok(vec![Box::new(fixed), Box::new(Fixed(vec![]))])
+    }
+
+    #[test]
+    fn mixed_backends() {
+        let debian = |name: &str| DebConfig {
+            name: name.to_string(),
+            repository: "http://ftp.debian.org/debian".to_string(),
+            suite: name.to_string(),
+            components: vec![],
+            priority: None,
+            network: None,
+        };
+        let config = Configuration::builder("/tmp/nebula")
+            .nebula_repo(NebulaConfig {
+                repository: "https://nebula.example.com/repo".to_string(),
+                trusted_keys: vec![],
+                priority: None,
+                network: None,
+            })
+            .debian_repo(debian("unstable"))
+            .debian_repo(debian("experimental"))
+            .build();
+
+        let repos = create_repos(&config).unwrap();
+        let names: Vec<(RepoType, &str)> =
+            repos.iter().map(|r| (r.repo_type(), r.name())).collect();
+        assert_eq!(
+            names,
+            vec![
+                (RepoType::Nebula, "nebula"),
+                (RepoType::Debian, "unstable"),
+                (RepoType::Debian, "experimental")
+            ]
+        );
+
+        // replace the debian backend
+        let mut registry = Registry::default();
+        registry.register(RepoType::Debian, fixed_repos);
+        assert_eq!(registry.types(), vec![RepoType::Nebula, RepoType::Debian]);
+        let repos = registry.create(&config).unwrap();
+        assert_eq!(repos.len(), 3);
+        let found = search_all(&repos[1..], "neofetch", None).unwrap().unwrap();
+        assert_eq!(found, vec![Package::new("neofetch", "7.1.0")]);
+    }
+}
@@ -1,6 +1,630 @@
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use walkdir::WalkDir;
 
-#[derive(Deserialize, Clone, Debug)]
+use crate::config::Configuration;
+use crate::config::NetworkConfig;
+use crate::hash::Checksum;
+use crate::nbpkg::{self, NBPKG_EXTENSION};
+use crate::policy::DEFAULT_PRIORITY;
+use crate::repos;
+use crate::sign::{self, sig_path};
+use crate::transport::{Fetched, Validators};
+use crate::{
+    download, download_if_modified, download_verified, file2hash, pkg, read_toml, write_toml,
+    NebulaError, Package, RepoType, Repository,
+};
+
+// ------------------------------------------------------------------ //
+//                          Configuration
+// ------------------------------------------------------------------ //
+#[derive(Deserialize, Serialize, Clone, Debug)]
 pub struct NebulaConfig {
     pub repository: String,
+    /// Hex encoded ed25519 public keys. If any is given, the repository metadata and its
+    /// packages must be signed by one of them.
+    #[serde(rename = "trusted-keys", default)]
+    pub trusted_keys: Vec<String>,
+    /// Priority of the repository's packages, defaults to `policy::DEFAULT_PRIORITY`
+    pub priority: Option<i32>,
+    pub network: Option<NetworkConfig>,
+}
+
+// ------------------------------------------------------------------ //
+//                          Repository files
+// ------------------------------------------------------------------ //
+
+/// Name of the release file at the root of a nebula repository.
+pub const RELEASE_FILE: &str = "release.toml";
+/// Name of the (compressed) index generated by `index_directory`.
+pub const INDEX_FILE: &str = "index.toml.xz";
+/// Cache used by `index_directory` to avoid reprocessing unchanged packages.
+const INDEX_CACHE_FILE: &str = ".index-cache.toml";
+
+/// The release file points to the repository's index and holds its Sha256 hash. Its date and
+/// expiry keep a mirror from serving an old (validly signed) release in place of the current one.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub struct Release {
+    /// Path of the index, relative to the repository root. If it ends with `.xz` the index is
+    /// compressed.
+    pub index: String,
+    pub hash: String,
+    /// Creation time, in seconds since the Unix epoch. Releases older than the one already
+    /// fetched are refused.
+    #[serde(default)]
+    pub date: u64,
+    /// Time after which the release is refused, in seconds since the Unix epoch
+    #[serde(
+        rename = "valid-until",
+        default,
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub valid_until: Option<u64>,
+}
+
+impl Release {
+    /// Checks that the release has not expired and is not older than `previous`, the release
+    /// fetched by the last update.
+    pub fn check(&self, previous: Option<&Release>) -> Result<(), NebulaError> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if let Some(valid_until) = self.valid_until {
+            if now > valid_until {
+                return Err(NebulaError::StaleRelease(format!(
+                    "the release expired {} seconds ago",
+                    now - valid_until
+                )));
+            }
+        }
+        if let Some(previous) = previous {
+            if self.date < previous.date {
+                return Err(NebulaError::StaleRelease(format!(
+                    "the release (dated {}) is older than the one already fetched (dated {})",
+                    self.date, previous.date
+                )));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The list of packages of a nebula repository.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Default)]
+pub struct Index {
+    pub packages: Vec<IndexEntry>,
+}
+
+/// A package of the index, the package's source is relative to the repository root.
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub struct IndexEntry {
+    /// Size of the package archive in bytes
+    pub size: u64,
+    /// Sha256 hash of the package archive
+    pub hash: String,
+    // tables must be serialized after plain values
+    pub package: Package,
+}
+
+/// Index cache entry, a package is only reprocessed if its size or modification time changed.
+#[derive(Deserialize, Serialize, Debug)]
+struct CachedEntry {
+    size: u64,
+    mtime: u64,
+    entry: IndexEntry,
+}
+
+#[derive(Deserialize, Serialize, Debug, Default)]
+struct IndexCache {
+    packages: HashMap<String, CachedEntry>,
+}
+
+// ------------------------------------------------------------------ //
+//                          Functionalities
+// ------------------------------------------------------------------ //
+
+pub struct Nebula<'n> {
+    conf: &'n NebulaConfig,
+    config: &'n Configuration,
+    repo_dir: PathBuf,
+}
+
+impl<'n> Repository for Nebula<'n> {
+    fn repo_type(&self) -> RepoType {
+        RepoType::Nebula
+    }
+
+    fn name(&self) -> &str {
+        "nebula"
+    }
+
+    fn priority(&self) -> i32 {
+        self.conf.priority.unwrap_or(DEFAULT_PRIORITY)
+    }
+
+    fn initialize(&self) -> Result<(), NebulaError> {
+        // finish an interrupted update before using the index
+        repos::recover_update(&self.repo_dir)?;
+        if !self.repo_dir.is_dir() {
+            if let Err(e) = fs::create_dir(&self.repo_dir) {
+                return Err(NebulaError::Fs(format!(
+                    "cannot create {}: {}",
+                    self.repo_dir.display(),
+                    e
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    fn update(&self) -> Result<(), NebulaError> {
+        println!("[*] updating nebula repository");
+        // the new index is downloaded next to the current one, which is kept if anything fails
+        repos::staged_update(&self.repo_dir, |dir| self.fetch_index(dir))
+    }
+
+    fn search(
+        &self,
+        name: &str,
+        version: Option<&str>,
+    ) -> Result<Option<Vec<Package>>, NebulaError> {
+        let index = self.index()?;
+        let pkgs_list: Vec<Package> = index
+            .packages
+            .into_iter()
+            .filter(|e| e.package.name.starts_with(name))
+            .filter(|e| version.is_none_or(|v| e.package.version == v))
+            .map(|e| {
+                // fetching verifies the archive against the hash of the index
+                let mut package = self.absolute_source(e.package);
+                package.sha256 = Some(e.hash);
+                package
+            })
+            .collect();
+
+        if pkgs_list.is_empty() {
+            Ok(None)
+        } else {
+            Ok(Some(pkgs_list))
+        }
+    }
+
+    fn fetch(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError> {
+        self.fetch_package(package, dir)
+    }
+}
+
+impl<'n> Nebula<'n> {
+    pub fn new(config: &'n Configuration) -> Result<Nebula<'n>, NebulaError> {
+        let conf = match &config.repos.nebula {
+            Some(c) => c,
+            None => return Err(NebulaError::RepoConfigNotFound),
+        };
+        let repo_dir = config.nebulahome.join("repo/nebula");
+        Ok(Nebula {
+            conf,
+            config,
+            repo_dir,
+        })
+    }
+
+    /// Reads the local copy of the repository's index.
+    pub fn index(&self) -> Result<Index, NebulaError> {
+        let path = self.repo_dir.join("index.toml");
+        if !path.is_file() {
+            return Err(NebulaError::Fs(format!(
+                "cannot read the index of repository nebula ({}), run nb-update",
+                path.display()
+            )));
+        }
+        read_toml(&path)
+    }
+
+    /// Downloads and verifies the release file and the index into `dir`. Returns false if the
+    /// release file did not change since the last update, nothing is downloaded then.
+    fn fetch_index(&self, dir: &Path) -> Result<bool, NebulaError> {
+        let net = self.config.network.merge(self.conf.network.as_ref());
+
+        info!("Downloading nebula release file...");
+        let release_url = format!("{}/{}", self.conf.repository, RELEASE_FILE);
+        let release_path = dir.join(RELEASE_FILE);
+        let mut validators = repos::load_validators(&self.repo_dir);
+        let current_path = self.repo_dir.join(RELEASE_FILE);
+        let current: Option<Release> = if current_path.is_file() {
+            Some(read_toml(&current_path)?)
+        } else {
+            None
+        };
+        // a conditional request only makes sense if the current index is complete
+        let previous = match validators.get(&release_url) {
+            Some(v) if self.repo_dir.join("index.toml").is_file() => v.clone(),
+            _ => Validators::default(),
+        };
+        match download_if_modified(&release_url, &release_path, &net, &previous)? {
+            Fetched::NotModified => {
+                // a mirror stuck on the current release must not go unnoticed either
+                if let Some(current) = &current {
+                    current.check(None)?;
+                }
+                info!("nebula repository is up to date");
+                return Ok(false);
+            }
+            Fetched::Modified(v) => validators.insert(release_url, v),
+        };
+        if !self.conf.trusted_keys.is_empty() {
+            info!("Verifying release file signature...");
+            let release_sig = sig_path(&release_path);
+            download(
+                &format!(
+                    "{}/{}",
+                    self.conf.repository,
+                    sig_path(Path::new(RELEASE_FILE)).display()
+                ),
+                &release_sig,
+                &net,
+            )?;
+            sign::verify_file(&release_path, &release_sig, &self.conf.trusted_keys)?;
+        }
+        let release: Release = read_toml(&release_path)?;
+        release.check(current.as_ref())?;
+
+        info!("Downloading nebula index {}...", release.index);
+        let compressed = release.index.ends_with(".xz");
+        let index_path = dir.join(if compressed {
+            "index.toml.xz"
+        } else {
+            "index.toml"
+        });
+        // the index is verified against the release file while it is downloaded
+        download_verified(
+            &format!("{}/{}", self.conf.repository, release.index),
+            &index_path,
+            &net,
+            &Checksum::sha256(&release.hash),
+        )?;
+
+        if compressed {
+            debug!("extracting {} with unxz", index_path.display());
+            crate::run_cmd("/usr/bin/unxz", &["--force", index_path.to_str().unwrap()])?;
+        }
+        repos::save_validators(dir, &validators)?;
+        Ok(true)
+    }
+
+    /// Fetches a package found by `search` into `dir`, checking its hash against the index and,
+    /// if the repository has trusted keys, its signature.
+    pub fn fetch_package(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError> {
+        let net = self.config.network.merge(self.conf.network.as_ref());
+        let checksum = match &package.sha256 {
+            Some(h) => Checksum::sha256(h),
+            None => {
+                return Err(NebulaError::Fs(format!(
+                    "{} {} is not in the index of repository nebula, run nb-update",
+                    package.name, package.version
+                )))
+            }
+        };
+        let pkg_path = package.fetch(dir, &net, Some(&checksum))?;
+
+        if !self.conf.trusted_keys.is_empty() {
+            let url = match &package.source {
+                Some(s) => s.url(),
+                None => {
+                    return Err(NebulaError::Fs(format!(
+                        "{} {} has no source to fetch its signature from",
+                        package.name, package.version
+                    )))
+                }
+            };
+            let pkg_sig = sig_path(&pkg_path);
+            download(&format!("{}.{}", url, sign::SIG_EXTENSION), &pkg_sig, &net)?;
+            sign::verify_file(&pkg_path, &pkg_sig, &self.conf.trusted_keys)?;
+        }
+        Ok(pkg_path)
+    }
+
+    /// Package sources in the index are relative to the repository root, make them absolute.
+    fn absolute_source(&self, mut package: Package) -> Package {
+        if let Some(src) = &package.source {
+            let url = format!("{}/{}", self.conf.repository, src.url());
+            package.source = Some(pkg::PkgSource::from(RepoType::Nebula, &url));
+        }
+        package
+    }
+}
+
+/// Generates a nebula repository from a directory of packages. Every `.nbpkg` file under `dir`
+/// is added to the index with its source relative to `dir`, size and hash. The compressed index
+/// and the release file (holding the index hash) are written into `dir`. The release expires
+/// after `valid_for` seconds, if given.
+///
+/// Indexing is incremental: packages whose size and modification time did not change since the
+/// last run are not reprocessed.
+pub fn index_directory(dir: &Path, valid_for: Option<u64>) -> Result<Release, NebulaError> {
+    let cache_path = dir.join(INDEX_CACHE_FILE);
+    let old_cache: IndexCache = if cache_path.is_file() {
+        read_toml(&cache_path).unwrap_or_default()
+    } else {
+        IndexCache::default()
+    };
+    let mut cache = IndexCache::default();
+
+    for entry in WalkDir::new(dir).sort_by(|a, b| a.file_name().cmp(b.file_name())) {
+        let entry = match entry {
+            Ok(e) => e,
+            Err(e) => return Err(NebulaError::Fs(e.to_string())),
+        };
+        let path = entry.path();
+        if !entry.file_type().is_file()
+            || path.extension().and_then(|e| e.to_str()) != Some(NBPKG_EXTENSION)
+        {
+            continue;
+        }
+        let rel_path = path
+            .strip_prefix(dir)
+            .unwrap()
+            .to_string_lossy()
+            .to_string();
+        let metadata = match entry.metadata() {
+            Ok(m) => m,
+            Err(e) => return Err(NebulaError::Fs(e.to_string())),
+        };
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .map_or(0, |d| d.as_secs());
+
+        // reuse the cached entry if the package did not change
+        if let Some(cached) = old_cache.packages.get(&rel_path) {
+            if cached.size == size && cached.mtime == mtime {
+                debug!("{} unchanged, skipping", rel_path);
+                let cached = CachedEntry {
+                    size,
+                    mtime,
+                    entry: cached.entry.clone(),
+                };
+                cache.packages.insert(rel_path, cached);
+                continue;
+            }
+        }
+
+        println!("[*] indexing {}", rel_path);
+        let manifest = nbpkg::read_manifest(path, &dir.join(".index-tmp"))?;
+        let mut package = manifest.package;
+        package.source = Some(pkg::PkgSource::from(RepoType::Nebula, &rel_path));
+        let index_entry = IndexEntry {
+            size,
+            hash: file2hash(path)?,
+            package,
+        };
+        cache.packages.insert(
+            rel_path,
+            CachedEntry {
+                size,
+                mtime,
+                entry: index_entry,
+            },
+        );
+    }
+
+    let mut paths: Vec<&String> = cache.packages.keys().collect();
+    paths.sort();
+    let index = Index {
+        packages: paths
+            .iter()
+            .map(|p| cache.packages[*p].entry.clone())
+            .collect(),
+    };
+
+    // write the index and compress it
+    let index_path = dir.join("index.toml");
+    write_toml(&index_path, &index)?;
+    crate::run_cmd(
+        "/usr/bin/xz",
+        &["--force", "--keep", index_path.to_str().unwrap()],
+    )?;
+    let _ = fs::remove_file(&index_path);
+
+    let date = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    let release = Release {
+        index: INDEX_FILE.to_string(),
+        hash: file2hash(&dir.join(INDEX_FILE))?,
+        date,
+        valid_until: valid_for.map(|v| date + v),
+    };
+    write_toml(&dir.join(RELEASE_FILE), &release)?;
+    write_toml(&cache_path, &cache)?;
+    Ok(release)
+}
+
+/// Signs the release file of a repository generated by `index_directory` and every package of
+/// its index. Packages that already have a signature newer than the package are skipped.
+pub fn sign_repository(dir: &Path, keypair: &ed25519_dalek::Keypair) -> Result<(), NebulaError> {
+    let index_path = dir.join("index.toml");
+    crate::run_cmd(
+        "/usr/bin/xz",
+        &[
+            "--decompress",
+            "--force",
+            "--keep",
+            dir.join(INDEX_FILE).to_str().unwrap(),
+        ],
+    )?;
+    let index: Result<Index, NebulaError> = read_toml(&index_path);
+    let _ = fs::remove_file(&index_path);
+
+    for entry in index?.packages {
+        let pkg_path = dir.join(entry.package.source.as_ref().unwrap().url());
+        let sig = sig_path(&pkg_path);
+        let modified = |p: &Path| fs::metadata(p).and_then(|m| m.modified()).ok();
+        if sig.is_file() && modified(&sig) >= modified(&pkg_path) {
+            continue;
+        }
+        debug!("signing {}", pkg_path.display());
+        sign::sign_file(&pkg_path, keypair)?;
+    }
+    sign::sign_file(&dir.join(RELEASE_FILE), keypair)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::pkg::{Dependency, PkgSource};
+    use crate::repos::nebula::{
+        index_directory, Index, IndexEntry, Nebula, NebulaConfig, Release, INDEX_FILE, RELEASE_FILE,
+    };
+    use crate::testutil::temp_dir;
+    use crate::{
+        file2hash, nbpkg, read_toml, write_toml, NebulaError, Package, RepoType, Repository,
+    };
+    use std::fs;
+
+    #[test]
+    fn index_serialization_deserialization() {
+        let mut package = Package::new("proba", "1.2.3");
+        package.source = Some(PkgSource::from(RepoType::Nebula, "pkgs/proba-1.2.3.nbpkg"));
+        package.depends = Some(vec![vec![Dependency::from("dep1", Some(">=3.1"))]]);
+        let index = Index {
+            packages: vec![IndexEntry {
+                size: 1024,
+                hash: "abcd".to_string(),
+                package,
+            }],
+        };
+
+        let index_str = toml::to_string(&index).unwrap();
+        let index_de: Index = toml::from_str(&index_str).unwrap();
+        assert_eq!(index_de, index);
+    }
+
+    #[test]
+    fn index_package_directory() {
+        let tmp = temp_dir("index");
+        let dir = tmp.path();
+        let staged = dir.join("stage");
+        let repo = dir.join("repo");
+        fs::create_dir_all(staged.join("usr/bin")).unwrap();
+        fs::create_dir_all(repo.join("pkgs")).unwrap();
+        fs::write(staged.join("usr/bin/proba"), "#!/bin/sh\n").unwrap();
+        nbpkg::build(&staged, Package::new("proba", "1.0"), &repo.join("pkgs")).unwrap();
+
+        let release = index_directory(&repo, Some(3600)).unwrap();
+        assert_eq!(release.hash, file2hash(&repo.join(INDEX_FILE)).unwrap());
+        assert_eq!(release.valid_until, Some(release.date + 3600));
+
+        // indexing again must produce the same index
+        let release2 = index_directory(&repo, None).unwrap();
+        assert_eq!(release.hash, release2.hash);
+        assert_eq!(release2.valid_until, None);
+    }
+
+    #[test]
+    fn failed_update_keeps_index() {
+        let tmp = temp_dir("update");
+        let dir = tmp.path();
+        let staged = dir.join("stage");
+        let repo = dir.join("repo");
+        fs::create_dir_all(staged.join("usr/bin")).unwrap();
+        fs::create_dir_all(repo.join("pkgs")).unwrap();
+        fs::create_dir_all(dir.join("nebula/repo")).unwrap();
+        fs::write(staged.join("usr/bin/proba"), "#!/bin/sh\n").unwrap();
+        nbpkg::build(&staged, Package::new("proba", "1.0"), &repo.join("pkgs")).unwrap();
+        index_directory(&repo, None).unwrap();
+
+        let config = Configuration::builder(dir.join("nebula"))
+            .nebula_repo(NebulaConfig {
+                repository: repo.to_str().unwrap().to_string(),
+                trusted_keys: vec![],
+                priority: None,
+                network: None,
+            })
+            .build();
+        let nebula = Nebula::new(&config).unwrap();
+        nebula.initialize().unwrap();
+        assert!(nebula.search("proba", None).is_err());
+        nebula.update().unwrap();
+        assert_eq!(nebula.search("proba", None).unwrap().unwrap().len(), 1);
+
+        // nothing changed, the current index is kept as is
+        nebula.update().unwrap();
+        assert_eq!(nebula.search("proba", None).unwrap().unwrap().len(), 1);
+
+        // a corrupted index must not replace the current one
+        fs::write(repo.join(INDEX_FILE), "corrupted").unwrap();
+        let release = fs::read(repo.join(RELEASE_FILE)).unwrap();
+        fs::write(repo.join(RELEASE_FILE), release).unwrap();
+        match nebula.update() {
+            Err(NebulaError::IncorrectHash) => (),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert_eq!(nebula.search("proba", None).unwrap().unwrap().len(), 1);
+        let entries: Vec<_> = fs::read_dir(dir.join("nebula/repo")).unwrap().collect();
+        assert_eq!(entries.len(), 1);
+
+        // an older release, or an expired one, is refused: a mirror could replay it forever
+        let mut release: Release = read_toml(&repo.join(RELEASE_FILE)).unwrap();
+        index_directory(&repo, None).unwrap();
+        release.hash = file2hash(&repo.join(INDEX_FILE)).unwrap();
+        release.date -= 1;
+        write_toml(&repo.join(RELEASE_FILE), &release).unwrap();
+        assert!(matches!(nebula.update(), Err(NebulaError::StaleRelease(_))));
+        release.date += 1;
+        release.valid_until = Some(release.date - 1);
+        write_toml(&repo.join(RELEASE_FILE), &release).unwrap();
+        assert!(matches!(nebula.update(), Err(NebulaError::StaleRelease(_))));
+        release.valid_until = Some(release.date + 3600);
+        write_toml(&repo.join(RELEASE_FILE), &release).unwrap();
+        nebula.update().unwrap();
+    }
+
+    #[test]
+    fn fetch_checks_index_hash() {
+        let tmp = temp_dir("fetch");
+        let dir = tmp.path();
+        let staged = dir.join("stage");
+        let repo = dir.join("repo");
+        fs::create_dir_all(staged.join("usr/bin")).unwrap();
+        fs::create_dir_all(repo.join("pkgs")).unwrap();
+        fs::create_dir_all(dir.join("nebula/repo")).unwrap();
+        fs::create_dir_all(dir.join("downloads")).unwrap();
+        fs::write(staged.join("usr/bin/proba"), "#!/bin/sh\n").unwrap();
+        let archive =
+            nbpkg::build(&staged, Package::new("proba", "1.0"), &repo.join("pkgs")).unwrap();
+        index_directory(&repo, None).unwrap();
+
+        let config = Configuration::builder(dir.join("nebula"))
+            .nebula_repo(NebulaConfig {
+                repository: repo.to_str().unwrap().to_string(),
+                trusted_keys: vec![],
+                priority: None,
+                network: None,
+            })
+            .build();
+        let nebula = Nebula::new(&config).unwrap();
+        nebula.initialize().unwrap();
+        nebula.update().unwrap();
+        let found = nebula.search("proba", None).unwrap().unwrap();
+        assert_eq!(found[0].sha256, Some(file2hash(&archive).unwrap()));
+        nebula.fetch(&found[0], &dir.join("downloads")).unwrap();
+
+        // the archive was replaced after the index was generated
+        fs::write(&archive, "tampered").unwrap();
+        assert!(matches!(
+            nebula.fetch(&found[0], &dir.join("downloads")),
+            Err(NebulaError::IncorrectHash)
+        ));
+
+        // a package the index does not list has no hash to check against
+        let mut unknown = found[0].clone();
+        unknown.sha256 = None;
+        assert!(nebula.fetch(&unknown, &dir.join("downloads")).is_err());
+    }
 }
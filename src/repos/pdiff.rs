@@ -0,0 +1,232 @@
+use regex::Regex;
+use std::fs;
+use std::path::Path;
+
+use crate::NebulaError;
+
+/// Parsed `Packages.diff/Index` file. Debian archives publish the changes of their package
+/// indices as ed scripts, so an index that is not too old can be brought up to date applying a
+/// few small patches instead of downloading it again.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct PdiffIndex {
+    /// SHA256 hash of the current index
+    pub current: String,
+    /// (hash of a previous version of the index, name of the patch to apply to it), oldest first
+    pub history: Vec<(String, String)>,
+    /// (patch name, SHA256 hash of the uncompressed patch)
+    pub patches: Vec<(String, String)>,
+    /// (patch name, SHA256 hash of the compressed patch, `<name>.gz`)
+    pub downloads: Vec<(String, String)>,
+    /// Whether each patch goes straight to the current index (`X-Patch-Precedence: merged`)
+    /// instead of to the next version in the history
+    pub merged: bool,
+}
+
+impl PdiffIndex {
+    pub fn parse(content: &str) -> Result<PdiffIndex, NebulaError> {
+        let mut index = PdiffIndex::default();
+        let mut section = "";
+        for line in content.lines() {
+            if !line.starts_with(' ') {
+                let (field, value) = match line.split_once(':') {
+                    Some((f, v)) => (f.trim(), v.trim()),
+                    None => continue,
+                };
+                section = match field {
+                    "SHA256-Current" => {
+                        index.current = value.split_whitespace().next().unwrap_or("").to_string();
+                        ""
+                    }
+                    "X-Patch-Precedence" => {
+                        index.merged = value == "merged";
+                        ""
+                    }
+                    "SHA256-History" | "SHA256-Patches" | "SHA256-Download" => field,
+                    _ => "",
+                };
+                continue;
+            }
+            // <hash> <size> <name>
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            if fields.len() != 3 {
+                return Err(NebulaError::Pdiff(format!(
+                    "malformed index line '{}'",
+                    line
+                )));
+            }
+            let entry = (fields[0].to_string(), fields[2].to_string());
+            match section {
+                "SHA256-History" => index.history.push(entry),
+                "SHA256-Patches" => index.patches.push((entry.1, entry.0)),
+                "SHA256-Download" => {
+                    let name = entry.1.strip_suffix(".gz").unwrap_or(&entry.1).to_string();
+                    index.downloads.push((name, entry.0))
+                }
+                _ => (),
+            }
+        }
+        if index.current.is_empty() {
+            return Err(NebulaError::Pdiff(
+                "index without SHA256-Current".to_string(),
+            ));
+        }
+        Ok(index)
+    }
+
+    /// Returns the patches to apply, in order, to the version of the index with the given hash.
+    /// `None` if that version is not in the history (too old, or unknown).
+    pub fn patches_for(&self, hash: &str) -> Option<Vec<&str>> {
+        if hash == self.current {
+            return Some(vec![]);
+        }
+        let start = self.history.iter().position(|(h, _)| h == hash)?;
+        let names = self.history[start..].iter().map(|(_, n)| n.as_str());
+        if self.merged {
+            Some(names.take(1).collect())
+        } else {
+            Some(names.collect())
+        }
+    }
+
+    /// Expected hash of the given (uncompressed) patch.
+    pub fn patch_hash(&self, name: &str) -> Option<&str> {
+        lookup(&self.patches, name)
+    }
+
+    /// Expected hash of the given patch, compressed.
+    pub fn download_hash(&self, name: &str) -> Option<&str> {
+        lookup(&self.downloads, name)
+    }
+}
+
+fn lookup<'a>(entries: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    entries
+        .iter()
+        .find(|(n, _)| n == name)
+        .map(|(_, h)| h.as_str())
+}
+
+/// Applies an ed script, as generated by `diff --ed`, to the given lines. Only the append
+/// (`a`), change (`c`) and delete (`d`) commands are supported.
+pub fn apply_ed(lines: &mut Vec<Vec<u8>>, script: &[u8]) -> Result<(), NebulaError> {
+    let re = Regex::new(r"^(\d+)(?:,(\d+))?([acd])$").unwrap();
+    let bad = |msg: String| NebulaError::Pdiff(msg);
+    let mut script_lines = script.split(|b| *b == b'\n');
+    while let Some(cmd) = script_lines.next() {
+        if cmd.is_empty() {
+            continue;
+        }
+        let cmd = String::from_utf8_lossy(cmd);
+        let cap = match re.captures(&cmd) {
+            Some(c) => c,
+            None => return Err(bad(format!("unsupported ed command '{}'", cmd))),
+        };
+        let line_number = |s: &str| {
+            s.parse::<usize>()
+                .map_err(|e| bad(format!("bad line number in '{}': {}", cmd, e)))
+        };
+        let first = line_number(&cap[1])?;
+        let last = match cap.get(2) {
+            Some(l) => line_number(l.as_str())?,
+            None => first,
+        };
+        let op = &cap[3];
+
+        // the text of appends and changes ends with a line holding a single dot
+        let mut text = vec![];
+        if op != "d" {
+            loop {
+                match script_lines.next() {
+                    Some(b".") => break,
+                    Some(l) => text.push(l.to_vec()),
+                    None => return Err(bad(format!("unterminated text for '{}'", cmd))),
+                }
+            }
+        }
+
+        let out_of_range = || bad(format!("'{}' is out of range ({} lines)", cmd, lines.len()));
+        match op {
+            "a" => {
+                if first > lines.len() {
+                    return Err(out_of_range());
+                }
+                lines.splice(first..first, text);
+            }
+            _ => {
+                if first == 0 || first > last || last > lines.len() {
+                    return Err(out_of_range());
+                }
+                lines.splice(first - 1..last, text);
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Applies the given ed scripts, in order, to the file at `src` and writes the result into
+/// `dest`.
+pub fn patch_file(src: &Path, patches: &[&Path], dest: &Path) -> Result<(), NebulaError> {
+    let content = fs::read(src).map_err(NebulaError::Io)?;
+    let mut lines: Vec<Vec<u8>> = content.split(|b| *b == b'\n').map(|l| l.to_vec()).collect();
+    // a trailing newline does not start a new line
+    if lines.last().is_some_and(|l| l.is_empty()) {
+        lines.pop();
+    }
+    for patch in patches {
+        let script = fs::read(patch).map_err(NebulaError::Io)?;
+        apply_ed(&mut lines, &script)?;
+    }
+    let mut out = Vec::with_capacity(content.len());
+    for line in lines {
+        out.extend_from_slice(&line);
+        out.push(b'\n');
+    }
+    fs::write(dest, out).map_err(NebulaError::Io)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::repos::pdiff::{apply_ed, PdiffIndex};
+
+    #[test]
+    fn pdiff_index_and_ed_scripts() {
+        let index = PdiffIndex::parse(
+            "SHA256-Current: cccc 300
+SHA256-History:
+ aaaa 100 2023-01-01-0000.00
+ bbbb 200 2023-01-02-0000.00
+SHA256-Patches:
+ 1111 10 2023-01-01-0000.00
+ 2222 20 2023-01-02-0000.00
+SHA256-Download:
+ 3333 8 2023-01-01-0000.00.gz
+ 4444 9 2023-01-02-0000.00.gz
+",
+        )
+        .unwrap();
+        assert_eq!(index.current, "cccc");
+        assert_eq!(
+            index.patches_for("aaaa").unwrap(),
+            vec!["2023-01-01-0000.00", "2023-01-02-0000.00"]
+        );
+        assert_eq!(index.patches_for("cccc").unwrap().len(), 0);
+        assert!(index.patches_for("dddd").is_none());
+        assert_eq!(index.download_hash("2023-01-02-0000.00"), Some("4444"));
+        assert_eq!(index.patch_hash("2023-01-01-0000.00"), Some("1111"));
+
+        let mut lines: Vec<Vec<u8>> = ["a", "b", "c", "d", "e"]
+            .iter()
+            .map(|l| l.as_bytes().to_vec())
+            .collect();
+        // diff --ed lists the commands from the end of the file to its start
+        apply_ed(&mut lines, b"5a\nf\n.\n3,4c\nC\n.\n1d\n").unwrap();
+        let lines: Vec<&[u8]> = lines.iter().map(|l| l.as_slice()).collect();
+        assert_eq!(lines, vec![&b"b"[..], b"C", b"e", b"f"]);
+
+        let mut lines = vec![b"a".to_vec()];
+        assert!(apply_ed(&mut lines, b"3d\n").is_err());
+        assert!(apply_ed(&mut lines, b"1a\nunterminated\n").is_err());
+        // overflowing line numbers fail the patch, so the index is downloaded in full
+        assert!(apply_ed(&mut lines, b"99999999999999999999999d\n").is_err());
+    }
+}
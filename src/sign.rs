@@ -0,0 +1,146 @@
+use ed25519_dalek::{Keypair, PublicKey, SecretKey, Signature, Signer, Verifier};
+use std::convert::TryFrom;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::NebulaError;
+
+/// Extension of detached signature files, the signature of `foo` is stored in `foo.sig`.
+pub const SIG_EXTENSION: &str = "sig";
+
+/// Returns the path of the detached signature of the given file.
+pub fn sig_path(path: &Path) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(SIG_EXTENSION);
+    PathBuf::from(name)
+}
+
+/// Generates a new ed25519 key. The secret key is written hex encoded into `secret_path`, which
+/// must not exist and is only readable by its owner, and the hex encoded public key is returned,
+/// to be added to the `trusted-keys` of the repository.
+pub fn generate_key(secret_path: &Path) -> Result<String, NebulaError> {
+    let mut seed = [0u8; 32];
+    if let Err(e) = File::open("/dev/urandom").and_then(|mut f| f.read_exact(&mut seed)) {
+        return Err(NebulaError::Io(e));
+    }
+    let secret = SecretKey::from_bytes(&seed).unwrap();
+    let public = PublicKey::from(&secret);
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(secret_path)
+        .and_then(|mut f| f.write_all(to_hex(secret.as_bytes()).as_bytes()));
+    if let Err(e) = written {
+        return Err(NebulaError::Fs(format!(
+            "cannot write {}: {}",
+            secret_path.display(),
+            e
+        )));
+    }
+    Ok(to_hex(public.as_bytes()))
+}
+
+/// Reads a hex encoded secret key and returns its keypair.
+pub fn read_keypair(secret_path: &Path) -> Result<Keypair, NebulaError> {
+    let content = match fs::read_to_string(secret_path) {
+        Ok(c) => c,
+        Err(e) => return Err(NebulaError::Io(e)),
+    };
+    let bad_key = || NebulaError::BadSignature(format!("{}: invalid key", secret_path.display()));
+    let bytes = from_hex(content.trim()).ok_or_else(bad_key)?;
+    let secret = SecretKey::from_bytes(&bytes).map_err(|_| bad_key())?;
+    let public = PublicKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+/// Signs the given file, writing the hex encoded signature into `<file>.sig`.
+pub fn sign_file(path: &Path, keypair: &Keypair) -> Result<PathBuf, NebulaError> {
+    let content = match fs::read(path) {
+        Ok(c) => c,
+        Err(e) => return Err(NebulaError::Io(e)),
+    };
+    let signature = keypair.sign(&content);
+    let sig = sig_path(path);
+    if let Err(e) = fs::write(&sig, to_hex(&signature.to_bytes())) {
+        return Err(NebulaError::Io(e));
+    }
+    Ok(sig)
+}
+
+/// Verifies a file against its detached signature. The file is accepted if it's signed by any of
+/// the trusted (hex encoded) public keys.
+pub fn verify_file(path: &Path, sig: &Path, trusted_keys: &[String]) -> Result<(), NebulaError> {
+    let bad_sig = |msg: &str| NebulaError::BadSignature(format!("{}: {}", path.display(), msg));
+
+    let content = fs::read(path).map_err(NebulaError::Io)?;
+    let sig_str = fs::read_to_string(sig).map_err(|_| bad_sig("signature not found"))?;
+    let sig_bytes = from_hex(sig_str.trim()).ok_or_else(|| bad_sig("malformed signature"))?;
+    let signature =
+        Signature::try_from(&sig_bytes[..]).map_err(|_| bad_sig("malformed signature"))?;
+
+    for key in trusted_keys {
+        let public = match from_hex(key).and_then(|k| PublicKey::from_bytes(&k).ok()) {
+            Some(p) => p,
+            None => {
+                warn!("ignoring malformed trusted key {}", key);
+                continue;
+            }
+        };
+        if public.verify(&content, &signature).is_ok() {
+            return Ok(());
+        }
+    }
+    Err(bad_sig("not signed by any trusted key"))
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| s.get(i..i + 2).and_then(|b| u8::from_str_radix(b, 16).ok()))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::sign::{generate_key, read_keypair, sig_path, sign_file, verify_file};
+    use crate::testutil::temp_dir;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn sign_and_verify() {
+        let tmp = temp_dir("sign");
+        let dir = tmp.path();
+        fs::create_dir_all(dir).unwrap();
+        let public = generate_key(&dir.join("key")).unwrap();
+        let other = generate_key(&dir.join("other-key")).unwrap();
+        // secret keys are private and never overwritten
+        let mode = fs::metadata(dir.join("key")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        assert!(generate_key(&dir.join("key")).is_err());
+        let keypair = read_keypair(&dir.join("key")).unwrap();
+
+        let file = dir.join("release.toml");
+        fs::write(&file, "index = 'index.toml.xz'\n").unwrap();
+        let sig = sign_file(&file, &keypair).unwrap();
+        assert_eq!(sig, sig_path(&file));
+
+        assert!(verify_file(&file, &sig, &[other.clone(), public.clone()]).is_ok());
+        assert!(verify_file(&file, &sig, &[other]).is_err());
+
+        // tampered content
+        fs::write(&file, "index = 'evil.toml.xz'\n").unwrap();
+        assert!(verify_file(&file, &sig, &[public]).is_err());
+    }
+}
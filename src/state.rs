@@ -0,0 +1,167 @@
+use serde_derive::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+use crate::config::Configuration;
+use crate::{read_toml, write_toml_atomic, NebulaError, Unfold};
+
+/// Name of the installed-state file, inside nebula's home.
+pub const STATE_FILE: &str = "installed.toml";
+
+/// The installed-state data: which packages are installed and which of them are held.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct InstalledState {
+    /// Generation this state was recorded as (see `generation`), none if not recorded yet
+    pub generation: Option<u32>,
+    /// Names of the held packages, their installed version must not change
+    #[serde(default)]
+    pub held: Vec<String>,
+    #[serde(default)]
+    pub packages: Vec<InstalledPackage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    /// Name of the repository the package was installed from
+    pub repository: Option<String>,
+    /// Name of the package's tree in the package store (see `store::Store`)
+    pub store: Option<String>,
+    /// Links of the package in the destination directory, relative to it
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub links: Vec<String>,
+}
+
+impl InstalledState {
+    pub fn path(config: &Configuration) -> PathBuf {
+        config.nebulahome.join(STATE_FILE)
+    }
+
+    /// Loads the installed-state data, if nothing was installed yet an empty state is returned.
+    pub fn load(config: &Configuration) -> Result<InstalledState, NebulaError> {
+        let path = Self::path(config);
+        if path.is_file() {
+            read_toml(&path)
+        } else {
+            Ok(InstalledState::default())
+        }
+    }
+
+    /// Saves the installed-state data, an interrupted save never leaves a truncated state file.
+    pub fn save(&self, config: &Configuration) -> Result<(), NebulaError> {
+        write_toml_atomic(&Self::path(config), self)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&InstalledPackage> {
+        self.packages.iter().find(|p| p.name == name)
+    }
+
+    pub fn is_held(&self, name: &str) -> bool {
+        self.held.iter().any(|h| h == name)
+    }
+
+    /// Holds an installed package at its current version. Returns false if it was already held.
+    pub fn hold(&mut self, name: &str) -> Result<bool, NebulaError> {
+        if self.get(name).is_none() {
+            return Err(NebulaError::PackageNotInstalled(name.to_string()));
+        }
+        if self.is_held(name) {
+            return Ok(false);
+        }
+        self.held.push(name.to_string());
+        self.held.sort();
+        Ok(true)
+    }
+
+    /// Releases the hold of a package. Returns false if the package was not held.
+    pub fn unhold(&mut self, name: &str) -> bool {
+        let len = self.held.len();
+        self.held.retain(|h| h != name);
+        self.held.len() != len
+    }
+
+    /// Fails if the package is held and its installed version would change to `version`.
+    pub fn check_change(&self, name: &str, version: &str) -> Result<(), NebulaError> {
+        if let Some(old) = self.get(name) {
+            if self.is_held(name) && old.version != version {
+                return Err(NebulaError::PackageHeld(format!(
+                    "{} is held at version {}, cannot change it to {}",
+                    old.name, old.version, version
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Records a package as installed, replacing the previously installed version. Fails if the
+    /// package is held and the version would change.
+    pub fn record_install(&mut self, package: InstalledPackage) -> Result<(), NebulaError> {
+        self.check_change(&package.name, &package.version)?;
+        self.packages.retain(|p| p.name != package.name);
+        self.packages.push(package);
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(())
+    }
+
+    /// Removes a package from the installed packages. Returns false if it was not installed.
+    pub fn record_remove(&mut self, name: &str) -> bool {
+        let len = self.packages.len();
+        self.packages.retain(|p| p.name != name);
+        self.packages.len() != len
+    }
+
+    /// Whether both states have the same packages installed, from the same trees of the store,
+    /// and the same holds. Links are not compared.
+    pub fn same_packages(&self, other: &InstalledState) -> bool {
+        let key = |p: &InstalledPackage| (p.name.clone(), p.version.clone(), p.store.clone());
+        let keys = |s: &InstalledState| s.packages.iter().map(key).collect::<Vec<_>>();
+        self.held == other.held && keys(self) == keys(other)
+    }
+
+    /// Records the folded directories of installed packages that were unfolded while linking
+    /// another package: the links to their entries replace the directory link.
+    pub fn record_unfolds(&mut self, destdir: &Path, unfolded: &[Unfold]) {
+        let relative = |p: &Path| {
+            p.strip_prefix(destdir)
+                .unwrap_or(p)
+                .to_string_lossy()
+                .to_string()
+        };
+        for unfold in unfolded {
+            let link = relative(&unfold.link);
+            let owner = self.packages.iter_mut().find(|p| p.links.contains(&link));
+            if let Some(owner) = owner {
+                owner.links.retain(|l| *l != link);
+                owner
+                    .links
+                    .extend(unfold.children.iter().map(|c| relative(c)));
+                owner.links.sort();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state::InstalledState;
+    use crate::testutil::installed;
+    use crate::NebulaError;
+
+    #[test]
+    fn held_packages_keep_their_version() {
+        let mut state = InstalledState::default();
+        let gcc = |version: &str| installed("gcc", version, None);
+        assert!(state.hold("gcc").is_err());
+        state.record_install(gcc("10.2")).unwrap();
+        assert!(state.hold("gcc").unwrap());
+        assert!(!state.hold("gcc").unwrap());
+
+        match state.record_install(gcc("11.1")) {
+            Err(NebulaError::PackageHeld(msg)) => assert!(msg.contains("gcc is held")),
+            r => panic!("unexpected result: {:?}", r),
+        }
+        assert!(state.unhold("gcc"));
+        state.record_install(gcc("11.1")).unwrap();
+        assert_eq!(state.get("gcc").unwrap().version, "11.1");
+    }
+}
@@ -0,0 +1,117 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Configuration;
+use crate::nbpkg::{self, NBPKG_EXTENSION};
+use crate::repos::Debian;
+use crate::{file2hash, NebulaError, Package};
+
+/// Number of hex characters of the archive hash used in the names of the store trees.
+pub const HASH_LEN: usize = 32;
+
+/// The package store: every package is unpacked once into its own tree of `fakeroot-dir`, named
+/// `<hash>-<name>-<version>` after the hash of its archive. Trees are never modified once added,
+/// so several versions of a package can coexist and switching between them only means switching
+/// the links of the destination directory.
+#[derive(Debug, Clone)]
+pub struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub fn new(config: &Configuration) -> Store {
+        Store {
+            dir: config.fakerootdir.clone(),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    /// Name of the tree of a package whose archive has the given hash.
+    pub fn entry_name(hash: &str, package: &Package) -> String {
+        let hash = &hash[..HASH_LEN.min(hash.len())];
+        format!("{}-{}-{}", hash, package.name, package.version)
+    }
+
+    /// Whether the given file name is the name of a store tree.
+    pub fn is_entry_name(name: &str) -> bool {
+        match name.split_once('-') {
+            Some((hash, rest)) => {
+                hash.len() == HASH_LEN
+                    && hash.bytes().all(|b| b.is_ascii_hexdigit())
+                    && !rest.is_empty()
+            }
+            None => false,
+        }
+    }
+
+    /// Path of a tree of the store.
+    pub fn path(&self, entry: &str) -> PathBuf {
+        self.dir.join(entry)
+    }
+
+    /// Path of the payload of a tree of the store, the tree that gets linked.
+    pub fn data_dir(&self, entry: &str) -> PathBuf {
+        self.path(entry).join(nbpkg::DATA_DIR)
+    }
+
+    /// Names of the trees of the store.
+    pub fn entries(&self) -> Result<Vec<String>, NebulaError> {
+        let read_err =
+            |e: std::io::Error| NebulaError::Fs(format!("{}: {}", self.dir.display(), e));
+        let mut entries = vec![];
+        for entry in fs::read_dir(&self.dir).map_err(read_err)? {
+            let name = entry.map_err(read_err)?.file_name();
+            let name = name.to_string_lossy();
+            if Self::is_entry_name(&name) {
+                entries.push(name.to_string());
+            }
+        }
+        entries.sort();
+        Ok(entries)
+    }
+
+    /// Adds the package archive (`.deb` or `.nbpkg`) to the store and returns the name of its
+    /// tree. Archives already in the store are not unpacked again. The archive is unpacked into a
+    /// temporary directory renamed once complete, so the store never holds partial trees.
+    pub fn add(&self, archive: &Path, package: &Package) -> Result<String, NebulaError> {
+        let name = Self::entry_name(&file2hash(archive)?, package);
+        let path = self.path(&name);
+        if path.is_dir() {
+            debug!("{} is already in the store", name);
+            return Ok(name);
+        }
+
+        let tmp = self.dir.join(format!(".{}.tmp", name));
+        if tmp.exists() {
+            if let Err(e) = fs::remove_dir_all(&tmp) {
+                return Err(NebulaError::Fs(format!(
+                    "cannot clean {}: {}",
+                    tmp.display(),
+                    e
+                )));
+            }
+        }
+        let extension = archive.extension().and_then(|e| e.to_str());
+        let result = match extension {
+            Some("deb") => Debian::extract_deb(archive, &tmp),
+            Some(NBPKG_EXTENSION) => nbpkg::extract_nbpkg(archive, &tmp).map(|_| ()),
+            _ => Err(NebulaError::Fs(format!(
+                "{} is not a package archive",
+                archive.display()
+            ))),
+        };
+        let result = result.and_then(|_| {
+            fs::rename(&tmp, &path)
+                .map_err(|e| NebulaError::Fs(format!("cannot move {}: {}", tmp.display(), e)))
+        });
+        if let Err(e) = result {
+            let _ = fs::remove_dir_all(&tmp);
+            return Err(e);
+        }
+        info!("{} added to the store", name);
+        Ok(name)
+    }
+}
@@ -0,0 +1,48 @@
+//! Fixtures shared by the unit tests.
+
+use crate::state::InstalledPackage;
+use std::fs;
+use std::path::Path;
+use tempfile::TempDir;
+
+/// Creates a temporary directory for a test, removed when the returned guard is dropped (also
+/// when the test fails).
+pub fn temp_dir(name: &str) -> TempDir {
+    tempfile::Builder::new()
+        .prefix(&format!("nbpm-{}-", name))
+        .tempdir()
+        .unwrap()
+}
+
+/// Name of the store tree of a package, with a dummy hash.
+pub fn store_entry(name: &str, version: &str) -> String {
+    format!("{}-{}-{}", "0".repeat(32), name, version)
+}
+
+/// An installed package, not linked yet.
+pub fn installed(name: &str, version: &str, store: Option<String>) -> InstalledPackage {
+    InstalledPackage {
+        name: name.to_string(),
+        version: version.to_string(),
+        repository: None,
+        store,
+        links: vec![],
+    }
+}
+
+/// Creates the store tree of a package in `fakerootdir`, with the given `(path, content)` files
+/// in its data directory, and returns the package to activate.
+pub fn store_tree(
+    fakerootdir: &Path,
+    name: &str,
+    version: &str,
+    files: &[(&str, &str)],
+) -> InstalledPackage {
+    let entry = store_entry(name, version);
+    for (file, content) in files {
+        let path = fakerootdir.join(&entry).join("data").join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+    installed(name, version, Some(entry))
+}
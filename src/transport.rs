@@ -0,0 +1,475 @@
+use curl::easy::{Easy, List};
+use serde_derive::{Deserialize, Serialize};
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::config::NetworkConfig;
+use crate::hash::{self, HashAlgorithm, Hasher, HashingWriter};
+use crate::NebulaError;
+
+/// A way of fetching a resource given its URL. Repositories do not care where their files come
+/// from, the transport is chosen from the URL of the resource (see `transport_for`).
+pub trait Transport {
+    /// Fetches the resource at `url` and writes its content into `outfile`.
+    fn fetch(&self, url: &str, outfile: &Path) -> Result<(), NebulaError>;
+
+    /// Fetches the resource at `url` only if it changed since it was fetched with the given
+    /// validators. Transports that cannot tell fetch the resource unconditionally.
+    fn fetch_if_modified(
+        &self,
+        url: &str,
+        outfile: &Path,
+        _validators: &Validators,
+    ) -> Result<Fetched, NebulaError> {
+        self.fetch(url, outfile)?;
+        Ok(Fetched::Modified(Validators::default()))
+    }
+
+    /// Fetches the resource at `url` and returns the hash of its content. Transports that can
+    /// hash the data while writing it override this, so the file is not read a second time.
+    fn fetch_hashed(
+        &self,
+        url: &str,
+        outfile: &Path,
+        algorithm: HashAlgorithm,
+    ) -> Result<String, NebulaError> {
+        self.fetch(url, outfile)?;
+        hash::hash_file(algorithm, outfile)
+    }
+}
+
+/// Validators of a fetched resource (HTTP `ETag` and `Last-Modified` headers), sent back on the
+/// next fetch so unchanged resources are not downloaded again.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Default)]
+pub struct Validators {
+    pub etag: Option<String>,
+    #[serde(rename = "last-modified")]
+    pub last_modified: Option<String>,
+}
+
+/// Result of a conditional fetch.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Fetched {
+    /// The resource was fetched, with its new validators
+    Modified(Validators),
+    /// The resource did not change, nothing was written
+    NotModified,
+}
+
+/// Fetches resources over HTTP(S) using curl. Proxy, TLS, authentication and timeout settings
+/// are applied to every curl handle it creates.
+pub struct HttpTransport {
+    net: NetworkConfig,
+}
+
+/// Fetches resources from the local file system, given as `file://` URLs or plain paths. Useful
+/// for local mirrors, removable media and test fixtures.
+pub struct FileTransport;
+
+impl HttpTransport {
+    pub fn new(net: &NetworkConfig) -> HttpTransport {
+        HttpTransport { net: net.clone() }
+    }
+
+    /// Applies the network settings to the given curl handle.
+    fn configure(&self, handle: &mut Easy, url: &str) -> Result<(), curl::Error> {
+        let net = &self.net;
+        if let Some(proxy) = &net.proxy {
+            handle.proxy(proxy)?;
+        }
+        if let Some(noproxy) = &net.noproxy {
+            handle.noproxy(&noproxy.join(","))?;
+        }
+        if let Some(cabundle) = &net.cabundle {
+            handle.cainfo(cabundle)?;
+        }
+        if let Some(cert) = &net.clientcert {
+            handle.ssl_cert(cert)?;
+        }
+        if let Some(key) = &net.clientkey {
+            handle.ssl_key(key)?;
+        }
+        // explicit credentials take precedence over the netrc file
+        let credentials = match (&net.username, &net.password) {
+            (Some(user), pass) => Some((user.clone(), pass.clone().unwrap_or_default())),
+            _ => match (&net.netrc, url_host(url)) {
+                (Some(netrc), Some(host)) => netrc_credentials(netrc, host),
+                _ => None,
+            },
+        };
+        if let Some((user, pass)) = credentials {
+            handle.username(&user)?;
+            handle.password(&pass)?;
+        }
+        if let Some(secs) = net.timeout {
+            handle.timeout(Duration::from_secs(secs))?;
+        }
+        if let Some(secs) = net.connect_timeout {
+            handle.connect_timeout(Duration::from_secs(secs))?;
+        }
+        Ok(())
+    }
+}
+
+impl HttpTransport {
+    /// Performs the transfer, as a conditional request if validators are given. If an algorithm
+    /// is given, the hash of the received data is returned too.
+    fn transfer(
+        &self,
+        url: &str,
+        outfile: &Path,
+        validators: Option<&Validators>,
+        algorithm: Option<HashAlgorithm>,
+    ) -> Result<(Fetched, Option<String>), NebulaError> {
+        let mut file = match File::create(outfile) {
+            Ok(f) => f,
+            Err(e) => {
+                return Err(NebulaError::Fs(format!(
+                    "cannot create {}: {}",
+                    outfile.display(),
+                    e
+                )))
+            }
+        };
+
+        let curl_err = |e: curl::Error| NebulaError::Transport(format!("{}: {}", url, e));
+        let mut handle = Easy::new();
+        handle.url(url).map_err(curl_err)?;
+        handle.follow_location(true).map_err(curl_err)?;
+        // treat HTTP errors (404, 500...) as transfer errors
+        handle.fail_on_error(true).map_err(curl_err)?;
+        self.configure(&mut handle, url).map_err(curl_err)?;
+        if let Some(validators) = validators {
+            let mut headers = List::new();
+            if let Some(etag) = &validators.etag {
+                headers
+                    .append(&format!("If-None-Match: {}", etag))
+                    .map_err(curl_err)?;
+            }
+            if let Some(date) = &validators.last_modified {
+                headers
+                    .append(&format!("If-Modified-Since: {}", date))
+                    .map_err(curl_err)?;
+            }
+            handle.http_headers(headers).map_err(curl_err)?;
+        }
+
+        let mut write_err = None;
+        let mut hasher = algorithm.map(Hasher::new);
+        let mut received = Validators::default();
+        let result = {
+            let mut transfer = handle.transfer();
+            transfer
+                .header_function(|header| {
+                    let header = String::from_utf8_lossy(header);
+                    let (name, value) = match header.split_once(':') {
+                        Some((n, v)) => (n.trim().to_ascii_lowercase(), v.trim().to_string()),
+                        // status line, headers of a previous response (redirection) are dropped
+                        None if header.starts_with("HTTP/") => {
+                            received = Validators::default();
+                            return true;
+                        }
+                        None => return true,
+                    };
+                    match name.as_str() {
+                        "etag" => received.etag = Some(value),
+                        "last-modified" => received.last_modified = Some(value),
+                        _ => (),
+                    }
+                    true
+                })
+                .map_err(curl_err)?;
+            transfer
+                .write_function(|new_data| match file.write_all(new_data) {
+                    Ok(()) => {
+                        if let Some(hasher) = &mut hasher {
+                            hasher.update(new_data);
+                        }
+                        Ok(new_data.len())
+                    }
+                    Err(e) => {
+                        write_err = Some(e);
+                        // returning a different length aborts the transfer
+                        Ok(0)
+                    }
+                })
+                .map_err(curl_err)?;
+            transfer.perform()
+        };
+        match (result, write_err) {
+            (Ok(()), _) => (),
+            (Err(_), Some(e)) => return Err(NebulaError::Io(e)),
+            (Err(e), None) => return Err(curl_err(e)),
+        }
+        if handle.response_code().map_err(curl_err)? == 304 {
+            drop(file);
+            let _ = fs::remove_file(outfile);
+            return Ok((Fetched::NotModified, None));
+        }
+        Ok((Fetched::Modified(received), hasher.map(Hasher::finalize)))
+    }
+}
+
+impl Transport for HttpTransport {
+    fn fetch(&self, url: &str, outfile: &Path) -> Result<(), NebulaError> {
+        self.transfer(url, outfile, None, None).map(|_| ())
+    }
+
+    fn fetch_if_modified(
+        &self,
+        url: &str,
+        outfile: &Path,
+        validators: &Validators,
+    ) -> Result<Fetched, NebulaError> {
+        self.transfer(url, outfile, Some(validators), None)
+            .map(|(fetched, _)| fetched)
+    }
+
+    fn fetch_hashed(
+        &self,
+        url: &str,
+        outfile: &Path,
+        algorithm: HashAlgorithm,
+    ) -> Result<String, NebulaError> {
+        match self.transfer(url, outfile, None, Some(algorithm))? {
+            (_, Some(hash)) => Ok(hash),
+            (_, None) => unreachable!("an algorithm was given"),
+        }
+    }
+}
+
+impl FileTransport {
+    /// Returns the local path a `file://` URL or plain path refers to.
+    pub fn local_path(url: &str) -> PathBuf {
+        PathBuf::from(url.strip_prefix("file://").unwrap_or(url))
+    }
+
+    /// Builds an entity tag for a local file from its size and modification time.
+    fn etag(path: &Path) -> Option<String> {
+        let meta = fs::metadata(path).ok()?;
+        let mtime = meta.modified().ok()?.duration_since(UNIX_EPOCH).ok()?;
+        Some(format!("{}-{}", meta.len(), mtime.as_nanos()))
+    }
+}
+
+impl Transport for FileTransport {
+    fn fetch(&self, url: &str, outfile: &Path) -> Result<(), NebulaError> {
+        let src = Self::local_path(url);
+        if !src.is_file() {
+            return Err(NebulaError::Transport(format!(
+                "{}: no such file",
+                src.display()
+            )));
+        }
+        match fs::copy(&src, outfile) {
+            Ok(_) => Ok(()),
+            Err(e) => Err(NebulaError::Transport(format!(
+                "cannot copy {} to {}: {}",
+                src.display(),
+                outfile.display(),
+                e
+            ))),
+        }
+    }
+
+    fn fetch_if_modified(
+        &self,
+        url: &str,
+        outfile: &Path,
+        validators: &Validators,
+    ) -> Result<Fetched, NebulaError> {
+        let etag = Self::etag(&Self::local_path(url));
+        if etag.is_some() && validators.etag == etag {
+            return Ok(Fetched::NotModified);
+        }
+        self.fetch(url, outfile)?;
+        Ok(Fetched::Modified(Validators {
+            etag,
+            last_modified: None,
+        }))
+    }
+
+    fn fetch_hashed(
+        &self,
+        url: &str,
+        outfile: &Path,
+        algorithm: HashAlgorithm,
+    ) -> Result<String, NebulaError> {
+        let src = Self::local_path(url);
+        if !src.is_file() {
+            return Err(NebulaError::Transport(format!(
+                "{}: no such file",
+                src.display()
+            )));
+        }
+        let copy_err = |e: io::Error| {
+            NebulaError::Transport(format!(
+                "cannot copy {} to {}: {}",
+                src.display(),
+                outfile.display(),
+                e
+            ))
+        };
+        let mut input = File::open(&src).map_err(copy_err)?;
+        let output = File::create(outfile).map_err(copy_err)?;
+        let mut writer = HashingWriter::new(output, algorithm);
+        io::copy(&mut input, &mut writer).map_err(copy_err)?;
+        let (mut output, hash) = writer.finalize();
+        output.flush().map_err(copy_err)?;
+        Ok(hash)
+    }
+}
+
+/// Returns the host part of a URL, without credentials nor port.
+pub(crate) fn url_host(url: &str) -> Option<&str> {
+    let rest = url.split("://").nth(1)?;
+    let authority = rest.split('/').next()?;
+    let host = authority.rsplit('@').next()?;
+    host.split(':').next().filter(|h| !h.is_empty())
+}
+
+/// Looks up the login and password for `host` in a netrc-style file. Falls back to the `default`
+/// entry if the host is not listed.
+fn netrc_credentials(netrc: &Path, host: &str) -> Option<(String, String)> {
+    let content = match fs::read_to_string(netrc) {
+        Ok(c) => c,
+        Err(e) => {
+            warn!("cannot read netrc file {}: {}", netrc.display(), e);
+            return None;
+        }
+    };
+
+    let mut found = None;
+    let mut default = None;
+    // (is default entry, machine, login, password)
+    let mut current: Option<(bool, String, String, String)> = None;
+    let mut tokens = content.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" | "default" => {
+                if let Some(entry) = current.take() {
+                    if entry.0 {
+                        default = Some(entry);
+                    } else if entry.1 == host && found.is_none() {
+                        found = Some(entry);
+                    }
+                }
+                let machine = if token == "machine" {
+                    tokens.next().unwrap_or("").to_string()
+                } else {
+                    String::new()
+                };
+                current = Some((token == "default", machine, String::new(), String::new()));
+            }
+            "login" => {
+                if let (Some(entry), Some(v)) = (current.as_mut(), tokens.next()) {
+                    entry.2 = v.to_string();
+                }
+            }
+            "password" => {
+                if let (Some(entry), Some(v)) = (current.as_mut(), tokens.next()) {
+                    entry.3 = v.to_string();
+                }
+            }
+            _ => {}
+        }
+    }
+    if let Some(entry) = current {
+        if entry.0 {
+            default = Some(entry);
+        } else if entry.1 == host && found.is_none() {
+            found = Some(entry);
+        }
+    }
+    found.or(default).map(|e| (e.2, e.3))
+}
+
+/// Returns the transport able to fetch the given URL. URLs without a known scheme are treated as
+/// local paths.
+pub fn transport_for(url: &str, net: &NetworkConfig) -> Box<dyn Transport> {
+    if url.starts_with("http://") || url.starts_with("https://") {
+        Box::new(HttpTransport::new(net))
+    } else {
+        Box::new(FileTransport)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::NetworkConfig;
+    use crate::file2hash;
+    use crate::hash::HashAlgorithm;
+    use crate::testutil::temp_dir;
+    use crate::transport::{netrc_credentials, transport_for, url_host, FileTransport};
+    use std::fs;
+    use std::path::PathBuf;
+
+    #[test]
+    fn file_urls_and_plain_paths() {
+        assert_eq!(
+            FileTransport::local_path("file:///srv/mirror/InRelease"),
+            PathBuf::from("/srv/mirror/InRelease")
+        );
+        assert_eq!(
+            FileTransport::local_path("/media/usb/debian"),
+            PathBuf::from("/media/usb/debian")
+        );
+    }
+
+    #[test]
+    fn fetch_local_file() {
+        let tmp = temp_dir("transport");
+        let dir = tmp.path();
+        fs::create_dir_all(dir).unwrap();
+        let src = dir.join("src");
+        fs::write(&src, "Package: proba\n").unwrap();
+
+        let url = format!("file://{}", src.display());
+        let net = NetworkConfig::default();
+        transport_for(&url, &net)
+            .fetch(&url, &dir.join("out"))
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.join("out")).unwrap(),
+            "Package: proba\n"
+        );
+        // hashed while copied
+        let hash = transport_for(&url, &net)
+            .fetch_hashed(&url, &dir.join("out"), HashAlgorithm::Sha256)
+            .unwrap();
+        assert_eq!(hash, file2hash(&src).unwrap());
+
+        assert!(transport_for("/does/not/exist", &net)
+            .fetch("/does/not/exist", &dir.join("out2"))
+            .is_err());
+    }
+
+    #[test]
+    fn netrc_lookup() {
+        assert_eq!(
+            url_host("https://user@repo.corp:8443/nebula"),
+            Some("repo.corp")
+        );
+        assert_eq!(url_host("/srv/mirror"), None);
+
+        let tmp = temp_dir("netrc");
+        let dir = tmp.path();
+        fs::create_dir_all(dir).unwrap();
+        let netrc = dir.join("netrc");
+        fs::write(
+            &netrc,
+            "machine repo.corp login builder password s3cret\ndefault login anon password anon\n",
+        )
+        .unwrap();
+        assert_eq!(
+            netrc_credentials(&netrc, "repo.corp"),
+            Some(("builder".to_string(), "s3cret".to_string()))
+        );
+        assert_eq!(
+            netrc_credentials(&netrc, "ftp.debian.org"),
+            Some(("anon".to_string(), "anon".to_string()))
+        );
+    }
+}
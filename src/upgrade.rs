@@ -0,0 +1,250 @@
+use crate::pkg::compare_versions;
+use crate::policy::{self, Candidate, Pin};
+use crate::state::{InstalledPackage, InstalledState};
+use crate::{NebulaError, Repository};
+use std::cmp::Ordering;
+
+/// Candidates with at least this priority are installed even if that means a downgrade.
+pub const DOWNGRADE_PRIORITY: i32 = 1000;
+
+/// A step of an upgrade plan: an installed package and the candidate it's upgraded to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Upgrade {
+    pub installed: InstalledPackage,
+    pub candidate: Candidate,
+}
+
+/// Plans the upgrade of the installed packages (or only the ones in `names`, if given).
+///
+/// Held packages are kept at their current version. If a held package is explicitly requested,
+/// or an upgraded package requires a version of a held package other than the installed one, the
+/// plan fails with a `PackageHeld` error naming the hold.
+pub fn plan_upgrade(
+    state: &InstalledState,
+    repos: &[impl Repository],
+    pins: &[Pin],
+    names: Option<&[String]>,
+) -> Result<Vec<Upgrade>, NebulaError> {
+    let installed: Vec<&InstalledPackage> = match names {
+        Some(names) => {
+            let mut pkgs = vec![];
+            for name in names {
+                match state.get(name) {
+                    Some(p) => pkgs.push(p),
+                    None => return Err(NebulaError::PackageNotInstalled(name.to_string())),
+                }
+            }
+            pkgs
+        }
+        None => state.packages.iter().collect(),
+    };
+
+    let mut plan = vec![];
+    for pkg in installed {
+        let candidate = match policy::select_candidate(repos, pins, &pkg.name)? {
+            Some(c) => c,
+            None => continue,
+        };
+        if !is_upgrade(pkg, &candidate) {
+            continue;
+        }
+        if state.is_held(&pkg.name) {
+            if names.is_some() {
+                return Err(NebulaError::PackageHeld(format!(
+                    "{} is held at version {}, {} is available (use nb-unhold {} to upgrade it)",
+                    pkg.name, pkg.version, candidate.package.version, pkg.name
+                )));
+            }
+            info!("{} is held, keeping version {}", pkg.name, pkg.version);
+            continue;
+        }
+        plan.push(Upgrade {
+            installed: pkg.clone(),
+            candidate,
+        });
+    }
+
+    check_holds(state, &plan)?;
+    Ok(plan)
+}
+
+/// Whether the candidate should replace the installed package: it's newer, or it's pinned with a
+/// priority high enough to allow downgrades.
+fn is_upgrade(installed: &InstalledPackage, candidate: &Candidate) -> bool {
+    match compare_versions(&candidate.package.version, &installed.version) {
+        Ordering::Greater => true,
+        Ordering::Less => candidate.priority >= DOWNGRADE_PRIORITY,
+        Ordering::Equal => false,
+    }
+}
+
+/// Checks that no upgrade of the plan depends on a held package at a version other than the
+/// installed one.
+fn check_holds(state: &InstalledState, plan: &[Upgrade]) -> Result<(), NebulaError> {
+    for upgrade in plan {
+        let depends = match &upgrade.candidate.package.depends {
+            Some(d) => d,
+            None => continue,
+        };
+        for options in depends {
+            // an alternative that is not held can always be changed
+            let satisfiable = options.is_empty()
+                || options.iter().any(|dep| {
+                    let (name, _) = dep.split_arch();
+                    match state.get(name) {
+                        Some(p) if state.is_held(name) => dep.satisfied_by(&p.version),
+                        _ => true,
+                    }
+                });
+            if !satisfiable {
+                let required: Vec<String> = options
+                    .iter()
+                    .map(|d| {
+                        format!("{} {}", d.name(), d.version_req())
+                            .trim_end()
+                            .to_string()
+                    })
+                    .collect();
+                let held: Vec<String> = options
+                    .iter()
+                    .map(|d| d.split_arch().0)
+                    .filter(|name| state.is_held(name))
+                    .filter_map(|name| state.get(name))
+                    .map(|p| format!("{} is held at version {}", p.name, p.version))
+                    .collect();
+                return Err(NebulaError::PackageHeld(format!(
+                    "{} {} requires {}, but {}",
+                    upgrade.candidate.package.name,
+                    upgrade.candidate.package.version,
+                    required.join(" | "),
+                    held.join(", ")
+                )));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::NetworkConfig;
+    use crate::policy::Pin;
+    use crate::state::InstalledState;
+    use crate::testutil::installed;
+    use crate::upgrade::plan_upgrade;
+    use crate::{Dependency, NebulaError, Package, RepoType, Repository};
+    use std::path::{Path, PathBuf};
+
+    struct Archive(Vec<Package>);
+
+    impl Repository for Archive {
+        fn repo_type(&self) -> RepoType {
+            RepoType::Debian
+        }
+        fn name(&self) -> &str {
+            "bullseye-security"
+        }
+        fn priority(&self) -> i32 {
+            500
+        }
+        fn initialize(&self) -> Result<(), NebulaError> {
+            Ok(())
+        }
+        fn update(&self) -> Result<(), NebulaError> {
+            Ok(())
+        }
+        fn search(
+            &self,
+            name: &str,
+            _version: Option<&str>,
+        ) -> Result<Option<Vec<Package>>, NebulaError> {
+            Ok(Some(
+                self.0.iter().filter(|p| p.name == name).cloned().collect(),
+            ))
+        }
+        fn fetch(&self, package: &Package, dir: &Path) -> Result<PathBuf, NebulaError> {
+            package.fetch(dir, &NetworkConfig::default(), None)
+        }
+    }
+
+    #[test]
+    fn plan_debian_updates() {
+        let mut state = InstalledState::default();
+        for (name, version) in &[
+            ("libc6", "2.31-13"),
+            ("bash", "5.1-2"),
+            ("gcc-10", "1:10.2.1-6"),
+            ("vim", "2:9.0.1378-2"),
+        ] {
+            state
+                .record_install(installed(name, version, None))
+                .unwrap();
+        }
+        let repos = [Archive(vec![
+            Package::new("libc6", "2.31-13+deb11u5"),
+            Package::new("bash", "5.1-2"),
+            // the installed version has an epoch, this one is older
+            Package::new("gcc-10", "10.2.1-6+b1"),
+            Package::new("vim", "2:8.2.2434-3+deb11u1"),
+        ])];
+        let planned = |pins: &[Pin]| -> Vec<(String, String)> {
+            plan_upgrade(&state, &repos, pins, None)
+                .unwrap()
+                .into_iter()
+                .map(|u| (u.installed.name, u.candidate.package.version))
+                .collect()
+        };
+
+        let security = ("libc6".to_string(), "2.31-13+deb11u5".to_string());
+        assert_eq!(planned(&[]), vec![security.clone()]);
+
+        // pinned at 1000, the security update is still an upgrade and vim is downgraded
+        let pins = ["libc6", "vim"].map(|name| Pin {
+            package: name.to_string(),
+            repository: None,
+            version: None,
+            priority: 1000,
+        });
+        assert_eq!(
+            planned(&pins),
+            vec![
+                security,
+                ("vim".to_string(), "2:8.2.2434-3+deb11u1".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn upgrade_needs_held_package() {
+        let mut state = InstalledState::default();
+        for (name, version) in &[("libc6", "2.31-13"), ("bash", "5.1-2"), ("dash", "0.5.11")] {
+            state
+                .record_install(installed(name, version, None))
+                .unwrap();
+        }
+        state.hold("bash").unwrap();
+        state.hold("dash").unwrap();
+        let mut libc6 = Package::new("libc6", "2.36-9");
+        libc6.depends = Some(vec![
+            vec![],
+            vec![
+                Dependency::from("bash", Some(">=5.2")),
+                Dependency::from("dash", Some(">=0.5.12")),
+            ],
+        ]);
+        let repos = [Archive(vec![libc6])];
+
+        match plan_upgrade(&state, &repos, &[], None) {
+            Err(NebulaError::PackageHeld(e)) => assert_eq!(
+                e,
+                "libc6 2.36-9 requires bash >=5.2 | dash >=0.5.12, but bash is held at version \
+                 5.1-2, dash is held at version 0.5.11"
+            ),
+            r => panic!("unexpected plan: {:?}", r.map(|p| p.len())),
+        }
+
+        // an alternative that is not held can be upgraded along
+        assert!(state.unhold("dash"));
+        assert_eq!(plan_upgrade(&state, &repos, &[], None).unwrap().len(), 1);
+    }
+}
@@ -0,0 +1,270 @@
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::Configuration;
+use crate::hash::{hash_file, Checksum, HashAlgorithm};
+use crate::nbpkg::{Manifest, MANIFEST_FILE};
+use crate::state::InstalledPackage;
+use crate::store::Store;
+use crate::{read_toml, NebulaError};
+
+/// Checksums of the payload of a debian package, extracted from its control archive.
+const MD5SUMS_FILE: &str = "control/md5sums";
+
+/// A problem found while verifying an installed package.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Issue {
+    /// A file of the package tree whose content differs from the package's checksums
+    Modified(PathBuf),
+    /// A file of the package tree, or a link of the destination directory, that does not exist
+    Missing(PathBuf),
+    /// A link of the destination directory whose target does not exist
+    Dangling(PathBuf),
+    /// A link of the destination directory pointing outside of its package tree: (link, target)
+    Misdirected(PathBuf, PathBuf),
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Issue::Modified(p) => write!(f, "modified: {}", p.display()),
+            Issue::Missing(p) => write!(f, "missing: {}", p.display()),
+            Issue::Dangling(p) => write!(f, "dangling: {}", p.display()),
+            Issue::Misdirected(p, target) => {
+                write!(f, "misdirected: {} -> {}", p.display(), target.display())
+            }
+        }
+    }
+}
+
+/// Result of the verification of an installed package.
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Verification {
+    /// Number of files checked against the package's checksums
+    pub files: usize,
+    /// Number of links of the destination directory checked
+    pub links: usize,
+    /// Whether the package came with checksums, files cannot be checked otherwise
+    pub checksums: bool,
+    pub issues: Vec<Issue>,
+}
+
+/// What an entry of a package tree should be.
+enum Expected {
+    File(Checksum),
+    Symlink(String),
+}
+
+/// Verifies an installed package: the files of its store tree are checked against the hashes
+/// of its manifest (nebula packages) or its `md5sums` (debian packages), and its links in the
+/// destination directory must point into that tree.
+pub fn verify(
+    config: &Configuration,
+    destdir: &Path,
+    package: &InstalledPackage,
+) -> Result<Verification, NebulaError> {
+    let mut verification = Verification::default();
+    let entry = match &package.store {
+        Some(e) => e,
+        // installed before the store existed, there is nothing to check
+        None => return Ok(verification),
+    };
+    let store = Store::new(config);
+    let tree = store.path(entry);
+    if !tree.is_dir() {
+        verification.issues.push(Issue::Missing(tree));
+    } else if let Some(expected) = expected_entries(&tree)? {
+        verification.checksums = true;
+        let data_dir = store.data_dir(entry);
+        for (path, expected) in expected {
+            verification.files += 1;
+            if let Some(issue) = check_entry(&data_dir.join(&path), &expected)? {
+                verification.issues.push(issue);
+            }
+        }
+    }
+
+    // links point to the canonical tree, see `create_links`
+    let data_dir = store.data_dir(entry);
+    let data_dir = fs::canonicalize(&data_dir).unwrap_or(data_dir);
+    for link in &package.links {
+        verification.links += 1;
+        let path = destdir.join(link);
+        let expected = data_dir.join(link);
+        let target = match fs::read_link(&path) {
+            Ok(t) => t,
+            Err(_) if fs::symlink_metadata(&path).is_ok() => {
+                // replaced by a real file or directory
+                verification.issues.push(Issue::Modified(path));
+                continue;
+            }
+            Err(_) => {
+                verification.issues.push(Issue::Missing(path));
+                continue;
+            }
+        };
+        if target != expected {
+            verification.issues.push(Issue::Misdirected(path, target));
+        } else if !target.exists() {
+            verification.issues.push(Issue::Dangling(path));
+        }
+    }
+    Ok(verification)
+}
+
+/// Reads the expected entries of a store tree, paths relative to its payload. None if the
+/// package came without checksums.
+fn expected_entries(tree: &Path) -> Result<Option<Vec<(String, Expected)>>, NebulaError> {
+    let manifest_path = tree.join(MANIFEST_FILE);
+    if manifest_path.is_file() {
+        let manifest: Manifest = read_toml(&manifest_path)?;
+        let entries = manifest
+            .files
+            .into_iter()
+            .map(|f| match f.link {
+                Some(target) => (f.path, Expected::Symlink(target)),
+                None => (f.path, Expected::File(Checksum::sha256(&f.hash))),
+            })
+            .collect();
+        return Ok(Some(entries));
+    }
+
+    let md5sums_path = tree.join(MD5SUMS_FILE);
+    if md5sums_path.is_file() {
+        let md5sums = fs::read_to_string(&md5sums_path).map_err(NebulaError::Io)?;
+        let mut entries = vec![];
+        // `<hash>  <path>` lines
+        for line in md5sums.lines() {
+            match line.split_once(char::is_whitespace) {
+                Some((hash, path)) => {
+                    let path = path.trim_start().trim_start_matches("./");
+                    let checksum = Checksum::new(HashAlgorithm::Md5, hash);
+                    entries.push((path.to_string(), Expected::File(checksum)));
+                }
+                None if line.trim().is_empty() => (),
+                None => warn!("{}: invalid line {}", md5sums_path.display(), line),
+            }
+        }
+        return Ok(Some(entries));
+    }
+    Ok(None)
+}
+
+fn check_entry(path: &Path, expected: &Expected) -> Result<Option<Issue>, NebulaError> {
+    let meta = match fs::symlink_metadata(path) {
+        Ok(m) => m,
+        Err(_) => return Ok(Some(Issue::Missing(path.to_path_buf()))),
+    };
+    let modified = match expected {
+        Expected::Symlink(target) => {
+            fs::read_link(path).map_or(true, |t| t.to_string_lossy() != target.as_str())
+        }
+        Expected::File(checksum) if meta.is_file() => {
+            !checksum.matches(&hash_file(checksum.algorithm, path)?)
+        }
+        Expected::File(_) => true,
+    };
+    Ok(if modified {
+        Some(Issue::Modified(path.to_path_buf()))
+    } else {
+        None
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::Configuration;
+    use crate::hash::{HashAlgorithm, Hasher};
+    use crate::install::{activate, destdir};
+    use crate::nbpkg;
+    use crate::state::InstalledState;
+    use crate::store::Store;
+    use crate::testutil::{installed, store_entry, temp_dir};
+    use crate::verify::{verify, Issue};
+    use crate::Package;
+    use std::fs;
+    use std::os::unix;
+
+    #[test]
+    fn verify_installed_packages() {
+        let tmp = temp_dir("verify");
+        let dir = tmp.path();
+        let config = Configuration::builder(dir.join("nebula"))
+            .dest_dir(dir.join("root"))
+            .build();
+        fs::create_dir_all(dir.join("root/usr/bin")).unwrap();
+        fs::create_dir_all(&config.fakerootdir).unwrap();
+        let store = Store::new(&config);
+
+        // a nebula package, checked against its manifest
+        let staged = dir.join("stage");
+        fs::create_dir_all(staged.join("usr/bin")).unwrap();
+        fs::write(staged.join("usr/bin/proba"), "proba").unwrap();
+        fs::write(staged.join("usr/bin/tool"), "tool").unwrap();
+        unix::fs::symlink("proba", staged.join("usr/bin/proba-1")).unwrap();
+        let package = Package::new("proba", "1.0");
+        let archive = nbpkg::build(&staged, package.clone(), dir).unwrap();
+        let entry = store.add(&archive, &package).unwrap();
+
+        // a debian package, checked against its md5sums
+        let deb_entry = store_entry("other", "1.0");
+        fs::create_dir_all(store.data_dir(&deb_entry).join("usr/bin")).unwrap();
+        fs::create_dir_all(store.path(&deb_entry).join("control")).unwrap();
+        fs::write(store.data_dir(&deb_entry).join("usr/bin/other"), "other").unwrap();
+        let mut hasher = Hasher::new(HashAlgorithm::Md5);
+        hasher.update(b"other");
+        let md5sums = format!("{}  usr/bin/other\n", hasher.finalize());
+        fs::write(store.path(&deb_entry).join("control/md5sums"), md5sums).unwrap();
+
+        let mut state = InstalledState::default();
+        activate(
+            &config,
+            &mut state,
+            installed("proba", "1.0", Some(entry.clone())),
+        )
+        .unwrap();
+        activate(
+            &config,
+            &mut state,
+            installed("other", "1.0", Some(deb_entry.clone())),
+        )
+        .unwrap();
+        let destdir = destdir(&config).unwrap();
+        let proba = state.get("proba").unwrap();
+        let other = state.get("other").unwrap();
+
+        let verification = verify(&config, &destdir, proba).unwrap();
+        assert!(verification.checksums);
+        assert_eq!(verification.files, 3);
+        assert_eq!(verification.links, 3);
+        assert!(verification.issues.is_empty());
+        assert!(verify(&config, &destdir, other).unwrap().issues.is_empty());
+
+        let data = store.data_dir(&entry);
+        fs::write(data.join("usr/bin/proba"), "changed").unwrap();
+        fs::remove_file(data.join("usr/bin/tool")).unwrap();
+        fs::remove_file(destdir.join("usr/bin/proba-1")).unwrap();
+        unix::fs::symlink("/nowhere", destdir.join("usr/bin/proba-1")).unwrap();
+        let issues = verify(&config, &destdir, proba).unwrap().issues;
+        assert_eq!(
+            issues,
+            vec![
+                Issue::Modified(data.join("usr/bin/proba")),
+                Issue::Missing(data.join("usr/bin/tool")),
+                Issue::Misdirected(destdir.join("usr/bin/proba-1"), "/nowhere".into()),
+                Issue::Dangling(destdir.join("usr/bin/tool")),
+            ]
+        );
+
+        fs::remove_dir_all(store.path(&deb_entry)).unwrap();
+        let issues = verify(&config, &destdir, other).unwrap().issues;
+        assert_eq!(
+            issues,
+            vec![
+                Issue::Missing(store.path(&deb_entry)),
+                Issue::Dangling(destdir.join("usr/bin/other")),
+            ]
+        );
+    }
+}